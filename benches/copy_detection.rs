@@ -0,0 +1,100 @@
+// Compares the rowclone detector's address-indexed copy lookup with the linear scan
+// over every tracked copy, which it still falls back to with a match tolerance.
+// With 8 byte aligned accesses a tolerance of 1 byte matches exactly the same
+// accesses, so both runs must emit the same trace. Run with `cargo bench`.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Instant;
+
+const KERNEL: u64 = 0xffff_8880_0001_0000;
+const USER: u64 = 0x7f00_0001_0000;
+const COPIES: u64 = 1024;
+const COPY_SIZE: u64 = 4096;
+// the fastest of this many runs is reported, the others are disturbed by noise
+const RUNS: usize = 3;
+
+// Kernel and memory logs of COPIES copy_to_user calls of a page, `concurrent` of
+// them advancing in turns, 8 bytes at a time.
+fn write_logs(dir: &Path, concurrent: u64) -> (PathBuf, PathBuf) {
+    let mut kernel_log = String::new();
+    for copy in 0..COPIES {
+        let offset = copy * COPY_SIZE;
+        kernel_log += &format!(
+            "N=bench,r,0,{},0x0,0x{:x},0x0,0x{:x}\n",
+            COPY_SIZE,
+            KERNEL + offset,
+            USER + offset
+        );
+    }
+    let mut mem_log = String::new();
+    let mut clock = 0;
+    for group in (0..COPIES).step_by(concurrent as usize) {
+        for offset in (0..COPY_SIZE).step_by(8) {
+            for copy in group..(group + concurrent).min(COPIES) {
+                let base = copy * COPY_SIZE + offset;
+                for (store, address) in [(0, KERNEL + base), (1, USER + base)] {
+                    mem_log += &format!("{},{},0,{},3,0x{:016x}\n", clock, clock, store, address);
+                    clock += 1;
+                }
+            }
+        }
+    }
+    let kernel_path = dir.join(format!("kernel_{}.log", concurrent));
+    let mem_path = dir.join(format!("mem_{}.csv", concurrent));
+    fs::write(&kernel_path, kernel_log).expect("failed to write the kernel log");
+    fs::write(&mem_path, mem_log).expect("failed to write the memory log");
+    (kernel_path, mem_path)
+}
+
+// Milliseconds of the fastest detection run, and the trace it wrote.
+fn time_detection(kernel_log: &Path, mem_log: &Path, tolerance: u64, out: &Path) -> (f64, String) {
+    let fastest = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            let status = Command::new(env!("CARGO_BIN_EXE_rowclone"))
+                .arg("--kernel-logfile")
+                .arg(kernel_log)
+                .arg("--mem-log")
+                .arg(mem_log)
+                .arg("--out")
+                .arg(out)
+                .args(["--match-tolerance", &tolerance.to_string()])
+                .args(["--progress-interval", "0"])
+                .stderr(Stdio::null())
+                .status()
+                .expect("failed to run rowclone");
+            assert!(status.success(), "rowclone failed: {}", status);
+            start.elapsed().as_secs_f64() * 1000.0
+        })
+        .fold(f64::INFINITY, f64::min);
+    let trace = fs::read_to_string(out).expect("failed to read the trace");
+    (fastest, trace)
+}
+
+fn main() {
+    let dir = std::env::temp_dir().join(format!("copy_detection_{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("failed to create the bench directory");
+    println!(
+        "{:>10} {:>10} {:>12} {:>12}",
+        "concurrent", "rowclones", "indexed_ms", "scan_ms"
+    );
+    for concurrent in [1, 16, 64, 128] {
+        let (kernel_log, mem_log) = write_logs(&dir, concurrent);
+        let out = dir.join("trace.csv");
+        let (indexed, indexed_trace) = time_detection(&kernel_log, &mem_log, 0, &out);
+        let (scan, scan_trace) = time_detection(&kernel_log, &mem_log, 1, &out);
+        assert_eq!(
+            indexed_trace, scan_trace,
+            "the lookups matched different copies"
+        );
+        println!(
+            "{:>10} {:>10} {:>12.1} {:>12.1}",
+            concurrent,
+            indexed_trace.lines().count(),
+            indexed,
+            scan
+        );
+    }
+    fs::remove_dir_all(&dir).expect("failed to remove the bench directory");
+}
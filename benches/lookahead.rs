@@ -0,0 +1,85 @@
+// Compares LookaheadIterator, which buffers peeked items in a VecDeque, with the
+// Vec it used before, whose next() shifted the whole buffer with remove(0). Each
+// run peeks a window ahead and then consumes it, like a matcher checking whether a
+// copy starts. Run with `cargo bench`.
+use std::hint::black_box;
+use std::time::Instant;
+
+use cf_qemu_post::lookahead_iter::LookaheadIterator;
+
+const ITEMS: u64 = 200_000;
+// the fastest of this many runs is reported, the others are disturbed by noise
+const RUNS: usize = 3;
+
+// The Vec-backed lookahead this crate used to have.
+struct VecLookahead<I: Iterator> {
+    iter: I,
+    buffer: Vec<I::Item>,
+}
+
+impl<I: Iterator> VecLookahead<I> {
+    fn peek_n(&mut self, n: usize) -> &[I::Item] {
+        while self.buffer.len() < n {
+            match self.iter.next() {
+                Some(item) => self.buffer.push(item),
+                None => break,
+            }
+        }
+        &self.buffer[..]
+    }
+}
+
+impl<I: Iterator> Iterator for VecLookahead<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() {
+            self.iter.next()
+        } else {
+            Some(self.buffer.remove(0))
+        }
+    }
+}
+
+// Milliseconds of the fastest pass over ITEMS items, peeking `window` ahead every
+// `window` items.
+fn time_pass(window: usize, mut pass: impl FnMut(usize) -> u64) -> f64 {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            black_box(pass(window));
+            start.elapsed().as_secs_f64() * 1000.0
+        })
+        .fold(f64::INFINITY, f64::min)
+}
+
+fn deque_pass(window: usize) -> u64 {
+    let mut items = LookaheadIterator::new(0..ITEMS);
+    let mut sum = 0;
+    while !items.peek_n(window).is_empty() {
+        sum += items.by_ref().take(window).sum::<u64>();
+    }
+    sum
+}
+
+fn vec_pass(window: usize) -> u64 {
+    let mut items = VecLookahead {
+        iter: 0..ITEMS,
+        buffer: vec![],
+    };
+    let mut sum = 0;
+    while !items.peek_n(window).is_empty() {
+        sum += items.by_ref().take(window).sum::<u64>();
+    }
+    sum
+}
+
+fn main() {
+    assert_eq!(deque_pass(1000), vec_pass(1000));
+    println!("{:>8} {:>10} {:>10}", "window", "deque_ms", "vec_ms");
+    for window in [10, 100, 1_000, 10_000] {
+        let deque = time_pass(window, deque_pass);
+        let vec = time_pass(window, vec_pass);
+        println!("{:>8} {:>10.1} {:>10.1}", window, deque, vec);
+    }
+}
@@ -0,0 +1,42 @@
+// Compares the lookup a cache picks for its associativity, a scan of the set's lines
+// up to INDEXED_SET_WAYS and the tag index above, with always using the tag index.
+// Run with `cargo bench`.
+use std::hint::black_box;
+use std::time::Instant;
+
+use cf_qemu_post::cache::Cache;
+
+const CACHE_SIZE: usize = 512 * 1024;
+const ACCESSES: u64 = 5_000_000;
+// the fastest of this many runs is reported, the others are disturbed by noise
+const RUNS: usize = 5;
+
+// Nanoseconds per access of a random mix of loads and stores over twice the cache
+// size, so that about half of them hit.
+fn time_accesses(new_cache: impl Fn() -> Cache) -> f64 {
+    (0..RUNS)
+        .map(|_| time_run(new_cache()))
+        .fold(f64::INFINITY, f64::min)
+}
+
+fn time_run(mut cache: Cache) -> f64 {
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    let start = Instant::now();
+    for _ in 0..ACCESSES {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let address = (state % (2 * CACHE_SIZE as u64)) & !63;
+        black_box(cache.access(address, state & 1 == 0));
+    }
+    start.elapsed().as_nanos() as f64 / ACCESSES as f64
+}
+
+fn main() {
+    println!("{:>5} {:>12} {:>12}", "ways", "default_ns", "indexed_ns");
+    for ways in [8, 16, 32, 64, 128] {
+        let default = time_accesses(|| Cache::new(CACHE_SIZE, 64, ways));
+        let indexed = time_accesses(|| Cache::new(CACHE_SIZE, 64, ways).with_indexed_sets());
+        println!("{:>5} {:>12.1} {:>12.1}", ways, default, indexed);
+    }
+}
@@ -1,123 +1,53 @@
 use std::{
-    io::{BufRead, BufReader, BufWriter, Write},
+    cell::Cell,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    rc::Rc,
     str::FromStr,
 };
 
 use cf_qemu_post::{
-    log_parser::{self},
-    memory_access::{MemRecord, MemoryAccess},
+    cache::{
+        AllocationPolicy, Cache, CacheHierarchy, CacheStats, CoherenceDirectory, CoherenceMode,
+        IndexHash, PAGE_SIZE, PolicyKind, PrefetcherKind, PrivateCaches, Tlb, WritePolicy,
+    },
+    memory_access::MemoryAccess,
+    trace_filter::{FilterOptions, TraceFilter, TraceStats, WarmupUnit, filter_through_cache},
+    trace_format::OutputFormat,
 };
-use clap::Parser;
+use clap::{ArgGroup, Parser};
 
-#[derive(Debug)]
-pub struct Cache {
-    block_size: usize, // in bytes
-    sets: Vec<CacheSet>,
+fn parse_address(text: &str) -> Result<u64, String> {
+    u64::from_str_radix(text.trim_start_matches("0x"), 16)
+        .map_err(|e| format!("invalid address {:?}: {}", text, e))
 }
 
-#[derive(Debug)]
-struct CacheSet {
-    // Each cache line stores an optional tag (here, a u64 representing the block address)
-    lines: Vec<Option<u64>>,
-    // For LRU, we maintain an ordering of indices (least-recently used first)
-    lru_order: Vec<usize>,
-}
-
-impl CacheSet {
-    pub fn new(associativity: usize) -> Self {
-        CacheSet {
-            lines: vec![None; associativity],
-            lru_order: vec![],
-        }
-    }
-
-    /// Returns true if tag hit; false if miss.
-    pub fn access(&mut self, tag: u64) -> bool {
-        if let Some(pos) = self.lines.iter().position(|&line| line == Some(tag)) {
-            // Cache hit: update LRU ordering.
-            self.lru_order.retain(|&i| i != pos);
-            self.lru_order.push(pos);
-            true
-        } else {
-            // Cache miss: evict the least-recently used line.
-            if let Some(free_pos) = self.lines.iter().position(|&line| line.is_none()) {
-                // Found a free line, so use it.
-                self.lines[free_pos] = Some(tag);
-                self.lru_order.push(free_pos);
-            } else {
-                // No free line: evict the least-recently used line.
-                let evict_index = self.lru_order.remove(0);
-                self.lines[evict_index] = Some(tag);
-                self.lru_order.push(evict_index);
-            }
-            false
-        }
-    }
-    // Invalidate a specific block tag in this set (if present).
-    pub fn invalidate(&mut self, tag: u64) {
-        if let Some(pos) = self.lines.iter().position(|&line| line == Some(tag)) {
-            // Remove the line
-            self.lines[pos] = None;
-            // Remove from LRU tracking
-            self.lru_order.retain(|&i| i != pos);
-        }
-    }
+fn parse_rowclone_record(line: &str) -> Result<MemoryAccess, Box<dyn std::error::Error>> {
+    MemoryAccess::from_str(line)
 }
 
-impl Cache {
-    pub fn new(size: usize, block_size: usize, associativity: usize) -> Self {
-        // total number of cache lines = size / block_size
-        // number of sets = (size / block_size) / associativity
-        let num_lines = size / block_size;
-        let num_sets = num_lines / associativity;
-        let sets = (0..num_sets)
-            .map(|_| CacheSet::new(associativity))
-            .collect();
-        Cache { block_size, sets }
-    }
+// Stands in for a trace file with --dry-run and counts the bytes written to it.
+struct CountingSink(Rc<Cell<u64>>);
 
-    /// Simulate an access to the cache.
-    /// Returns true if hit, false if miss.
-    pub fn access(&mut self, address: u64) -> bool {
-        let block_addr = address / (self.block_size as u64);
-        let set_index = (block_addr as usize) % self.sets.len();
-        // The tag can simply be the block_addr
-        self.sets[set_index].access(block_addr)
+impl Write for CountingSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.set(self.0.get() + buf.len() as u64);
+        Ok(buf.len())
     }
 
-    pub fn invalidate_page(&mut self, address: u64) {
-        const PAGE_SIZE: u64 = 4096;
-        assert!(address % PAGE_SIZE == 0);
-
-        // Compute block indices in page
-        let start_block = address / (self.block_size as u64);
-        let end_block = (address + PAGE_SIZE - 1) / (self.block_size as u64);
-
-        for block_addr in start_block..=end_block {
-            let set_index = (block_addr as usize) % self.sets.len();
-            self.sets[set_index].invalidate(block_addr);
-        }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
     }
 }
 
-fn parse_rowclone_record(line: &str) -> Result<MemoryAccess, Box<dyn std::error::Error>> {
-    MemoryAccess::from_str(line)
-}
-
-fn parse_binary_record(line: &str) -> Result<MemoryAccess, Box<dyn std::error::Error>> {
-    let access = log_parser::LogRecord::from_str(line)?;
-    Ok(MemoryAccess::Regular(MemRecord {
-        cpu: access.cpu.into(),
-        address: access.address,
-        insn_count: access.insn_count,
-        store: access.store == 1,
-    }))
-}
-
 #[derive(Parser, Debug)]
 #[command(about)]
+#[command(group(
+    ArgGroup::new("output")
+        .required(true)
+        .args(["log_dir", "merged_output", "dry_run"])
+))]
 struct Args {
-    // Whether the input logs are in binary format
+    // Whether the input is in the binary frame format written by rowclone --binary-out
     #[arg(short, long, default_value_t = false)]
     binary_in: bool,
 
@@ -125,84 +55,1244 @@ struct Args {
     #[arg(short, long, default_value_t = 8)]
     cpus: usize,
 
+    // directory receiving one cpu_N.trace per CPU
     #[arg(short, long)]
-    log_dir: String,
+    log_dir: Option<String>,
+
+    // write a single trace in input order instead, each line prefixed with its CPU
+    #[arg(long)]
+    merged_output: Option<String>,
+
+    // simulate without writing any trace, print the records and bytes every CPU's
+    // trace would have instead
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    // dialect of the text traces
+    #[arg(long, value_enum, default_value_t = OutputFormat::RamulatorInsn)]
+    format: OutputFormat,
+
+    // cycles per instruction used by --format ramulator-cycles and dramsim3
+    #[arg(long, default_value_t = 1.0)]
+    cpi: f64,
+
+    // prefix every text line with the cycles since the previous request of its CPU
+    #[arg(long, default_value_t = false, conflicts_with = "binary_out")]
+    cycles: bool,
+
+    // instructions per cycle used by --cycles
+    #[arg(long, default_value_t = 1.0)]
+    ipc: f64,
+
+    // write fixed-size binary records (see trace_format) instead of ramulator text
+    #[arg(long, default_value_t = false, conflicts_with = "merged_output")]
+    binary_out: bool,
+
+    // trace to simulate, stdin if omitted or `-`
+    input: Option<String>,
+
+    // total cache size in bytes
+    #[arg(long, default_value_t = 512 * 1024)]
+    cache_size: usize,
+
+    // cache block size in bytes
+    #[arg(long, default_value_t = 64)]
+    block_size: usize,
+
+    #[arg(long, default_value_t = 8)]
+    associativity: usize,
+
+    // make the per-CPU cache a single set, overriding --associativity
+    #[arg(long, default_value_t = false)]
+    fully_associative: bool,
+
+    // seed of the random replacement policy (0 selects the default seed)
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    // how every cache level maps blocks to sets
+    #[arg(long, value_enum, default_value_t = IndexHash::None)]
+    index_hash: IndexHash,
+
+    // replacement policy used by every cache level
+    #[arg(long, value_enum, default_value_t = PolicyKind::Lru)]
+    policy: PolicyKind,
+
+    // whether store misses allocate a line in every cache level
+    #[arg(long, value_enum, default_value_t = AllocationPolicy::WriteAllocate)]
+    allocation: AllocationPolicy,
+
+    // in writethrough mode every store is emitted, whether it hits or not
+    #[arg(long, value_enum, default_value_t = WritePolicy::WriteBack)]
+    store_mode: WritePolicy,
+
+    // keep the private caches coherent: invalidate other copies on stores, and with
+    // mesi also write back other CPUs' modified copies on loads
+    #[arg(long, value_enum, default_value_t = CoherenceMode::None)]
+    coherence: CoherenceMode,
+
+    // lines of the fully associative victim cache attached to every cache (0 disables it)
+    #[arg(long, default_value_t = 0)]
+    victim_entries: usize,
+
+    // classify misses as compulsory, capacity or conflict (needs memory per block seen)
+    #[arg(long, default_value_t = false)]
+    classify_misses: bool,
+
+    // make the cache a single one shared by all CPUs instead of one per CPU
+    #[arg(long, default_value_t = false)]
+    shared_llc: bool,
+
+    // ways of the shared cache each CPU may fill, e.g. 4,4,2,2,1,1,1,1
+    #[arg(long, value_delimiter = ',')]
+    way_partition: Vec<usize>,
+
+    // size of a private L1 in front of the cache in bytes (0 disables it)
+    #[arg(long, default_value_t = 0)]
+    l1_size: usize,
+
+    #[arg(long, default_value_t = 8)]
+    l1_associativity: usize,
+
+    // size of an L3 shared by all CPUs in bytes (0 disables it)
+    #[arg(long, visible_alias = "llc-size", default_value_t = 0)]
+    l3_size: usize,
+
+    #[arg(long, visible_alias = "llc-assoc", default_value_t = 16)]
+    l3_assoc: usize,
+
+    // back-invalidate the private caches when the L3 evicts a block
+    #[arg(long)]
+    inclusive_l3: bool,
+
+    // size of rowclones whose record doesn't carry one, e.g. 2097152 for huge pages
+    #[arg(long, default_value_t = PAGE_SIZE)]
+    page_size: u64,
+
+    // number of write-combining store buffer entries per CPU (0 disables it)
+    #[arg(long, default_value_t = 8)]
+    store_buffer_entries: usize,
+
+    // number of outstanding misses per CPU merged with later misses to the same
+    // block (0 disables it)
+    #[arg(long, default_value_t = 0)]
+    mshr_entries: usize,
+
+    // instructions after which an outstanding miss no longer absorbs others
+    #[arg(long, default_value_t = 1000)]
+    mshr_window: u64,
+
+    // entries of the per-CPU TLB translating every access first (0 disables it)
+    #[arg(long, default_value_t = 0)]
+    tlb_entries: usize,
+
+    #[arg(long, default_value_t = 4)]
+    tlb_assoc: usize,
+
+    // loads emitted for the page walk of a TLB miss
+    #[arg(long, default_value_t = 4)]
+    page_walk_loads: u32,
+
+    // don't translate rowclone source and destination pages
+    #[arg(long, default_value_t = false)]
+    tlb_bypass_rowclones: bool,
+
+    // width of a store buffer entry in bytes
+    #[arg(long, default_value_t = 64)]
+    store_buffer_entry_size: usize,
+
+    // prefetcher of the per-CPU cache
+    #[arg(long, value_enum, default_value_t = PrefetcherKind::None)]
+    prefetcher: PrefetcherKind,
+
+    // maximum number of blocks fetched per prefetch trigger
+    #[arg(long, default_value_t = 1)]
+    prefetch_degree: usize,
+
+    // emit prefetch fills that reach memory as loads
+    #[arg(long, default_value_t = false)]
+    emit_prefetches: bool,
+
+    // emit writebacks for dirty lines evicted by an access
+    #[arg(long, visible_alias = "emit-writebacks", default_value_t = false)]
+    writebacks: bool,
+
+    // emit writebacks for dirty lines invalidated by a rowclone
+    #[arg(long, default_value_t = false)]
+    rowclone_writebacks: bool,
+
+    // invalidate a rowclone's destination in every CPU's private caches that hold
+    // it, not just in the issuing CPU's
+    #[arg(long, default_value_t = false)]
+    coherent: bool,
+
+    // write back and clean the dirty lines of a rowclone's source before the copy
+    #[arg(long, default_value_t = false)]
+    flush_rowclone_source: bool,
+
+    // length of the warmup, during which accesses only warm up the caches: nothing
+    // is emitted or counted and bubbles start at the end of the warmup
+    #[arg(long, default_value_t = 0)]
+    warmup: u64,
+
+    // whether --warmup counts input records or instructions
+    #[arg(long, value_enum, default_value_t = WarmupUnit::Records)]
+    warmup_unit: WarmupUnit,
+
+    // bubble of records whose insn_count is below the previous one of their CPU
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u64).range(0..=1))]
+    out_of_order_bubble: u64,
+
+    // write per-CPU statistics of the emitted traces as CSV to this file
+    #[arg(long)]
+    stats_file: Option<String>,
+
+    // write the valid and dirty lines invalidated by every rowclone as CSV to this file
+    #[arg(long)]
+    rowclone_cost_file: Option<String>,
+
+    // only simulate records of these CPUs, may be repeated; no traces are written
+    // for the others
+    #[arg(long)]
+    cpu: Vec<usize>,
+
+    // write the number of accesses to every set of every cache as CSV to this file
+    #[arg(long)]
+    dump_set_histogram: Option<String>,
+
+    // print the sets holding this block to stderr on every access to it
+    #[arg(long, value_parser = parse_address)]
+    dump_on: Option<u64>,
+
+    // accesses at or above this address are counted as kernel space in the statistics
+    #[arg(long, value_parser = parse_address, default_value = "0xffff800000000000")]
+    kernel_threshold: u64,
+
+    // additionally simulate a baseline that performs rowclones as regular copies and
+    // print a comparison table to stderr
+    #[arg(long, default_value_t = false)]
+    compare_baseline: bool,
+
+    // write the --compare-baseline report as JSON to this file
+    #[cfg(feature = "serde")]
+    #[arg(long, requires = "compare_baseline")]
+    compare_json: Option<String>,
+
+    // print progress to stderr every this many input records (0 disables it)
+    #[arg(long, default_value_t = 10_000_000)]
+    progress_interval: u64,
+
+    // don't simulate any caches, every access and rowclone is written to the traces
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["compare_baseline", "dump_set_histogram", "dump_on"]
+    )]
+    no_cache: bool,
 }
 
-fn ramulator_mem_format(rec: &MemRecord, prev_insn_count: &u64) -> String {
-    let bubble = rec.insn_count - prev_insn_count;
-    if rec.store {
-        format!("{} -1 0x{:016x}", bubble, rec.address)
+fn validate_partition(args: &Args) -> Result<(), String> {
+    if !args.shared_llc {
+        return Err("only the --shared-llc cache can be partitioned".into());
+    }
+    if args.way_partition.len() != args.cpus {
+        return Err(format!(
+            "{} partitions given for {} CPUs",
+            args.way_partition.len(),
+            args.cpus
+        ));
+    }
+    if args.way_partition.contains(&0) {
+        return Err("every CPU needs at least one way".into());
+    }
+    let ways: usize = args.way_partition.iter().sum();
+    if ways != args.associativity {
+        return Err(format!(
+            "partitions cover {} ways but the cache has {}",
+            ways, args.associativity
+        ));
+    }
+    Ok(())
+}
+
+fn new_cache(args: &Args, size: usize, associativity: usize) -> Cache {
+    let cache = Cache::with_policy(size, args.block_size, associativity, args.policy)
+        .with_page_size(args.page_size)
+        .with_allocation(args.allocation)
+        .with_write_policy(args.store_mode)
+        .with_seed(args.seed)
+        .with_index_hash(args.index_hash)
+        .with_victim_entries(args.victim_entries);
+    if args.classify_misses {
+        cache.with_miss_classification()
     } else {
-        format!("{} 0x{:016x}", bubble, rec.address)
+        cache
     }
 }
 
-fn main() {
-    let args = Args::parse();
-    let reader = BufReader::new(std::io::stdin());
-    let mut writers: Vec<BufWriter<std::fs::File>> = (0..args.cpus)
-        .map(|cpu_id| {
-            let filename = format!("{}/cpu_{}.trace", args.log_dir, cpu_id);
-            let file = std::fs::File::create(&filename)
-                .unwrap_or_else(|_| panic!("Failed to create output file: {}", filename));
-            BufWriter::new(file)
+// The baseline performs rowclones as regular copies and writes no traces.
+fn filter_options(args: &Args, baseline: bool) -> FilterOptions {
+    FilterOptions {
+        cpus: args.cpus,
+        store_buffer_entries: args.store_buffer_entries,
+        store_buffer_entry_size: args.store_buffer_entry_size,
+        mshr_entries: args.mshr_entries,
+        mshr_window: args.mshr_window,
+        tlb_entries: args.tlb_entries,
+        tlb_assoc: args.tlb_assoc,
+        page_walk_loads: args.page_walk_loads,
+        tlb_bypass_rowclones: args.tlb_bypass_rowclones,
+        expand_rowclones: baseline,
+        writebacks: args.writebacks,
+        rowclone_writebacks: args.rowclone_writebacks,
+        coherent_rowclones: args.coherent,
+        flush_rowclone_source: args.flush_rowclone_source,
+        emit_prefetches: args.emit_prefetches,
+        write_through: args.store_mode == WritePolicy::WriteThrough,
+        page_size: args.page_size,
+        warmup: args.warmup,
+        warmup_unit: args.warmup_unit,
+        dump_on: args.dump_on.filter(|_| !baseline),
+        kernel_threshold: args.kernel_threshold,
+        merged: args.merged_output.is_some() && !baseline,
+        binary_out: args.binary_out,
+        format: args.format,
+        cpi: args.cpi,
+        ipc: args.cycles.then_some(args.ipc),
+        out_of_order_bubble: args.out_of_order_bubble,
+        rowclone_costs: None,
+        progress_interval: if baseline { 0 } else { args.progress_interval },
+    }
+}
+
+fn new_hierarchy(args: &Args) -> CacheHierarchy {
+    if args.shared_llc {
+        let mut llc = new_cache(args, args.cache_size, args.associativity)
+            .with_prefetcher(args.prefetcher)
+            .with_prefetch_degree(args.prefetch_degree);
+        if !args.way_partition.is_empty() {
+            llc = llc.with_way_partition(&args.way_partition);
+        }
+        return CacheHierarchy::shared(args.cpus, llc);
+    }
+    // Create an L2 cache per CPU (512KB, 64B blocks, 8-way associative by default),
+    // behind an optional L1 that filters the hits before they reach it
+    let private = (0..args.cpus)
+        .map(|_| {
+            let l1 =
+                (args.l1_size > 0).then(|| new_cache(args, args.l1_size, args.l1_associativity));
+            let l2 = new_cache(args, args.cache_size, args.associativity)
+                .with_prefetcher(args.prefetcher)
+                .with_prefetch_degree(args.prefetch_degree);
+            PrivateCaches::new(l1, l2)
         })
         .collect();
-    let input_parser = if args.binary_in {
-        parse_binary_record
+    let l3 = (args.l3_size > 0).then(|| new_cache(args, args.l3_size, args.l3_assoc));
+    let caches = CacheHierarchy::new(private, l3).with_coherence(args.coherence);
+    if args.inclusive_l3 {
+        caches.with_inclusive_l3()
+    } else {
+        caches
+    }
+}
+
+fn hit_rate(hits: u64, accesses: u64) -> f64 {
+    if accesses == 0 {
+        0.0
+    } else {
+        hits as f64 / accesses as f64
+    }
+}
+
+fn average_bubble(stats: &TraceStats) -> f64 {
+    if stats.requests == 0 {
+        0.0
     } else {
-        parse_rowclone_record
+        stats.bubble_sum as f64 / stats.requests as f64
+    }
+}
+
+fn write_stats_csv(path: &str, stats: &[TraceStats]) -> std::io::Result<()> {
+    let mut out = BufWriter::new(std::fs::File::create(path)?);
+    writeln!(
+        out,
+        "cpu,accesses,hits,misses,rowclones,records,first_insn,last_insn,avg_bubble,\
+         kernel_accesses,kernel_hits,user_accesses,user_hits"
+    )?;
+    let mut total = TraceStats::default();
+    for (cpu, cpu_stats) in stats.iter().enumerate() {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{:.4},{},{},{},{}",
+            cpu,
+            cpu_stats.accesses,
+            cpu_stats.hits,
+            cpu_stats.accesses - cpu_stats.hits,
+            cpu_stats.rowclones,
+            cpu_stats.requests,
+            cpu_stats
+                .first_insn
+                .map_or(String::new(), |i| i.to_string()),
+            cpu_stats
+                .first_insn
+                .map_or(String::new(), |_| cpu_stats.last_insn.to_string()),
+            average_bubble(cpu_stats),
+            cpu_stats.kernel_accesses,
+            cpu_stats.kernel_hits,
+            cpu_stats.accesses - cpu_stats.kernel_accesses,
+            cpu_stats.hits - cpu_stats.kernel_hits
+        )?;
+        total.accesses += cpu_stats.accesses;
+        total.hits += cpu_stats.hits;
+        total.kernel_accesses += cpu_stats.kernel_accesses;
+        total.kernel_hits += cpu_stats.kernel_hits;
+        total.rowclones += cpu_stats.rowclones;
+        total.requests += cpu_stats.requests;
+        total.bubble_sum += cpu_stats.bubble_sum;
+        if let Some(first) = cpu_stats.first_insn {
+            total.first_insn = Some(total.first_insn.map_or(first, |f| f.min(first)));
+            total.last_insn = total.last_insn.max(cpu_stats.last_insn);
+        }
+    }
+    writeln!(
+        out,
+        "total,{},{},{},{},{},{},{},{:.4},{},{},{},{}",
+        total.accesses,
+        total.hits,
+        total.accesses - total.hits,
+        total.rowclones,
+        total.requests,
+        total.first_insn.map_or(String::new(), |i| i.to_string()),
+        total
+            .first_insn
+            .map_or(String::new(), |_| total.last_insn.to_string()),
+        average_bubble(&total),
+        total.kernel_accesses,
+        total.kernel_hits,
+        total.accesses - total.kernel_accesses,
+        total.hits - total.kernel_hits
+    )?;
+    out.flush()
+}
+
+fn write_set_histogram(path: &str, caches: &CacheHierarchy, llc_name: &str) -> std::io::Result<()> {
+    let mut levels: Vec<(&str, String, &Cache)> = vec![];
+    for (cpu, private) in caches.private().iter().enumerate() {
+        if let Some(l1) = private.l1() {
+            levels.push(("L1", cpu.to_string(), l1));
+        }
+        levels.push(("L2", cpu.to_string(), private.l2()));
+    }
+    if let Some(l3) = caches.l3() {
+        levels.push((llc_name, String::new(), l3));
+    }
+    let mut out = BufWriter::new(std::fs::File::create(path)?);
+    // every CPU's private caches share the geometry, one formula per level is enough
+    let mut described = vec![];
+    for (name, _, cache) in &levels {
+        if !described.contains(name) {
+            writeln!(out, "# {}: set = {}", name, cache.set_index_formula())?;
+            described.push(name);
+        }
+    }
+    writeln!(out, "level,cpu,set,accesses")?;
+    for (name, cpu, cache) in &levels {
+        for (set, accesses) in cache.set_access_histogram().iter().enumerate() {
+            writeln!(out, "{},{},{},{}", name, cpu, set, accesses)?;
+        }
+    }
+    out.flush()
+}
+
+// Trace totals of one configuration in the --compare-baseline report.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+struct ModeTotals {
+    requests: u64,
+    bubble_sum: u64,
+    rowclones: u64,
+}
+
+// Rowclone minus baseline totals.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+struct Delta {
+    requests: i64,
+    bubble_sum: i64,
+}
+
+// One CPU's row of the --compare-baseline report.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+struct Comparison {
+    cpu: usize,
+    rowclone: ModeTotals,
+    baseline: ModeTotals,
+    delta: Delta,
+}
+
+fn compare(rowclone: &[TraceStats], baseline: &[TraceStats]) -> Vec<Comparison> {
+    let totals = |stats: &TraceStats| ModeTotals {
+        requests: stats.requests,
+        bubble_sum: stats.bubble_sum,
+        rowclones: stats.rowclones,
     };
+    rowclone
+        .iter()
+        .zip(baseline)
+        .enumerate()
+        .map(|(cpu, (rc, base))| Comparison {
+            cpu,
+            rowclone: totals(rc),
+            baseline: totals(base),
+            delta: Delta {
+                requests: rc.requests as i64 - base.requests as i64,
+                bubble_sum: rc.bubble_sum as i64 - base.bubble_sum as i64,
+            },
+        })
+        .collect()
+}
 
-    // Create an L2 cache: 512KB, 64B blocks, 8-way associative.
-    // no need for an L1 since we model inclusive cache and only care about
-    // memory accesses
-    let mut caches: Vec<Cache> = (0..args.cpus)
-        .map(|_| Cache::new(512 * 1024, 64, 8))
-        .collect();
+fn print_comparison(report: &[Comparison]) {
+    eprintln!(
+        "{:>4} {:>12} {:>12} {:>12} {:>16} {:>16} {:>16} {:>10}",
+        "cpu",
+        "requests",
+        "base_req",
+        "delta_req",
+        "bubble_sum",
+        "base_bubbles",
+        "delta_bubbles",
+        "rowclones"
+    );
+    for row in report {
+        eprintln!(
+            "{:>4} {:>12} {:>12} {:>12} {:>16} {:>16} {:>16} {:>10}",
+            row.cpu,
+            row.rowclone.requests,
+            row.baseline.requests,
+            row.delta.requests,
+            row.rowclone.bubble_sum,
+            row.baseline.bubble_sum,
+            row.delta.bubble_sum,
+            row.rowclone.rowclones
+        );
+    }
+}
+
+fn print_cache_stats(name: &str, caches: &[&Cache]) {
+    eprintln!(
+        "{:>5} {:>12} {:>12} {:>12} {:>12} {:>13} {:>12} {:>12} {:>12} {:>12} {:>12} {:>9}",
+        name,
+        "accesses",
+        "hits",
+        "misses",
+        "evictions",
+        "invalidations",
+        "coh_inv",
+        "victim_hits",
+        "prefetches",
+        "useful_pf",
+        "useless_pf",
+        "miss_rate"
+    );
+    let mut total = CacheStats::default();
+    for (cpu, cache) in caches.iter().enumerate() {
+        total.merge(cache.stats());
+        print_stats_row(&cpu.to_string(), cache.stats());
+    }
+    print_stats_row("total", &total);
+    let classified = total.compulsory_misses + total.capacity_misses + total.conflict_misses;
+    if classified > 0 {
+        eprintln!(
+            "{:>5} misses: {} compulsory, {} capacity, {} conflict",
+            name, total.compulsory_misses, total.capacity_misses, total.conflict_misses
+        );
+    }
+    if total.cross_cpu_evictions > 0 {
+        eprintln!(
+            "{:>5} evictions of lines filled by another CPU: {}",
+            name, total.cross_cpu_evictions
+        );
+    }
+    if total.coherence_downgrades > 0 {
+        eprintln!(
+            "{:>5} modified lines downgraded to shared: {}",
+            name, total.coherence_downgrades
+        );
+    }
+    if total.buddy_prefetches > 0 {
+        eprintln!(
+            "{:>5} adjacent blocks prefetched: {}",
+            name, total.buddy_prefetches
+        );
+    }
+}
+
+fn print_stats_row(label: &str, stats: &CacheStats) {
+    eprintln!(
+        "{:>5} {:>12} {:>12} {:>12} {:>12} {:>13} {:>12} {:>12} {:>12} {:>12} {:>12} {:>9.4}",
+        label,
+        stats.accesses,
+        stats.hits,
+        stats.misses,
+        stats.evictions,
+        stats.invalidations,
+        stats.coherence_invalidations,
+        stats.victim_hits,
+        stats.prefetches,
+        stats.useful_prefetches,
+        stats.useless_prefetches,
+        stats.miss_rate()
+    );
+}
+
+fn main() {
+    let mut args = Args::parse();
+    if args.fully_associative && args.block_size > 0 {
+        args.associativity = args.cache_size / args.block_size;
+    }
+    if !args.page_size.is_power_of_two() || args.page_size < args.block_size as u64 {
+        eprintln!(
+            "Invalid page size {}: must be a power of two no smaller than a block",
+            args.page_size
+        );
+        std::process::exit(1);
+    }
+    if !(args.cpi.is_finite() && args.cpi > 0.0) {
+        eprintln!("Invalid CPI {}: must be positive", args.cpi);
+        std::process::exit(1);
+    }
+    if !(args.ipc.is_finite() && args.ipc > 0.0) {
+        eprintln!("Invalid IPC {}: must be positive", args.ipc);
+        std::process::exit(1);
+    }
+    if !args.store_buffer_entry_size.is_power_of_two() {
+        eprintln!(
+            "Invalid store buffer entry size {}: must be a power of two",
+            args.store_buffer_entry_size
+        );
+        std::process::exit(1);
+    }
+    if let Err(e) = Cache::check_geometry(args.cache_size, args.block_size, args.associativity) {
+        eprintln!("Invalid cache configuration: {}", e);
+        std::process::exit(1);
+    }
+    if args.shared_llc && (args.l1_size > 0 || args.l3_size > 0) {
+        eprintln!("--shared-llc can't be combined with an L1 or L3");
+        std::process::exit(1);
+    }
+    if args.inclusive_l3 && args.l3_size == 0 {
+        eprintln!("--inclusive-l3 needs an L3, see --l3-size");
+        std::process::exit(1);
+    }
+    if args.coherence != CoherenceMode::None && args.cpus > CoherenceDirectory::MAX_CPUS {
+        eprintln!(
+            "--coherence supports at most {} CPUs",
+            CoherenceDirectory::MAX_CPUS
+        );
+        std::process::exit(1);
+    }
+    if let Some(cpu) = args.cpu.iter().find(|&&cpu| cpu >= args.cpus) {
+        eprintln!("CPU {} is out of range for {} CPUs", cpu, args.cpus);
+        std::process::exit(1);
+    }
+    if !args.way_partition.is_empty() {
+        if let Err(e) = validate_partition(&args) {
+            eprintln!("Invalid way partition: {}", e);
+            std::process::exit(1);
+        }
+        eprintln!("Way partition: {:?}", args.way_partition);
+    }
+    if args.no_cache {
+        eprintln!("Cache: none, every record is written to the traces");
+    } else {
+        eprintln!(
+            "Cache: {} bytes, {}B blocks, {}-way, {} sets {}, {:?} replacement",
+            args.cache_size,
+            args.block_size,
+            args.associativity,
+            args.cache_size / args.block_size / args.associativity,
+            if args.shared_llc {
+                "shared by all CPUs"
+            } else {
+                "per CPU"
+            },
+            args.policy
+        );
+    }
+    if args.tlb_entries > 0
+        && let Err(e) = Tlb::new(args.tlb_entries, args.tlb_assoc)
+    {
+        eprintln!("Invalid TLB configuration: {}", e);
+        std::process::exit(1);
+    }
+    if args.l1_size > 0 {
+        if let Err(e) = Cache::check_geometry(args.l1_size, args.block_size, args.l1_associativity)
+        {
+            eprintln!("Invalid L1 configuration: {}", e);
+            std::process::exit(1);
+        }
+        eprintln!(
+            "L1: {} bytes, {}-way, {} sets per CPU",
+            args.l1_size,
+            args.l1_associativity,
+            args.l1_size / args.block_size / args.l1_associativity
+        );
+    }
+    if args.l3_size > 0 {
+        if let Err(e) = Cache::check_geometry(args.l3_size, args.block_size, args.l3_assoc) {
+            eprintln!("Invalid L3 configuration: {}", e);
+            std::process::exit(1);
+        }
+        eprintln!(
+            "L3: {} bytes, {}-way, {} sets shared by all CPUs, {}",
+            args.l3_size,
+            args.l3_assoc,
+            args.l3_size / args.block_size / args.l3_assoc,
+            if args.inclusive_l3 {
+                "inclusive"
+            } else {
+                "non-inclusive"
+            }
+        );
+    }
+    let input = match open_input(args.input.as_deref()) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = simulate(&args, input, &mut std::io::stdout()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
 
-    let mut lines = reader.lines();
-    let mut first = vec![true; args.cpus];
-    let mut prev_insn_count = vec![0; args.cpus];
-
-    while let Some(Ok(line)) = lines.next() {
-        if let Ok(rec) = input_parser(&line) {
-            match rec {
-                MemoryAccess::Regular(mem) => {
-                    let cpu = mem.cpu;
-                    if first[cpu] {
-                        prev_insn_count[cpu] = mem.insn_count;
-                        first[cpu] = false;
-                    }
-                    if !caches[cpu].access(mem.address) {
-                        writeln!(
-                            writers[cpu],
-                            "{}",
-                            ramulator_mem_format(&mem, &prev_insn_count[cpu])
-                        );
-                        prev_insn_count[cpu] = mem.insn_count;
-                    }
+// Stdin without an input or with `-`.
+fn open_input(path: Option<&str>) -> Result<Box<dyn Read>, String> {
+    match path {
+        None | Some("-") => Ok(Box::new(std::io::stdin())),
+        Some(path) => match std::fs::File::open(path) {
+            Ok(file) => Ok(Box::new(file)),
+            Err(e) => Err(format!("Failed to open input {}: {}", path, e)),
+        },
+    }
+}
+
+// Simulates the input with checked arguments, writing the --dry-run summary to
+// `summary`.
+fn simulate(args: &Args, input: Box<dyn Read>, summary: &mut impl Write) -> Result<(), String> {
+    let reader = BufReader::new(input);
+    let included = |cpu: usize| args.cpu.is_empty() || args.cpu.contains(&cpu);
+    let create = |filename: &str| -> Result<Box<dyn Write>, String> {
+        match std::fs::File::create(filename) {
+            Ok(file) => Ok(Box::new(BufWriter::new(file))),
+            Err(e) => Err(format!("Failed to create {}: {}", filename, e)),
+        }
+    };
+    let trace_bytes: Vec<Rc<Cell<u64>>> = (0..args.cpus).map(|_| Rc::default()).collect();
+    let writers: Vec<Box<dyn Write>> = match (&args.merged_output, &args.log_dir) {
+        _ if args.dry_run => trace_bytes
+            .iter()
+            .map(|bytes| -> Box<dyn Write> { Box::new(CountingSink(bytes.clone())) })
+            .collect(),
+        (Some(filename), _) => vec![create(filename)?],
+        (None, Some(log_dir)) => (0..args.cpus)
+            .map(|cpu_id| -> Result<Box<dyn Write>, String> {
+                if !included(cpu_id) {
+                    return Ok(Box::new(std::io::sink()));
                 }
-                MemoryAccess::Rowclone(rc) => {
-                    let cpu = rc.cpu;
-                    if first[cpu] {
-                        prev_insn_count[cpu] = rc.insn_count;
-                        first[cpu] = false;
-                    }
-                    for cache in &mut caches {
-                        cache.invalidate_page(rc.to);
-                    }
-                    writeln!(
-                        writers[cpu],
-                        "{} 0x{:016x} 0x{:016x}",
-                        rc.insn_count - prev_insn_count[cpu],
-                        rc.from,
-                        rc.to,
+                create(&format!("{}/cpu_{}.trace", log_dir, cpu_id))
+            })
+            .collect::<Result<_, _>>()?,
+        (None, None) => unreachable!("clap requires an output"),
+    };
+    let mut options = filter_options(args, false);
+    if let Some(path) = args.rowclone_cost_file.as_deref() {
+        let created = std::fs::File::create(path).and_then(|file| {
+            let mut out = BufWriter::new(file);
+            writeln!(out, "insn_count,cpu,to,lines_invalidated,dirty_lines")?;
+            Ok(out)
+        });
+        match created {
+            Ok(out) => options.rowclone_costs = Some(Box::new(out)),
+            Err(e) => return Err(format!("Failed to create {}: {}", path, e)),
+        }
+    }
+    let mut baseline = args.compare_baseline.then(|| {
+        let sinks = (0..args.cpus).map(|_| std::io::sink()).collect();
+        TraceFilter::new(Some(new_hierarchy(args)), sinks, filter_options(args, true))
+            .expect("TLB configuration checked above")
+    });
+
+    let records: Box<dyn Iterator<Item = MemoryAccess>> = if args.binary_in {
+        let mut reader = reader;
+        Box::new(std::iter::from_fn(move || {
+            MemoryAccess::deserialize(&mut reader).unwrap_or_else(|e| {
+                eprintln!("stopping at malformed binary record: {}", e);
+                None
+            })
+        }))
+    } else {
+        Box::new(reader.lines().map_while(Result::ok).filter_map(|line| {
+            parse_rowclone_record(&line)
+                .map_err(|e| eprintln!("skipping malformed record {:?}: {}", line, e))
+                .ok()
+        }))
+    };
+    // the baseline sees the same records
+    let records = records.filter(|rec| included(rec.cpu())).inspect(|rec| {
+        if let Some(baseline) = baseline.as_mut() {
+            baseline.process(rec).expect("the baseline writes to sinks");
+        }
+    });
+    let caches = (!args.no_cache).then(|| new_hierarchy(args));
+    let sim = filter_through_cache(records, caches, writers, options)
+        .map_err(|e| format!("Error: {}", e))?;
+
+    if args.dry_run {
+        write_dry_run_summary(summary, sim.stats(), &trace_bytes)
+            .map_err(|e| format!("Failed to print the summary: {}", e))?;
+    }
+
+    if let Some(path) = args.stats_file.as_deref() {
+        write_stats_csv(path, sim.stats())
+            .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    }
+
+    if let Some(path) = args.dump_set_histogram.as_deref()
+        && let Some(caches) = sim.caches()
+    {
+        let llc_name = if args.shared_llc { "LLC" } else { "L3" };
+        write_set_histogram(path, caches, llc_name)
+            .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    }
+
+    for (cpu, stats) in sim.stats().iter().enumerate() {
+        if stats.accesses == 0 {
+            continue;
+        }
+        let (user_hits, user_accesses) = (
+            stats.hits - stats.kernel_hits,
+            stats.accesses - stats.kernel_accesses,
+        );
+        eprintln!(
+            "cpu {}: kernel hit rate {:.4} ({} of {}), user hit rate {:.4} ({} of {})",
+            cpu,
+            hit_rate(stats.kernel_hits, stats.kernel_accesses),
+            stats.kernel_hits,
+            stats.kernel_accesses,
+            hit_rate(user_hits, user_accesses),
+            user_hits,
+            user_accesses
+        );
+    }
+
+    let out_of_order: u64 = sim.stats().iter().map(|stats| stats.out_of_order).sum();
+    if out_of_order > 0 {
+        eprintln!("Out-of-order records: {}", out_of_order);
+    }
+
+    if args.store_buffer_entries > 0 {
+        for (cpu, buffer) in sim.store_buffers().iter().enumerate() {
+            eprintln!(
+                "cpu {}: {} stores coalesced into {} drains (ratio {:.2})",
+                cpu,
+                buffer.stores(),
+                buffer.drained(),
+                buffer.coalescing_ratio()
+            );
+        }
+    }
+
+    if args.writebacks || args.rowclone_writebacks || args.flush_rowclone_source {
+        for (cpu, stats) in sim.stats().iter().enumerate() {
+            eprintln!("cpu {}: {} writebacks emitted", cpu, stats.writebacks);
+        }
+    }
+
+    if args.flush_rowclone_source {
+        for (cpu, stats) in sim.stats().iter().enumerate() {
+            let per_rowclone = if stats.rowclones == 0 {
+                0.0
+            } else {
+                stats.source_flushes as f64 / stats.rowclones as f64
+            };
+            eprintln!(
+                "cpu {}: {} dirty source lines flushed for {} rowclones ({:.2} per rowclone)",
+                cpu, stats.source_flushes, stats.rowclones, per_rowclone
+            );
+        }
+    }
+
+    if args.tlb_entries > 0 {
+        for (cpu, tlb) in sim.tlbs().iter().enumerate() {
+            let stats = tlb.stats();
+            eprintln!(
+                "cpu {}: TLB hit rate {:.4} ({} of {} accesses)",
+                cpu,
+                1.0 - stats.miss_rate(),
+                stats.hits,
+                stats.accesses
+            );
+        }
+    }
+
+    if args.mshr_entries > 0 {
+        for (cpu, mshr) in sim.mshrs().iter().enumerate() {
+            eprintln!(
+                "cpu {}: {} of {} misses coalesced by the MSHRs, {} found them full",
+                cpu,
+                mshr.secondary_misses(),
+                mshr.misses(),
+                mshr.full()
+            );
+        }
+    }
+
+    if let Some(caches) = sim.caches() {
+        let private = caches.private();
+        let l1s: Vec<&Cache> = private.iter().filter_map(|c| c.l1()).collect();
+        if !l1s.is_empty() {
+            print_cache_stats("L1", &l1s);
+        }
+        let l2s: Vec<&Cache> = private.iter().map(|c| c.l2()).collect();
+        if !l2s.is_empty() {
+            print_cache_stats("L2", &l2s);
+        }
+        if let Some(l3) = caches.l3() {
+            print_cache_stats(if args.shared_llc { "LLC" } else { "L3" }, &[l3]);
+            if args.shared_llc {
+                let occupancy = l3.occupancy();
+                for cpu in 0..args.cpus {
+                    eprintln!(
+                        "cpu {}: {} LLC lines",
+                        cpu,
+                        occupancy.get(cpu).copied().unwrap_or(0)
                     );
-                    prev_insn_count[cpu] = rc.insn_count;
                 }
             }
         }
     }
+
+    if let Some(mut baseline) = baseline {
+        baseline.finish().expect("the baseline writes to sinks");
+        let report = compare(sim.stats(), baseline.stats());
+        print_comparison(&report);
+        #[cfg(feature = "serde")]
+        if let Some(path) = args.compare_json.as_deref() {
+            let written = std::fs::File::create(path)
+                .map_err(serde_json::Error::io)
+                .and_then(|file| serde_json::to_writer_pretty(BufWriter::new(file), &report));
+            written.map_err(|e| format!("Failed to write {}: {}", path, e))?;
+        }
+    }
+    Ok(())
+}
+
+fn write_dry_run_summary(
+    out: &mut impl Write,
+    stats: &[TraceStats],
+    trace_bytes: &[Rc<Cell<u64>>],
+) -> std::io::Result<()> {
+    let (mut records, mut bytes) = (0, 0);
+    for (cpu, stats) in stats.iter().enumerate() {
+        writeln!(
+            out,
+            "cpu {}: {} records, {} bytes",
+            cpu,
+            stats.requests,
+            trace_bytes[cpu].get()
+        )?;
+        records += stats.requests;
+        bytes += trace_bytes[cpu].get();
+    }
+    writeln!(out, "total: {} records, {} bytes", records, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A load of the source, a 4KB copy and a load of the destination.
+    const ONE_LARGE_COPY: &str = "\
+        10,0,0,0,0x200000,3
+        100,1,0,0,0x200000,0x400000,4096
+        200,0,0,0,0x400000,3
+    ";
+
+    fn run(args: &Args, baseline: bool) -> Vec<TraceStats> {
+        let records = ONE_LARGE_COPY
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| parse_rowclone_record(line).unwrap());
+        let writers = vec![std::io::sink()];
+        let caches = Some(new_hierarchy(args));
+        let options = filter_options(args, baseline);
+        let filter = filter_through_cache(records, caches, writers, options).unwrap();
+        filter.stats().to_vec()
+    }
+
+    #[test]
+    fn rowclones_save_the_requests_of_a_large_copy() {
+        let args = Args::parse_from(["cache", "--cpus", "1", "--dry-run"]);
+        let report = compare(&run(&args, false), &run(&args, true));
+        let row = &report[0];
+        // the load of the source, the rowclone and the load of the destination
+        assert_eq!(row.rowclone.requests, 3);
+        assert_eq!(row.rowclone.rowclones, 1);
+        // the copy loads 63 more source blocks and stores 64 destination blocks,
+        // after which the destination load hits
+        assert_eq!(row.baseline.requests, 128);
+        assert_eq!(row.baseline.rowclones, 0);
+        assert_eq!(row.delta.requests, -125);
+    }
+
+    #[test]
+    fn the_partition_must_cover_every_way() {
+        let partitioned = |ways: &str| {
+            Args::parse_from([
+                "cache",
+                "--dry-run",
+                "--shared-llc",
+                "--cpus",
+                "2",
+                "--associativity",
+                "8",
+                "--way-partition",
+                ways,
+            ])
+        };
+        assert_eq!(
+            validate_partition(&partitioned("4,2")),
+            Err("partitions cover 6 ways but the cache has 8".to_string())
+        );
+        assert_eq!(validate_partition(&partitioned("4,4")), Ok(()));
+    }
+
+    #[test]
+    fn set_histogram_lists_every_set_of_every_level() {
+        let private = PrivateCaches::new(Some(Cache::new(128, 64, 1)), Cache::new(256, 64, 1));
+        let mut caches = CacheHierarchy::new(vec![private], Some(Cache::new(512, 64, 4)));
+        for block in [0, 1, 5, 0] {
+            caches.access(0, block * 64, false);
+        }
+        let path = std::env::temp_dir().join(format!("set_histogram_{}.csv", std::process::id()));
+        write_set_histogram(path.to_str().unwrap(), &caches, "L3").unwrap();
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // the second access to block 0 hits in the L1 and never reaches the L2
+        assert_eq!(
+            csv,
+            "\
+# L1: set = (address >> 6) % 2
+# L2: set = (address >> 6) % 4
+# L3: set = (address >> 6) % 2
+level,cpu,set,accesses
+L1,0,0,2
+L1,0,1,2
+L2,0,0,1
+L2,0,1,2
+L2,0,2,0
+L2,0,3,0
+L3,,0,1
+L3,,1,2
+"
+        );
+    }
+
+    // An empty directory for the outputs of one test.
+    fn output_dir(test: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("cache_{}_{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn args(flags: &[&str]) -> Args {
+        Args::parse_from(["cache"].iter().chain(flags))
+    }
+
+    // CPU 0 loads two blocks and CPU 1 one, and each CPU hits its first block again.
+    const TWO_CPUS: &str = "\
+        10,0,0,0,0x1000,3\n\
+        12,0,0,1,0x2000,3\n\
+        15,0,0,0,0x1008,3\n\
+        20,0,1,1,0x2008,3\n\
+        30,0,0,0,0x3000,3\n";
+
+    #[test]
+    fn traces_are_read_from_an_input_file() {
+        let dir = output_dir("input_file");
+        let input = dir.join("input.csv");
+        std::fs::write(&input, TWO_CPUS).unwrap();
+        let log_dir = dir.to_str().unwrap();
+        let input = open_input(Some(input.to_str().unwrap())).unwrap();
+        simulate(
+            &args(&["--cpus", "2", "--log-dir", log_dir]),
+            input,
+            &mut vec![],
+        )
+        .unwrap();
+        let trace = |cpu| std::fs::read_to_string(dir.join(format!("cpu_{}.trace", cpu))).unwrap();
+        assert_eq!(trace(0), "0 0x0000000000001000\n20 0x0000000000003000\n");
+        assert_eq!(trace(1), "0 0x0000000000002000\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_inputs_and_outputs_name_their_path() {
+        assert!(
+            open_input(Some("/nonexistent/input.csv"))
+                .err()
+                .unwrap()
+                .starts_with("Failed to open input /nonexistent/input.csv: ")
+        );
+        let flags = ["--cpus", "1", "--log-dir", "/nonexistent"];
+        assert!(
+            simulate(&args(&flags), Box::new(TWO_CPUS.as_bytes()), &mut vec![])
+                .unwrap_err()
+                .starts_with("Failed to create /nonexistent/cpu_0.trace: ")
+        );
+    }
+
+    #[test]
+    fn merged_output_interleaves_the_cpus_in_input_order() {
+        let dir = output_dir("merged");
+        let merged = dir.join("merged.trace");
+        let flags = ["--cpus", "2", "--merged-output", merged.to_str().unwrap()];
+        simulate(&args(&flags), Box::new(TWO_CPUS.as_bytes()), &mut vec![]).unwrap();
+        // CPU 0's bubble counts from its own previous miss, not CPU 1's
+        assert_eq!(
+            std::fs::read_to_string(&merged).unwrap(),
+            "0 0 0x0000000000001000\n\
+             1 0 0x0000000000002000\n\
+             0 20 0x0000000000003000\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cpu_filter_keeps_a_subset_of_the_traces() {
+        let traces = |flags: &[&str]| {
+            let dir = output_dir(&format!("cpu_filter_{}", flags.len()));
+            let log_dir = dir.to_str().unwrap();
+            let flags = [&["--cpus", "2", "--log-dir", log_dir], flags].concat();
+            simulate(&args(&flags), Box::new(TWO_CPUS.as_bytes()), &mut vec![]).unwrap();
+            let trace = |cpu| std::fs::read_to_string(dir.join(format!("cpu_{}.trace", cpu))).ok();
+            let traces = (trace(0), trace(1));
+            std::fs::remove_dir_all(&dir).unwrap();
+            traces
+        };
+        let (cpu_0, cpu_1) = traces(&[]);
+        // the excluded CPU doesn't get a trace at all
+        assert_eq!(traces(&["--cpu", "1"]), (None, cpu_1));
+        assert!(cpu_0.is_some());
+    }
+
+    #[test]
+    fn every_cpu_gets_a_trace() {
+        for cpus in [3, 12] {
+            let dir = output_dir(&format!("cpus_{}", cpus));
+            let input: String = (0..cpus)
+                .map(|cpu| format!("{},0,0,{},0x{:x},3\n", 10 + cpu, cpu, 0x1000 * (cpu + 1)))
+                .collect();
+            let flags = [
+                "--cpus",
+                &cpus.to_string(),
+                "--log-dir",
+                dir.to_str().unwrap(),
+            ];
+            simulate(
+                &args(&flags),
+                Box::new(std::io::Cursor::new(input)),
+                &mut vec![],
+            )
+            .unwrap();
+            for cpu in 0..cpus {
+                let trace = std::fs::read_to_string(dir.join(format!("cpu_{}.trace", cpu)));
+                assert_eq!(trace.unwrap(), format!("0 0x{:016x}\n", 0x1000 * (cpu + 1)));
+            }
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn stats_csv_has_a_row_per_cpu_and_their_total() {
+        let dir = output_dir("stats_csv");
+        let path = dir.join("stats.csv");
+        let flags = [
+            "--cpus",
+            "2",
+            "--dry-run",
+            "--stats-file",
+            path.to_str().unwrap(),
+        ];
+        simulate(&args(&flags), Box::new(TWO_CPUS.as_bytes()), &mut vec![]).unwrap();
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let mut lines = csv.lines();
+        let header: Vec<&str> = lines.next().unwrap().split(',').collect();
+        let rows: Vec<Vec<&str>> = lines.map(|line| line.split(',').collect()).collect();
+        let field =
+            |row: usize, name: &str| rows[row][header.iter().position(|&h| h == name).unwrap()];
+        assert_eq!(rows.len(), 3);
+        let columns = [
+            "cpu",
+            "accesses",
+            "hits",
+            "misses",
+            "records",
+            "first_insn",
+            "last_insn",
+            "avg_bubble",
+        ];
+        let row = |row| columns.map(|name| field(row, name));
+        assert_eq!(row(0), ["0", "3", "1", "2", "2", "10", "30", "10.0000"]);
+        // CPU 1's store waits in the store buffer until the end and then hits
+        assert_eq!(row(1), ["1", "2", "1", "1", "1", "12", "20", "0.0000"]);
+        assert_eq!(row(2), ["total", "5", "2", "3", "3", "10", "30", "6.6667"]);
+        assert_eq!(
+            (field(2, "user_accesses"), field(2, "kernel_accesses")),
+            ("5", "0")
+        );
+    }
+
+    #[test]
+    fn dry_run_counts_what_a_real_run_writes() {
+        let mut summary = vec![];
+        let flags = ["--cpus", "2", "--dry-run"];
+        simulate(&args(&flags), Box::new(TWO_CPUS.as_bytes()), &mut summary).unwrap();
+
+        let dir = output_dir("dry_run");
+        let log_dir = dir.to_str().unwrap();
+        simulate(
+            &args(&["--cpus", "2", "--log-dir", log_dir]),
+            Box::new(TWO_CPUS.as_bytes()),
+            &mut vec![],
+        )
+        .unwrap();
+        let written: Vec<String> = (0..2)
+            .map(|cpu| std::fs::read_to_string(dir.join(format!("cpu_{}.trace", cpu))).unwrap())
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let mut expected = String::new();
+        for (cpu, trace) in written.iter().enumerate() {
+            expected += &format!(
+                "cpu {}: {} records, {} bytes\n",
+                cpu,
+                trace.lines().count(),
+                trace.len()
+            );
+        }
+        expected += "total: 3 records, 64 bytes\n";
+        assert_eq!(String::from_utf8(summary).unwrap(), expected);
+
+        // there are no trace files to write alongside a dry run
+        let both = ["cache", "--dry-run", "--log-dir", log_dir];
+        assert!(Args::try_parse_from(both).is_err());
+    }
 }
@@ -1,62 +1,391 @@
 use std::{
     cmp::Reverse,
     collections::BinaryHeap,
-    fs::{self, File},
-    io::{BufWriter, Write},
+    fs,
+    io::{self, BufWriter, Read, Write},
+    path::PathBuf,
+    sync::mpsc::{self, Receiver},
+    thread::{self, JoinHandle},
 };
 
 use cf_qemu_post::log_parser;
 use clap::Parser;
+use regex::Regex;
 
-const CPUS: usize = 8;
+// records buffered between the merge and the writer thread
+const WRITE_QUEUE_DEPTH: usize = 4096;
 
+// Formats and writes the merged records so the merge doesn't wait on output.
+fn spawn_writer<W: Write + Send + 'static>(
+    records: Receiver<log_parser::LogRecord>,
+    out: W,
+) -> JoinHandle<io::Result<W>> {
+    thread::spawn(move || {
+        let mut writer = BufWriter::new(out);
+        for record in records {
+            writeln!(writer, "{}", record)?;
+        }
+        writer.into_inner().map_err(|e| e.into_error())
+    })
+}
+
+// Pushes the next record of log `i`, skipping and counting invalid lines of CSV logs
+// with a warning. Other read errors end the merge.
 fn push_next_record(
     heap: &mut BinaryHeap<Reverse<(log_parser::LogRecord, usize)>>,
-    parser: &mut log_parser::LogParser,
+    parser: &mut log_parser::LogParser<impl Read>,
     i: usize,
-) {
-    if let Some(Ok(record)) = parser.next() {
-        heap.push(Reverse((record, i)));
+    name: &str,
+    skipped: &mut u64,
+) -> Result<(), String> {
+    loop {
+        match parser.next() {
+            Some(Ok(record)) => {
+                heap.push(Reverse((record, i)));
+                return Ok(());
+            }
+            Some(Err(e)) if parser.is_text() && e.kind() == io::ErrorKind::InvalidData => {
+                eprintln!("Warning: skipping {}: {}", name, e);
+                *skipped += 1;
+            }
+            Some(Err(e)) => return Err(format!("failed to read {}: {}", name, e)),
+            None => return Ok(()),
+        }
+    }
+}
+
+// Turns a shell-style pattern with `*` and `?` wildcards into an anchored regex.
+fn glob_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    let translated = regex::escape(pattern)
+        .replace(r"\*", ".*")
+        .replace(r"\?", ".");
+    Regex::new(&format!("^{}$", translated))
+}
+
+// CPU index given by the trailing number of a log name, e.g. 3 for `log.txt.3.gz`.
+fn cpu_suffix(name: &str) -> Option<u64> {
+    let stem = name.strip_suffix(".gz").unwrap_or(name);
+    let digits = stem.len() - stem.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+    stem[stem.len() - digits..].parse().ok()
+}
+
+// Files in the directory whose name matches the pattern, ordered by CPU index.
+fn find_logs(dir: &str, pattern: &Regex) -> io::Result<Vec<PathBuf>> {
+    let mut logs = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if pattern.is_match(name) {
+            let cpu = cpu_suffix(name);
+            logs.push((cpu.is_none(), cpu, name.to_string(), path));
+        }
     }
+    logs.sort();
+    Ok(logs.into_iter().map(|(_, _, _, path)| path).collect())
 }
 
 #[derive(Parser, Debug)]
 #[command(about)]
 struct Args {
-    // Whether the input logs are in binary format
     #[arg(short, long)]
     log_dir: String,
+
+    // names of the per-CPU logs in the directory, `*` and `?` are wildcards
+    #[arg(long, default_value = "log.txt*")]
+    pattern: String,
+
+    // abort on the first record that goes back in time instead of warning
+    #[arg(long, default_value_t = false)]
+    strict: bool,
+
+    // only merge records of these CPUs, may be repeated
+    #[arg(long)]
+    cpu: Vec<u8>,
 }
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
-    let mut parsers: Vec<log_parser::LogParser> = fs::read_dir(args.log_dir)?
-        .filter_map(Result::ok)
-        .map(|entry| entry.path())
-        .filter(|path| {
-            if let Some(file_name) = path.file_name().and_then(|name| name.to_str()) {
-                file_name.starts_with("log.txt")
-            } else {
-                false
+
+// A per-CPU log, which may be gzipped, headerless or CSV.
+type CpuLog = log_parser::LogParser<Box<dyn Read>>;
+
+// Opens the matching logs of the directory in CPU order, with their names. Logs that
+// can't be opened are skipped.
+fn open_logs(dir: &str, pattern: &Regex) -> io::Result<(Vec<CpuLog>, Vec<String>)> {
+    let mut parsers = vec![];
+    let mut names = vec![];
+    for path in find_logs(dir, pattern)? {
+        let Some(file) = path.to_str() else {
+            continue;
+        };
+        match log_parser::LogParser::open_auto(file) {
+            Ok(parser) => {
+                eprintln!("Merging {}", file);
+                parsers.push(parser);
+                names.push(file.to_string());
             }
-        })
-        .filter_map(|path| path.into_os_string().into_string().ok())
-        .filter_map(|file| log_parser::LogParser::new(&file).ok())
-        .collect();
+            Err(e) => eprintln!("Skipping {}: {}", file, e),
+        }
+    }
+    Ok((parsers, names))
+}
+
+// Tallies of a finished merge.
+#[derive(Debug, Default, PartialEq)]
+struct Merged {
+    inversions: u64,
+    skipped: u64,
+}
 
-    let mut writer = BufWriter::new(std::io::stdout());
+// Merges the logs by logical clock into `out`, keeping only the given CPUs unless
+// there are none. Everything merged before an error is still written.
+fn merge<W: Write + Send + 'static>(
+    parsers: &mut [log_parser::LogParser<impl Read>],
+    names: &[String],
+    cpus: &[u8],
+    strict: bool,
+    out: W,
+) -> Result<(Merged, W), String> {
+    let (sender, receiver) = mpsc::sync_channel(WRITE_QUEUE_DEPTH);
+    let writer = spawn_writer(receiver, out);
     let mut prev_clock = 0;
+    let mut merged = Merged::default();
+    let mut aborted = None;
 
     let mut heap: BinaryHeap<Reverse<(log_parser::LogRecord, usize)>> = BinaryHeap::new();
     for (i, parser) in parsers.iter_mut().enumerate() {
-        push_next_record(&mut heap, parser, i);
+        if let Err(message) = push_next_record(&mut heap, parser, i, &names[i], &mut merged.skipped)
+        {
+            aborted = Some(message);
+            heap.clear();
+            break;
+        }
     }
     while let Some(Reverse((record, i))) = heap.pop() {
+        if !cpus.is_empty() && !cpus.contains(&record.cpu) {
+            if let Err(message) = push_next_record(
+                &mut heap,
+                &mut parsers[i],
+                i,
+                &names[i],
+                &mut merged.skipped,
+            ) {
+                aborted = Some(message);
+                break;
+            }
+            continue;
+        }
         if prev_clock > record.logical_clock {
-            eprintln!("Warning: instruction count out of order!");
+            merged.inversions += 1;
+            let message = format!(
+                "instruction count out of order on cpu {}: logical clock {} after {} (insn_count {})",
+                record.cpu, record.logical_clock, prev_clock, record.insn_count
+            );
+            if strict {
+                aborted = Some(message);
+                break;
+            }
+            eprintln!("Warning: {}", message);
         }
         prev_clock = record.logical_clock;
-        writeln!(writer, "{}", record);
-        push_next_record(&mut heap, &mut parsers[i], i);
+        // the writer only hangs up after failing, which join reports below
+        if sender.send(record).is_err() {
+            break;
+        }
+        if let Err(message) = push_next_record(
+            &mut heap,
+            &mut parsers[i],
+            i,
+            &names[i],
+            &mut merged.skipped,
+        ) {
+            aborted = Some(message);
+            break;
+        }
+    }
+    drop(sender);
+    let out = writer
+        .join()
+        .expect("writer thread panicked")
+        .map_err(|e| format!("failed to write the merged log: {}", e))?;
+    match aborted {
+        Some(message) => Err(message),
+        None => Ok((merged, out)),
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let pattern = glob_regex(&args.pattern)?;
+    let (mut parsers, names) = open_logs(&args.log_dir, &pattern)?;
+    match merge(&mut parsers, &names, &args.cpu, args.strict, io::stdout()) {
+        Ok((merged, _)) => {
+            eprintln!("CPU logs merged: {}", parsers.len());
+            eprintln!("Out-of-order records: {}", merged.inversions);
+            eprintln!("Malformed lines skipped: {}", merged.skipped);
+            Ok(())
+        }
+        Err(message) => {
+            eprintln!("Error: {}", message);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_csv_lines_are_skipped() {
+        let log = "1,101,0,0,3,0x1000\nnot a record\n2,102,0,1,3,0x1008\n";
+        let mut parser = log_parser::LogParser::from_text(log.as_bytes());
+        let mut heap = BinaryHeap::new();
+        let mut skipped = 0;
+        push_next_record(&mut heap, &mut parser, 0, "log.txt", &mut skipped).unwrap();
+        push_next_record(&mut heap, &mut parser, 0, "log.txt", &mut skipped).unwrap();
+        assert_eq!(skipped, 1);
+        let clocks: Vec<u64> = std::iter::from_fn(|| heap.pop())
+            .map(|Reverse((record, _))| record.logical_clock)
+            .collect();
+        assert_eq!(clocks, [1, 2]);
+    }
+
+    // A CSV log of one CPU with a load at each logical clock.
+    fn csv_log(cpu: u8, clocks: &[u64]) -> String {
+        clocks
+            .iter()
+            .map(|clock| {
+                format!(
+                    "{},{},{},0,3,0x{:016x}\n",
+                    clock,
+                    clock + 100,
+                    cpu,
+                    0x1000 * clock
+                )
+            })
+            .collect()
+    }
+
+    // Merges in-memory logs and returns the tallies and the merged lines.
+    fn merge_logs(logs: &[String], cpus: &[u8], strict: bool) -> Result<(Merged, String), String> {
+        let mut parsers: Vec<_> = logs
+            .iter()
+            .map(|log| log_parser::LogParser::from_text(log.as_bytes()))
+            .collect();
+        let names: Vec<_> = (0..logs.len()).map(|i| format!("log.txt.{}", i)).collect();
+        let (merged, out) = merge(&mut parsers, &names, cpus, strict, vec![])?;
+        Ok((merged, String::from_utf8(out).unwrap()))
+    }
+
+    #[test]
+    fn cpu_filter_keeps_a_subset_of_the_merge() {
+        let logs = [csv_log(0, &[1, 4, 5]), csv_log(1, &[2, 3, 6])];
+        let (_, all) = merge_logs(&logs, &[], false).unwrap();
+        let (_, cpu_1) = merge_logs(&logs, &[1], false).unwrap();
+        assert_eq!(cpu_1, logs[1]);
+        assert!(cpu_1.lines().all(|line| all.contains(line)));
+        assert_eq!(all.lines().count(), 6);
+    }
+
+    // A directory of its own for the logs of one test.
+    fn log_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("log_merger_{}_{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn every_discovered_log_is_merged() {
+        for cpus in [3, 12] {
+            let dir = log_dir(&format!("cpus_{}", cpus));
+            for cpu in 0..cpus {
+                let log = csv_log(cpu, &[u64::from(cpu) + 1, u64::from(cpu) + 20]);
+                fs::write(dir.join(format!("log.txt.{}", cpu)), log).unwrap();
+            }
+            let pattern = glob_regex("log.txt*").unwrap();
+            let (mut parsers, names) = open_logs(dir.to_str().unwrap(), &pattern).unwrap();
+            let expected: Vec<_> = (0..cpus)
+                .map(|cpu| {
+                    dir.join(format!("log.txt.{}", cpu))
+                        .to_str()
+                        .unwrap()
+                        .to_string()
+                })
+                .collect();
+            assert_eq!(names, expected);
+            let (merged, out) = merge(&mut parsers, &names, &[], false, vec![]).unwrap();
+            assert_eq!(merged, Merged::default());
+            let cpus_seen: Vec<u8> = String::from_utf8(out)
+                .unwrap()
+                .lines()
+                .map(|line| line.split(',').nth(2).unwrap().parse().unwrap())
+                .collect();
+            let expected: Vec<u8> = (0..cpus).chain(0..cpus).collect();
+            assert_eq!(cpus_seen, expected);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn merged_streams_come_out_sorted() {
+        // more records than the write queue holds, so the merge waits on the writer
+        let logs: Vec<String> = (0..4u8)
+            .map(|cpu| {
+                let clocks: Vec<u64> = (0..3000).map(|i| i * 7 + u64::from(cpu) * 3).collect();
+                csv_log(cpu, &clocks)
+            })
+            .collect();
+        let (merged, out) = merge_logs(&logs, &[], false).unwrap();
+        assert_eq!(merged.inversions, 0);
+        let clocks: Vec<u64> = out
+            .lines()
+            .map(|line| line.split(',').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(clocks.len(), 4 * 3000);
+        assert!(clocks.is_sorted());
+    }
+
+    #[test]
+    fn only_matching_log_files_are_merged() {
+        let dir = log_dir("noise");
+        fs::write(dir.join("log.txt.10"), csv_log(10, &[3])).unwrap();
+        fs::write(dir.join("log.txt.2"), csv_log(2, &[1, 2])).unwrap();
+        fs::write(dir.join("notes.txt"), "not a log\n").unwrap();
+        fs::write(dir.join("exec.log.1"), csv_log(1, &[4])).unwrap();
+        fs::create_dir(dir.join("log.txt.3")).unwrap();
+        let pattern = glob_regex("log.txt*").unwrap();
+        let (mut parsers, names) = open_logs(dir.to_str().unwrap(), &pattern).unwrap();
+        // ordered by CPU, not by name
+        let expected: Vec<_> = ["log.txt.2", "log.txt.10"]
+            .iter()
+            .map(|name| dir.join(name).to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(names, expected);
+        let (_, out) = merge(&mut parsers, &names, &[], false, vec![]).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out, csv_log(2, &[1, 2]) + &csv_log(10, &[3]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn inversions_are_counted_or_abort_in_strict_mode() {
+        // both logs go back in time once
+        let logs = [csv_log(0, &[1, 5, 3]), csv_log(1, &[2, 8, 6])];
+        let (merged, out) = merge_logs(&logs, &[], false).unwrap();
+        assert_eq!(merged.inversions, 2);
+        let clocks: Vec<&str> = out
+            .lines()
+            .map(|line| line.split(',').next().unwrap())
+            .collect();
+        assert_eq!(clocks, ["1", "2", "5", "3", "8", "6"]);
+
+        assert_eq!(
+            merge_logs(&logs, &[], true).unwrap_err(),
+            "instruction count out of order on cpu 0: logical clock 3 after 5 (insn_count 103)"
+        );
     }
-    Ok(())
 }
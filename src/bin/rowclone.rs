@@ -1,24 +1,34 @@
 use cf_qemu_post::log_parser;
 use cf_qemu_post::lookahead_iter::LookaheadIterator;
 use cf_qemu_post::memory_access::{MemRecord, MemoryAccess, RowcloneRecord};
-use clap::{Parser, command};
+use cf_qemu_post::progress::Progress;
+use clap::{Parser, ValueEnum};
 use once_cell::sync::Lazy;
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
 use std::sync::atomic::{AtomicU64, Ordering};
 
 const COPY_WINDOW: usize = 200;
 const COPY_WINDOW_STALE_THRESHOLD: usize = 20; // if 10 newer logs have been matched expect no more matches
 // for this one
 const COPY_CONFIDENCE_THRESHOLD: u64 = 128; // how many bytes worth of matching of loads AND stores we should see 
-// TODO: [yb] make confidence threshold dependent on
-// transfer size
-// TODO: [yb] this is too large, optimize, by perhaps keeping track of all copy begins in a vec and loop through whole
-// file once after that
-const COPY_CONFIDENCE_WINDOW: usize = 200000; // in the next COPY_CONFIDENCE_WINDOW accesses
+const COPY_MATCH_TOLERANCE: u64 = 0; // bytes a copy may skip ahead when accesses are missing
+
+// physical address bits selecting the DRAM subarray; RowClone only copies within one
+const SUBARRAY_LSB: u32 = 21;
+const SUBARRAY_BITS: u32 = 7;
+
+// Tunables of the copy detection, defaulting to the constants above.
+#[derive(Debug, Clone, Copy)]
+pub struct DetectionConfig {
+    copy_window: usize,
+    stale_threshold: usize,
+    confidence_threshold: u64,
+    match_tolerance: u64,
+}
 
 static NEXT_KERNEL_REC_ID: AtomicU64 = AtomicU64::new(0);
 
@@ -39,6 +49,8 @@ struct KernelRecord {
     operation: char,
     kernel_address: u64,
     user_address: u64,
+    // trace timestamp in seconds, only available for ftrace logs
+    timestamp: Option<f64>,
     stale: usize,
 }
 
@@ -48,17 +60,35 @@ static KERNEL_LOG_PATTERN: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r#"N=([^,]+),([rw]),(\d+),(\d+),(0x[0-9a-fA-F]+),(0x[0-9a-fA-F]+),(0x[0-9a-fA-F]+),(0x[0-9a-fA-F]+)"#).expect("failed to compile regex")
 });
 
+// e.g. `firefox-1234  [003] d..1.  1234.567890: probe_copy_to_user: (_copy_to_user+0x0/0x60) to=0x... from=0x... n=4096`
+static FTRACE_LOG_PATTERN: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"^\s*(.+?)-(\d+)\s+(?:\(\s*[\d-]+\)\s+)?\[(\d+)\]\s+(?:\S+\s+)?(\d+\.\d+):\s+([\w.]+):(.*)$"#)
+        .expect("failed to compile regex")
+});
+
+static FTRACE_ARG_PATTERN: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(\w+)=(\S+)"#).expect("failed to compile regex"));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum KernelLogFormat {
+    // printk lines of the form `N=comm,op,cpu,size,...`
+    Legacy,
+    // ftrace/kprobe lines for copy_{to,from}_user probes
+    Ftrace,
+}
+
 impl fmt::Debug for KernelRecord {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "KernelRecord {{command: {}, cpu: {}, size: {}, op: {}, kernel_address: 0x{:016x}, user_address: 0x{:016x} }}",
+            "KernelRecord {{command: {}, cpu: {}, size: {}, op: {}, kernel_address: 0x{:016x}, user_address: 0x{:016x}, timestamp: {:?} }}",
             self.command,
             self.cpu,
             self.size,
             self.operation,
             self.kernel_address,
-            self.user_address
+            self.user_address,
+            self.timestamp
         )
     }
 }
@@ -75,12 +105,103 @@ struct MemCpy {
     current_to: u64,
 }
 
+// Copies in the order they were added, indexed by the addresses their next load and
+// store are expected at so an access is only compared against copies it can match.
+#[derive(Default)]
+struct CopyList {
+    copies: BTreeMap<u64, MemCpy>,
+    expected: AddrMap<u64>,
+    next_key: u64,
+}
+
+impl CopyList {
+    fn len(&self) -> usize {
+        self.copies.len()
+    }
+
+    fn get(&self, key: u64) -> &MemCpy {
+        &self.copies[&key]
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &MemCpy> {
+        self.copies.values()
+    }
+
+    fn find_by_rec_id(&mut self, rec_id: u64) -> Option<&mut MemCpy> {
+        self.copies.values_mut().find(|copy| copy.rec_id == rec_id)
+    }
+
+    fn push(&mut self, copy: MemCpy) {
+        let key = self.next_key;
+        self.next_key += 1;
+        self.index(key, &copy);
+        self.copies.insert(key, copy);
+    }
+
+    fn remove(&mut self, key: u64) -> MemCpy {
+        let copy = self.copies.remove(&key).expect("copy not in list");
+        self.unindex(key, &copy);
+        copy
+    }
+
+    // Keys of the copies the access continues, oldest first.
+    fn matches(&self, mem_access: &log_parser::LogRecord, tolerance: u64) -> Vec<u64> {
+        let mut keys: Vec<u64> = if tolerance == 0 {
+            self.expected
+                .get(&mem_access.address)
+                .into_iter()
+                .flatten()
+                .copied()
+                .filter(|key| mem_copy_match(mem_access, &self.copies[key], tolerance))
+                .collect()
+        } else {
+            // accesses may land anywhere in a range ahead of the expected address
+            self.copies
+                .iter()
+                .filter(|(_, copy)| mem_copy_match(mem_access, copy, tolerance))
+                .map(|(key, _)| *key)
+                .collect()
+        };
+        keys.sort_unstable();
+        keys.dedup();
+        keys
+    }
+
+    fn update(&mut self, key: u64, mem_access: &log_parser::LogRecord, stats: &mut Stats) -> bool {
+        let mut copy = self.copies.remove(&key).expect("copy not in list");
+        self.unindex(key, &copy);
+        let done = update_copy(&mut copy, mem_access, stats);
+        self.index(key, &copy);
+        self.copies.insert(key, copy);
+        done
+    }
+
+    fn index(&mut self, key: u64, copy: &MemCpy) {
+        self.expected
+            .entry(copy.current_from)
+            .or_default()
+            .push(key);
+        self.expected.entry(copy.current_to).or_default().push(key);
+    }
+
+    fn unindex(&mut self, key: u64, copy: &MemCpy) {
+        for address in [copy.current_from, copy.current_to] {
+            if let Some(keys) = self.expected.get_mut(&address) {
+                keys.retain(|k| *k != key);
+                if keys.is_empty() {
+                    self.expected.remove(&address);
+                }
+            }
+        }
+    }
+}
+
 fn parse_hex_address(hex_str: &str) -> Option<u64> {
     // Remove the "0x" prefix and parse as a base 16 number
     u64::from_str_radix(hex_str.trim_start_matches("0x"), 16).ok()
 }
 
-fn parse_kernel_line(line: &str) -> Option<KernelRecord> {
+fn parse_legacy_line(line: &str) -> Option<KernelRecord> {
     // Regular expression to capture the CSV-like part of the log line
     if let Some(caps) = KERNEL_LOG_PATTERN.captures(line) {
         Some(KernelRecord {
@@ -91,6 +212,7 @@ fn parse_kernel_line(line: &str) -> Option<KernelRecord> {
             operation: caps[2].chars().next()?,
             kernel_address: parse_hex_address(&caps[6])?,
             user_address: parse_hex_address(&caps[8])?,
+            timestamp: None,
             stale: 0,
         })
     } else {
@@ -99,23 +221,148 @@ fn parse_kernel_line(line: &str) -> Option<KernelRecord> {
     }
 }
 
-fn address_in_same_subarray(a: u64, b: u64) -> bool {
-    let subarray_mask = 0x7F; // 7 bits
-    let subarray_lsb = 21;
-    let a_subarray = (a >> subarray_lsb) & subarray_mask;
-    let b_subarray = (b >> subarray_lsb) & subarray_mask;
+fn parse_ftrace_line(line: &str, stats: &mut Stats) -> Option<KernelRecord> {
+    if line.starts_with('#') {
+        return None;
+    }
+    let Some(caps) = FTRACE_LOG_PATTERN.captures(line) else {
+        eprintln!("Failed to parse ftrace line: {}", line);
+        return None;
+    };
+    // copy_to_user copies kernel -> user, copy_from_user user -> kernel
+    let operation = match &caps[5] {
+        event if event.contains("copy_to_user") => 'r',
+        event if event.contains("copy_from_user") => 'w',
+        _ => {
+            stats.unknown_events += 1;
+            return None;
+        }
+    };
+    let mut to = None;
+    let mut from = None;
+    let mut size = None;
+    for arg in FTRACE_ARG_PATTERN.captures_iter(&caps[6]) {
+        match &arg[1] {
+            "to" => to = parse_hex_address(&arg[2]),
+            "from" => from = parse_hex_address(&arg[2]),
+            "n" => size = arg[2].parse().ok(),
+            _ => {}
+        }
+    }
+    let (Some(to), Some(from), Some(size)) = (to, from, size) else {
+        eprintln!("Missing copy arguments in ftrace line: {}", line);
+        return None;
+    };
+    let (kernel_address, user_address) = if operation == 'r' {
+        (from, to)
+    } else {
+        (to, from)
+    };
+    Some(KernelRecord {
+        rec_id: NEXT_KERNEL_REC_ID.fetch_add(1, Ordering::Relaxed),
+        command: caps[1].trim().to_string(),
+        cpu: caps[3].parse().ok()?,
+        size,
+        operation,
+        kernel_address,
+        user_address,
+        timestamp: caps[4].parse().ok(),
+        stale: 0,
+    })
+}
+
+impl KernelLogFormat {
+    fn parse(self, line: &str, stats: &mut Stats) -> Option<KernelRecord> {
+        match self {
+            KernelLogFormat::Legacy => parse_legacy_line(line),
+            KernelLogFormat::Ftrace => parse_ftrace_line(line, stats),
+        }
+    }
+
+    fn detect(line: &str) -> Option<KernelLogFormat> {
+        if KERNEL_LOG_PATTERN.is_match(line) {
+            Some(KernelLogFormat::Legacy)
+        } else if FTRACE_LOG_PATTERN.is_match(line) {
+            Some(KernelLogFormat::Ftrace)
+        } else {
+            None
+        }
+    }
+}
+
+// Kernel copy log in either format. When no format is given it is detected from the
+// first recognizable line.
+struct KernelLog<I> {
+    lines: I,
+    format: Option<KernelLogFormat>,
+    subarray: Subarray,
+}
+
+impl<I> KernelLog<I>
+where
+    I: Iterator<Item = io::Result<String>>,
+{
+    fn new(lines: I, format: Option<KernelLogFormat>, subarray: Subarray) -> Self {
+        KernelLog {
+            lines,
+            format,
+            subarray,
+        }
+    }
+
+    fn parse_line(&mut self, line: &str, stats: &mut Stats) -> Option<KernelRecord> {
+        if self.format.is_none() {
+            if line.starts_with('#') {
+                return None;
+            }
+            self.format = KernelLogFormat::detect(line);
+            match self.format {
+                Some(format) => eprintln!("Detected kernel log format: {:?}", format),
+                None => {
+                    eprintln!("Failed to parse kernel line: {}", line);
+                    return None;
+                }
+            }
+        }
+        self.format?.parse(line, stats)
+    }
+}
 
-    return a_subarray == b_subarray;
+// Location of the subarray index within an address.
+#[derive(Debug, Clone, Copy)]
+pub struct Subarray {
+    lsb: u32,
+    mask: u64,
 }
 
-fn page_number(address: u64) -> u64 {
-    address & !0xFFF
+impl Subarray {
+    fn new(lsb: u32, bits: u32) -> Self {
+        Subarray {
+            lsb,
+            mask: u64::MAX >> (64 - bits),
+        }
+    }
+
+    fn address_in_same_subarray(self, a: u64, b: u64) -> bool {
+        let a_subarray = (a >> self.lsb) & self.mask;
+        let b_subarray = (b >> self.lsb) & self.mask;
+
+        a_subarray == b_subarray
+    }
 }
 
-fn mem_copy_match(mem_access: &log_parser::LogRecord, copy: &MemCpy) -> bool {
-    // TODO: [yb] make this somewhat fuzzy in case a mem access is missed occasionally..
-    (copy.current_from == mem_access.address && mem_access.store == 0)
-        || (copy.current_to == mem_access.address && mem_access.store == 1)
+// An access matches if it is at most `tolerance` bytes ahead of where the copy is
+// expected to continue, so occasionally missing accesses don't derail it.
+fn mem_copy_match(mem_access: &log_parser::LogRecord, copy: &MemCpy, tolerance: u64) -> bool {
+    let expected = if mem_access.store == 1 {
+        copy.current_to
+    } else {
+        copy.current_from
+    };
+    mem_access
+        .address
+        .checked_sub(expected)
+        .is_some_and(|ahead| ahead <= tolerance)
 }
 
 fn copy_done(copy: &MemCpy) -> bool {
@@ -123,42 +370,42 @@ fn copy_done(copy: &MemCpy) -> bool {
     copy.current_to >= copy.to + copy.size
 }
 
-fn update_copy(
-    copies: &mut Vec<MemCpy>,
-    copy_idx: usize,
-    mem_access: &log_parser::LogRecord,
-) -> bool {
+fn update_copy(copy: &mut MemCpy, mem_access: &log_parser::LogRecord, stats: &mut Stats) -> bool {
     // mem_access.size is in shifts (0 = 1 byte, 1 = 2 bytes,...)
     let access_size_bytes = 1 << mem_access.size;
-    let copy = &mut copies[copy_idx];
-    if mem_access.store == 1 {
-        copy.current_to += access_size_bytes;
+    let current = if mem_access.store == 1 {
+        &mut copy.current_to
     } else {
-        copy.current_from += access_size_bytes;
-    }
+        &mut copy.current_from
+    };
+    stats.skipped_bytes += mem_access.address - *current;
+    *current = mem_access.address + access_size_bytes;
     copy.insn_count = mem_access.insn_count;
     copy.cpu = mem_access.cpu as usize;
-    copy_done(&copy)
+    copy_done(copy)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 struct Stats {
+    unknown_events: usize,
     total: usize,
     not4kb: usize,
     notaligned: usize,
-    not_same_subarray: usize,
     rowclone: usize,
+    // bytes of matched copies for which no access was seen
+    skipped_bytes: u64,
 }
 
+// Copies between subarrays are still tracked so their accesses are recognized, see
+// part_of_potential_copy.
 fn filter_non_rowclone(record: KernelRecord, stats: &mut Stats) -> Option<KernelRecord> {
     const PAGE_SIZE: u64 = 4096;
     stats.total += 1;
-    if record.size != PAGE_SIZE {
+    // copies of several pages, e.g. huge pages, are cloned page by page
+    if record.size == 0 || !record.size.is_multiple_of(PAGE_SIZE) {
         stats.not4kb += 1;
     } else if (record.user_address & (PAGE_SIZE - 1)) != 0 {
         stats.notaligned += 1;
-    } else if !address_in_same_subarray(record.user_address, record.kernel_address) {
-        stats.not_same_subarray += 1;
     } else {
         stats.rowclone += 1;
         return Some(record);
@@ -167,57 +414,97 @@ fn filter_non_rowclone(record: KernelRecord, stats: &mut Stats) -> Option<Kernel
 }
 
 fn next_kernel_line(
-    lines: &mut impl Iterator<Item = io::Result<String>>,
+    copy_logs: &mut KernelLog<impl Iterator<Item = io::Result<String>>>,
     stats: &mut Stats,
 ) -> Option<KernelRecord> {
-    while let Some(Ok(line)) = lines.next() {
-        if let Some(record) = parse_kernel_line(&line) {
-            if let Some(record) = filter_non_rowclone(record, stats) {
-                return Some(record);
-            }
-        } else {
-            eprintln!("not parsed?");
+    while let Some(Ok(line)) = copy_logs.lines.next() {
+        if let Some(record) = copy_logs
+            .parse_line(&line, stats)
+            .and_then(|record| filter_non_rowclone(record, stats))
+        {
+            return Some(record);
         }
     }
     None
 }
 
-fn push_ongoing_copy(
-    ongoing_copies: &mut Vec<MemCpy>,
-    potential_copies: &mut Vec<MemCpy>,
-    idx: usize,
-) {
-    let copy = potential_copies.remove(idx);
-    ongoing_copies.push(copy);
+// Kernel copy records considered for matching, refilled from the log as records are
+// matched or go stale.
+struct KernelWindow<I> {
+    records: Vec<KernelRecord>,
+    log: KernelLog<I>,
 }
 
-fn print_rowclone(copy: &MemCpy, output: &mut BufWriter<std::io::Stdout>) {
-    writeln!(
-        output,
-        "{}",
-        RowcloneRecord {
-            cpu: copy.cpu,
-            insn_count: copy.insn_count,
-            from: copy.from,
-            to: copy.to,
+impl<I> KernelWindow<I>
+where
+    I: Iterator<Item = io::Result<String>>,
+{
+    fn fill(&mut self, stats: &mut Stats, config: DetectionConfig) {
+        while self.records.len() < config.copy_window {
+            match next_kernel_line(&mut self.log, stats) {
+                Some(record) => self.records.push(record),
+                None => return,
+            }
         }
-    );
+    }
+}
+
+// Copies being tracked: potential ones whose start matched a kernel record and
+// ongoing ones already emitted as a rowclone whose remaining accesses are skipped.
+#[derive(Default)]
+struct Copies {
+    potential: CopyList,
+    ongoing: CopyList,
+    rowclones: usize,
+    // detected copies whose source and destination lie in different subarrays
+    non_rowclonable: usize,
+}
+
+impl Copies {
+    fn push_ongoing(&mut self, key: u64) {
+        let copy = self.potential.remove(key);
+        self.ongoing.push(copy);
+    }
+}
+
+// Destination of the annotated trace, as text lines or binary frames.
+struct TraceWriter<W: Write> {
+    out: BufWriter<W>,
+    binary: bool,
+}
+
+impl<W: Write> TraceWriter<W> {
+    fn write(&mut self, access: &MemoryAccess) -> io::Result<()> {
+        if self.binary {
+            access.serialize(&mut self.out)
+        } else {
+            writeln!(self.out, "{}", access)
+        }
+    }
+}
+
+fn print_rowclone(copy: &MemCpy, output: &mut TraceWriter<impl Write>) -> io::Result<()> {
+    output.write(&MemoryAccess::Rowclone(RowcloneRecord {
+        cpu: copy.cpu,
+        insn_count: copy.insn_count,
+        from: copy.from,
+        to: copy.to,
+        size: Some(copy.size),
+    }))
 }
 
 fn print_regular_access(
     mem_access: &log_parser::LogRecord,
-    output: &mut BufWriter<std::io::Stdout>,
-) {
-    writeln!(
-        output,
-        "{}",
-        MemRecord {
-            cpu: mem_access.cpu.into(),
-            insn_count: mem_access.insn_count,
-            address: mem_access.address,
-            store: mem_access.store == 1,
-        }
-    );
+    output: &mut TraceWriter<impl Write>,
+) -> io::Result<()> {
+    output.write(&MemoryAccess::Regular(MemRecord {
+        cpu: mem_access.cpu.into(),
+        insn_count: mem_access.insn_count,
+        address: mem_access.address,
+        store: mem_access.store == 1,
+        size: mem_access.size,
+        non_temporal: false,
+    }))
 }
 
 fn update_stale(rec_id: u64, copy_window: &mut Vec<KernelRecord>) {
@@ -229,88 +516,96 @@ fn update_stale(rec_id: u64, copy_window: &mut Vec<KernelRecord>) {
 }
 fn remove_stale_copies(
     rec_id: u64,
-    copy_window: &mut Vec<KernelRecord>,
-    copy_logs: &mut impl Iterator<Item = io::Result<String>>,
+    window: &mut KernelWindow<impl Iterator<Item = io::Result<String>>>,
     stats: &mut Stats,
+    config: DetectionConfig,
 ) {
-    update_stale(rec_id, copy_window);
-    copy_window.retain(|copy| copy.stale <= COPY_WINDOW_STALE_THRESHOLD);
-
-    while copy_window.len() < COPY_WINDOW {
-        if let Some(line) = next_kernel_line(copy_logs, stats) {
-            copy_window.push(line);
-        } else {
-            return;
-        }
-    }
+    update_stale(rec_id, &mut window.records);
+    window
+        .records
+        .retain(|copy| copy.stale <= config.stale_threshold);
+    window.fill(stats, config);
 }
 
 fn part_of_ongoing_copy(
     mem_access: &log_parser::LogRecord,
-    ongoing_copies: &mut Vec<MemCpy>,
+    ongoing_copies: &mut CopyList,
+    stats: &mut Stats,
+    config: DetectionConfig,
 ) -> bool {
-    for (idx, copy) in ongoing_copies.iter().enumerate() {
-        if mem_copy_match(mem_access, copy) {
-            let done = update_copy(ongoing_copies, idx, &mem_access);
-            if done {
-                ongoing_copies.remove(idx);
-            }
-            return true;
-        }
+    let Some(&key) = ongoing_copies
+        .matches(mem_access, config.match_tolerance)
+        .first()
+    else {
+        return false;
+    };
+    let done = ongoing_copies.update(key, mem_access, stats);
+    if done {
+        ongoing_copies.remove(key);
     }
-    false
+    true
 }
 
-fn copy_matched(potential_copies: &Vec<MemCpy>, idx: usize) -> bool {
-    let copy = &potential_copies[idx];
-    (copy.current_to - copy.to) > COPY_CONFIDENCE_THRESHOLD
-        && (copy.current_from - copy.from) > COPY_CONFIDENCE_THRESHOLD
+fn copy_matched(copy: &MemCpy, confidence_threshold: u64) -> bool {
+    // copies smaller than twice the threshold are confirmed once more than half of
+    // them has been seen, otherwise they could only ever complete
+    let threshold = confidence_threshold.min(copy.size / 2);
+    (copy.current_to - copy.to) > threshold && (copy.current_from - copy.from) > threshold
 }
+
+// Returns whether the access was consumed by a copy. Copies between subarrays can't
+// be performed by RowClone: their accesses are left to be emitted as regular ones and
+// once detected the copy is dropped without emitting a rowclone.
 fn part_of_potential_copy(
     mem_access: &log_parser::LogRecord,
-    potential_copies: &mut Vec<MemCpy>,
-    ongoing_copies: &mut Vec<MemCpy>,
-    rowclones: &mut usize,
-    copy_window: &mut Vec<KernelRecord>,
-    copy_logs: &mut impl Iterator<Item = io::Result<String>>,
-    output: &mut BufWriter<std::io::Stdout>,
+    copies: &mut Copies,
+    window: &mut KernelWindow<impl Iterator<Item = io::Result<String>>>,
+    output: &mut TraceWriter<impl Write>,
     stats: &mut Stats,
-) -> bool {
-    let mut potential_copy = false;
-    let mut matches: Vec<usize> = vec![];
-    for (idx, copy) in potential_copies.iter().enumerate() {
-        if mem_copy_match(mem_access, copy) {
-            potential_copy = true;
-            matches.push(idx);
+    config: DetectionConfig,
+) -> io::Result<bool> {
+    let subarray = window.log.subarray;
+    let mut consumed = false;
+    for key in copies
+        .potential
+        .matches(mem_access, config.match_tolerance)
+        .into_iter()
+        .rev()
+    {
+        let done = copies.potential.update(key, mem_access, stats);
+        let copy = copies.potential.get(key);
+        let rowclonable = subarray.address_in_same_subarray(copy.from, copy.to);
+        consumed |= rowclonable;
+        if !done && !copy_matched(copy, config.confidence_threshold) {
+            continue;
         }
-    }
-    for idx in matches.iter().rev() {
-        let done = update_copy(potential_copies, *idx, &mem_access);
+        let rec_id = copy.rec_id;
+        window.records.retain(|i| i.rec_id != rec_id);
+        remove_stale_copies(rec_id, window, stats, config);
+        if !rowclonable {
+            copies.non_rowclonable += 1;
+            copies.potential.remove(key);
+            continue;
+        }
+        eprintln!("new rowclone");
+        copies.rowclones += 1;
+        print_rowclone(copies.potential.get(key), output)?;
         if done {
-            eprintln!("new rowclone");
-            *rowclones += 1;
-            let rec_id = potential_copies[*idx].rec_id;
-            copy_window.retain(|i| i.rec_id != rec_id);
-            remove_stale_copies(rec_id, copy_window, copy_logs, stats);
-            print_rowclone(&potential_copies[*idx], output);
-            potential_copies.remove(*idx);
-        } else if copy_matched(potential_copies, *idx) {
-            eprintln!("new rowclone");
-            *rowclones += 1;
-            let rec_id = potential_copies[*idx].rec_id;
-            copy_window.retain(|i| i.rec_id != rec_id);
-            remove_stale_copies(rec_id, copy_window, copy_logs, stats);
-            print_rowclone(&potential_copies[*idx], output);
-            push_ongoing_copy(ongoing_copies, potential_copies, *idx);
+            copies.potential.remove(key);
+        } else {
+            copies.push_ongoing(key);
         }
     }
-    potential_copy
+    Ok(consumed)
 }
 
+// Returns whether the access starts a copy RowClone can perform, see
+// part_of_potential_copy.
 fn check_potential_copy_start(
     mem_access: &log_parser::LogRecord,
-    copy_window: &Vec<KernelRecord>,
-    potential_copies: &mut Vec<MemCpy>,
+    copy_window: &[KernelRecord],
+    potential_copies: &mut CopyList,
+    subarray: Subarray,
 ) -> bool {
     let mut potential_copy = false;
 
@@ -330,19 +625,13 @@ fn check_potential_copy_start(
             }
         };
         if is_start {
-            let mut existing_potential_copy = false;
-            for pot_copy in potential_copies.iter_mut() {
-                if pot_copy.rec_id == copy.rec_id {
-                    if pot_copy.current_to == pot_copy.to {
-                        pot_copy.insn_count = mem_access.insn_count;
-                        potential_copy = true;
-                    }
-                    // TODO: [yb] print previous potential copy
-                    existing_potential_copy = true;
-                    break;
+            if let Some(pot_copy) = potential_copies.find_by_rec_id(copy.rec_id) {
+                if pot_copy.current_to == pot_copy.to {
+                    pot_copy.insn_count = mem_access.insn_count;
+                    potential_copy |= subarray.address_in_same_subarray(pot_copy.from, pot_copy.to);
                 }
-            }
-            if !existing_potential_copy {
+                // TODO: [yb] print previous potential copy
+            } else {
                 let to = if copy.operation == 'w' {
                     copy.kernel_address
                 } else {
@@ -356,106 +645,817 @@ fn check_potential_copy_start(
                     to,
                     cpu: mem_access.cpu as usize,
                     size: copy.size,
-                    current_from: mem_access.address + 1 << mem_access.size,
+                    current_from: mem_access.address + (1 << mem_access.size),
                     current_to: to,
                 });
-                potential_copy = true;
+                potential_copy |= subarray.address_in_same_subarray(mem_access.address, to);
             }
         }
     }
     potential_copy
 }
 
+// Outcome of a detection run, written by --stats-json.
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DetectionStats {
+    rowclones_matched: usize,
+    // copies detected between subarrays, emitted as regular accesses
+    non_rowclonable: usize,
+    potential_copies: usize,
+    unfinished_copies: usize,
+    unmatched_kernel_records: usize,
+    accesses: usize,
+}
+
+// Writes the kernel records still in the window and the copies that never completed
+// as CSV, one row each; the columns that do not apply to a row are left empty.
+fn dump_unmatched(
+    path: &str,
+    copy_window: &[KernelRecord],
+    ongoing_copies: &CopyList,
+    potential_copies: &CopyList,
+) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(
+        out,
+        "kind,command,cpu,size,op,kernel_address,user_address,from,to,current_from,current_to,remaining"
+    )?;
+    for record in copy_window {
+        writeln!(
+            out,
+            "kernel,\"{}\",{},{},{},0x{:016x},0x{:016x},,,,,",
+            record.command.replace('"', "\"\""),
+            record.cpu,
+            record.size,
+            record.operation,
+            record.kernel_address,
+            record.user_address
+        )?;
+    }
+    for (kind, copies) in [("ongoing", ongoing_copies), ("potential", potential_copies)] {
+        for copy in copies.iter() {
+            writeln!(
+                out,
+                "{},,{},{},,,,0x{:016x},0x{:016x},0x{:016x},0x{:016x},{}",
+                kind,
+                copy.cpu,
+                copy.size,
+                copy.from,
+                copy.to,
+                copy.current_from,
+                copy.current_to,
+                (copy.to + copy.size).saturating_sub(copy.current_to)
+            )?;
+        }
+    }
+    out.flush()
+}
+
 fn match_copy_to_mem_accesses(
-    mem_reader: BufReader<std::io::Stdin>,
-    mut copy_logs: impl Iterator<Item = io::Result<String>>,
-    copy_window: &mut Vec<KernelRecord>,
-    output: &mut BufWriter<std::io::Stdout>,
+    mem_reader: impl BufRead,
+    window: &mut KernelWindow<impl Iterator<Item = io::Result<String>>>,
+    output: &mut TraceWriter<impl Write>,
     stats: &mut Stats,
-) {
-    let mut ongoing_copies: Vec<MemCpy> = vec![];
-    let mut potential_copies: Vec<MemCpy> = vec![];
+    config: DetectionConfig,
+    options: &OutputOptions,
+) -> io::Result<DetectionStats> {
+    let mut copies = Copies::default();
     let mut mem_accesses = LookaheadIterator::new(
         mem_reader
             .lines()
             .filter_map(|line| line.ok()?.parse::<log_parser::LogRecord>().ok()),
     );
-    let mut rowclones = 0;
-    while let Some(mem_access) = mem_accesses.next() {
+    let mut accesses = 0;
+    let mut progress = Progress::new(options.progress_interval);
+    for mem_access in mem_accesses.by_ref() {
+        accesses += 1;
+        progress.tick(|| copies.rowclones as u64);
         // TODO: [yb] potentially run accesses through cache here immediately (avoiding
         // intermediate file)
-        if part_of_ongoing_copy(&mem_access, &mut ongoing_copies) {
-            continue;
-        } else if part_of_potential_copy(
-            &mem_access,
-            &mut potential_copies,
-            &mut ongoing_copies,
-            &mut rowclones,
-            copy_window,
-            &mut copy_logs,
-            output,
-            stats,
-        ) {
-            continue;
-        } else if check_potential_copy_start(&mem_access, &copy_window, &mut potential_copies) {
+        if part_of_ongoing_copy(&mem_access, &mut copies.ongoing, stats, config)
+            || part_of_potential_copy(&mem_access, &mut copies, window, output, stats, config)?
+            || check_potential_copy_start(
+                &mem_access,
+                &window.records,
+                &mut copies.potential,
+                window.log.subarray,
+            )
+        {
             continue;
         }
 
-        print_regular_access(&mem_access, output);
+        print_regular_access(&mem_access, output)?;
+    }
+
+    if let Some(path) = options.dump_unmatched {
+        dump_unmatched(path, &window.records, &copies.ongoing, &copies.potential)?;
     }
+    Ok(DetectionStats {
+        rowclones_matched: copies.rowclones,
+        non_rowclonable: copies.non_rowclonable,
+        potential_copies: copies.potential.len(),
+        unfinished_copies: copies.ongoing.len(),
+        unmatched_kernel_records: 0,
+        accesses,
+    })
+}
 
-    eprintln!("Rowclones matched: {}", rowclones);
-    eprintln!("Potential copies: {}", potential_copies.len());
-    eprintln!("Unfinished copies: {}", ongoing_copies.len());
+// What is written besides the annotated trace.
+pub struct OutputOptions<'a> {
+    pub binary_out: bool,
+    #[cfg(feature = "serde")]
+    pub stats_json: Option<&'a str>,
+    pub dump_unmatched: Option<&'a str>,
+    // memory accesses between two progress reports, 0 disables them
+    pub progress_interval: u64,
 }
 
+// Annotates the memory accesses on stdin and writes the trace to stdout.
 pub fn add_rowclone_info(
     mem_reader: BufReader<std::io::Stdin>,
     kernel_logfile: &str,
+    kernel_format: Option<KernelLogFormat>,
+    subarray: Subarray,
+    config: DetectionConfig,
+    options: OutputOptions,
 ) -> io::Result<()> {
-    let mut stats = Stats {
-        total: 0,
-        not4kb: 0,
-        notaligned: 0,
-        not_same_subarray: 0,
-        rowclone: 0,
-    };
+    let kernel_log = BufReader::new(File::open(kernel_logfile)?);
+    add_rowclone_info_to(
+        mem_reader,
+        kernel_log,
+        std::io::stdout(),
+        kernel_format,
+        subarray,
+        config,
+        options,
+    )?;
+    Ok(())
+}
 
-    let kernel_log = File::open(kernel_logfile)?;
-    let mut writer = BufWriter::new(std::io::stdout());
-    let reader = BufReader::new(kernel_log);
-    let mut lines = reader.lines();
-    let mut copy_window = lines
-        .by_ref()
-        .filter_map(|l| {
-            let line = l.expect("Failed to read copy line");
-            parse_kernel_line(&line)
-        })
-        .filter_map(|record| filter_non_rowclone(record, &mut stats))
-        .take(COPY_WINDOW)
-        .collect();
+// Like add_rowclone_info, for any memory access log, kernel log and output.
+pub fn add_rowclone_info_to(
+    mem_reader: impl BufRead,
+    kernel_log: impl BufRead,
+    out: impl Write,
+    kernel_format: Option<KernelLogFormat>,
+    subarray: Subarray,
+    config: DetectionConfig,
+    options: OutputOptions,
+) -> io::Result<DetectionStats> {
+    let mut stats = Stats::default();
+
+    let mut writer = TraceWriter {
+        out: BufWriter::new(out),
+        binary: options.binary_out,
+    };
+    let mut window = KernelWindow {
+        records: vec![],
+        log: KernelLog::new(kernel_log.lines(), kernel_format, subarray),
+    };
+    window.fill(&mut stats, config);
 
-    match_copy_to_mem_accesses(mem_reader, lines, &mut copy_window, &mut writer, &mut stats);
+    let mut detection = match_copy_to_mem_accesses(
+        mem_reader,
+        &mut window,
+        &mut writer,
+        &mut stats,
+        config,
+        &options,
+    )?;
 
-    eprintln!("Unmatched Rowclones: {}", copy_window.len());
+    detection.unmatched_kernel_records = window.records.len();
+    eprintln!("Memory accesses: {}", detection.accesses);
+    eprintln!("Rowclones matched: {}", detection.rowclones_matched);
+    eprintln!("Non-rowclonable copies: {}", detection.non_rowclonable);
+    eprintln!("Potential copies: {}", detection.potential_copies);
+    eprintln!("Unfinished copies: {}", detection.unfinished_copies);
+    eprintln!(
+        "Unmatched Rowclones: {}",
+        detection.unmatched_kernel_records
+    );
     eprintln!("{:#?}", stats);
-    let _ = writer.flush();
-    Ok(())
+    writer.out.flush()?;
+    #[cfg(feature = "serde")]
+    if let Some(path) = options.stats_json {
+        serde_json::to_writer_pretty(File::create(path)?, &detection)?;
+    }
+    Ok(detection)
 }
 #[derive(Parser, Debug)]
 #[command(about)]
 struct Args {
-    // Whether the input logs are in binary format
-    #[arg(short, long)]
+    // memory access log to annotate, stdin if omitted or -
+    #[arg(long)]
+    mem_log: Option<String>,
+
+    // kernel copy log the memory accesses are matched against
+    #[arg(short, long, alias = "kernel-log")]
     kernel_logfile: String,
+
+    // write the annotated trace to this file instead of stdout
+    #[arg(short, long)]
+    out: Option<String>,
+
+    // format of the kernel copy log, detected from the first parseable line if omitted
+    #[arg(long, value_enum)]
+    kernel_format: Option<KernelLogFormat>,
+
+    // lowest address bit of the subarray index
+    #[arg(long, default_value_t = SUBARRAY_LSB)]
+    subarray_lsb: u32,
+
+    // width of the subarray index in bits
+    #[arg(long, default_value_t = SUBARRAY_BITS)]
+    subarray_bits: u32,
+
+    // number of kernel copy records considered for matching at a time
+    #[arg(long, default_value_t = COPY_WINDOW)]
+    copy_window: usize,
+
+    // matches to newer records after which a kernel record is dropped from the window
+    #[arg(long, default_value_t = COPY_WINDOW_STALE_THRESHOLD)]
+    stale_threshold: usize,
+
+    // bytes of matching loads and stores needed to confirm a copy
+    #[arg(long, default_value_t = COPY_CONFIDENCE_THRESHOLD)]
+    confidence_threshold: u64,
+
+    // bytes a copy may skip ahead when an access is missing (0 requires exact matches)
+    #[arg(long, default_value_t = COPY_MATCH_TOLERANCE)]
+    match_tolerance: u64,
+
+    // write detection statistics as JSON to this file
+    #[cfg(feature = "serde")]
+    #[arg(long)]
+    stats_json: Option<String>,
+
+    // write the annotated trace as binary frames instead of text
+    #[arg(long, default_value_t = false)]
+    binary_out: bool,
+
+    // write the kernel records and copies left unmatched at the end as CSV to this file
+    #[arg(long)]
+    dump_unmatched: Option<String>,
+
+    // print progress to stderr every this many memory accesses (0 disables it)
+    #[arg(long, default_value_t = 10_000_000)]
+    progress_interval: u64,
 }
 
 fn main() {
     let args = Args::parse();
-    let reader = BufReader::new(std::io::stdin());
-    if add_rowclone_info(reader, &args.kernel_logfile).is_ok() {
-        eprintln!("Finished adding rowclone info");
-    } else {
-        eprintln!("Error adding rowclone info");
+    if args.subarray_bits == 0 || args.subarray_lsb + args.subarray_bits > 64 {
+        eprintln!(
+            "Invalid subarray bits {}..{}",
+            args.subarray_lsb,
+            args.subarray_lsb + args.subarray_bits
+        );
+        std::process::exit(1);
+    }
+    if args.copy_window == 0 || args.confidence_threshold == 0 {
+        eprintln!("The copy window and confidence threshold must be non-zero");
+        std::process::exit(1);
+    }
+    let subarray = Subarray::new(args.subarray_lsb, args.subarray_bits);
+    let config = DetectionConfig {
+        copy_window: args.copy_window,
+        stale_threshold: args.stale_threshold,
+        confidence_threshold: args.confidence_threshold,
+        match_tolerance: args.match_tolerance,
+    };
+    let input: Box<dyn Read> = match args.mem_log.as_deref() {
+        None | Some("-") => Box::new(std::io::stdin()),
+        Some(path) => match File::open(path) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                eprintln!("Failed to open memory log {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+    };
+    let kernel_log = match File::open(&args.kernel_logfile) {
+        Ok(file) => BufReader::new(file),
+        Err(e) => {
+            eprintln!("Failed to open kernel log {}: {}", args.kernel_logfile, e);
+            std::process::exit(1);
+        }
+    };
+    let out: Box<dyn Write> = match args.out.as_deref() {
+        None => Box::new(std::io::stdout()),
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                eprintln!("Failed to create {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+    };
+    let added = add_rowclone_info_to(
+        BufReader::new(input),
+        kernel_log,
+        out,
+        args.kernel_format,
+        subarray,
+        config,
+        OutputOptions {
+            binary_out: args.binary_out,
+            #[cfg(feature = "serde")]
+            stats_json: args.stats_json.as_deref(),
+            dump_unmatched: args.dump_unmatched.as_deref(),
+            progress_interval: args.progress_interval,
+        },
+    );
+    match added {
+        Ok(_) => eprintln!("Finished adding rowclone info"),
+        Err(e) => {
+            eprintln!("Error adding rowclone info: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KERNEL: u64 = 0xffff_8880_0001_0000;
+
+    fn config() -> DetectionConfig {
+        DetectionConfig {
+            copy_window: COPY_WINDOW,
+            stale_threshold: COPY_WINDOW_STALE_THRESHOLD,
+            confidence_threshold: COPY_CONFIDENCE_THRESHOLD,
+            match_tolerance: COPY_MATCH_TOLERANCE,
+        }
+    }
+
+    // A page copied from `from` to `to` as 8 byte loads and stores, starting at `clock`.
+    fn copy_accesses(from: u64, to: u64, first_clock: u64) -> String {
+        let mut mem_log = String::new();
+        for offset in (0..4096).step_by(8) {
+            let clock = first_clock + offset / 4;
+            mem_log += &format!("{},{},0,0,3,0x{:016x}\n", clock, clock, from + offset);
+            mem_log += &format!("{},{},0,1,3,0x{:016x}\n", clock + 1, clock + 1, to + offset);
+        }
+        mem_log
+    }
+
+    // A copy_to_user of a page from KERNEL to `user` as 8 byte loads and stores.
+    fn copy_to_user(user: u64) -> (String, String) {
+        let kernel_log = format!("N=cat,r,0,4096,0x0,0x{:x},0x0,0x{:x}\n", KERNEL, user);
+        (kernel_log, copy_accesses(KERNEL, user, 0))
+    }
+
+    fn options() -> OutputOptions<'static> {
+        OutputOptions {
+            binary_out: false,
+            #[cfg(feature = "serde")]
+            stats_json: None,
+            dump_unmatched: None,
+            progress_interval: 0,
+        }
+    }
+
+    // Runs the whole detection over in-memory logs and returns the annotated trace.
+    fn annotate(
+        kernel_log: &str,
+        mem_log: &str,
+        config: DetectionConfig,
+        options: OutputOptions,
+    ) -> (DetectionStats, String) {
+        let mut out = vec![];
+        let detection = add_rowclone_info_to(
+            mem_log.as_bytes(),
+            kernel_log.as_bytes(),
+            &mut out,
+            None,
+            Subarray::new(SUBARRAY_LSB, SUBARRAY_BITS),
+            config,
+            options,
+        )
+        .unwrap();
+        (detection, String::from_utf8(out).unwrap())
+    }
+
+    fn detect(kernel_log: &str, mem_log: &str, subarray: Subarray) -> (DetectionStats, String) {
+        let (detection, _, trace) = detect_with(kernel_log, mem_log, subarray, config());
+        (detection, trace)
+    }
+
+    // Like detect, also returning the counters printed at the end.
+    fn detect_with(
+        kernel_log: &str,
+        mem_log: &str,
+        subarray: Subarray,
+        config: DetectionConfig,
+    ) -> (DetectionStats, Stats, String) {
+        let mut stats = Stats::default();
+        let mut window = KernelWindow {
+            records: vec![],
+            log: KernelLog::new(
+                kernel_log.as_bytes().lines(),
+                Some(KernelLogFormat::Legacy),
+                subarray,
+            ),
+        };
+        window.fill(&mut stats, config);
+        let mut output = TraceWriter {
+            out: BufWriter::new(vec![]),
+            binary: false,
+        };
+        let detection = match_copy_to_mem_accesses(
+            mem_log.as_bytes(),
+            &mut window,
+            &mut output,
+            &mut stats,
+            config,
+            &options(),
+        )
+        .unwrap();
+        let trace = String::from_utf8(output.out.into_inner().unwrap()).unwrap();
+        (detection, stats, trace)
+    }
+
+    #[test]
+    fn same_subarray_copy_is_a_rowclone() {
+        let subarray = Subarray::new(SUBARRAY_LSB, SUBARRAY_BITS);
+        let user = 0x7f00_0001_0000;
+        assert!(subarray.address_in_same_subarray(KERNEL, user));
+        let (kernel_log, mem_log) = copy_to_user(user);
+        let (detection, trace) = detect(&kernel_log, &mem_log, subarray);
+        assert_eq!(detection.rowclones_matched, 1);
+        assert_eq!(detection.non_rowclonable, 0);
+        // confirmed by the store past the first 128 bytes, at instruction 33
+        assert_eq!(
+            trace,
+            format!("33,1,0,0,0x{:016x},0x{:016x},4096\n", KERNEL, user)
+        );
+    }
+
+    #[test]
+    fn cross_subarray_copy_stays_regular() {
+        let subarray = Subarray::new(SUBARRAY_LSB, SUBARRAY_BITS);
+        // one subarray further up
+        let user = 0x7f00_0001_0000 + (1 << SUBARRAY_LSB);
+        assert!(!subarray.address_in_same_subarray(KERNEL, user));
+        let (kernel_log, mem_log) = copy_to_user(user);
+        let (detection, trace) = detect(&kernel_log, &mem_log, subarray);
+        assert_eq!(detection.rowclones_matched, 0);
+        assert_eq!(detection.non_rowclonable, 1);
+        assert_eq!(detection.accesses, 1024);
+        assert_eq!(trace.lines().count(), 1024);
+        assert!(
+            trace
+                .lines()
+                .all(|line| line.split(',').nth(1) == Some("0"))
+        );
+    }
+
+    #[test]
+    fn annotates_in_memory_logs_end_to_end() {
+        let user = 0x7f00_0001_0000;
+        let (kernel_log, mut mem_log) = copy_to_user(user);
+        mem_log += "5000,5000,0,0,3,0x0000000000001000\n";
+        let (detection, trace) = annotate(&kernel_log, &mem_log, config(), options());
+        assert_eq!(detection.rowclones_matched, 1);
+        assert_eq!(detection.unmatched_kernel_records, 0);
+        assert_eq!(
+            trace,
+            format!(
+                "33,1,0,0,0x{:016x},0x{:016x},4096\n5000,0,0,0,0x0000000000001000,3\n",
+                KERNEL, user
+            )
+        );
+    }
+
+    #[test]
+    fn potential_copy_starts_one_access_past_the_first_load() {
+        let user = 0x7f00_0001_0000;
+        let copy = KernelRecord {
+            rec_id: 7,
+            command: "cat".into(),
+            cpu: 0,
+            size: 4096,
+            operation: 'r',
+            kernel_address: KERNEL,
+            user_address: user,
+            timestamp: None,
+            stale: 0,
+        };
+        let load = log_parser::LogRecord {
+            logical_clock: 1,
+            insn_count: 1,
+            cpu: 0,
+            store: 0,
+            size: 4,
+            address: KERNEL,
+        };
+        let mut potential = CopyList::default();
+        let subarray = Subarray::new(SUBARRAY_LSB, SUBARRAY_BITS);
+        assert!(check_potential_copy_start(
+            &load,
+            &[copy],
+            &mut potential,
+            subarray
+        ));
+        let started = potential.iter().next().unwrap();
+        // a 16 byte load, advancing by 1 << size rather than (address + 1) << size
+        assert_eq!(started.current_from, KERNEL + (1 << 4));
+        assert_eq!(started.current_to, user);
+        assert_eq!(started.rec_id, 7);
+    }
+
+    // An ftrace excerpt with a copy_to_user and a copy_from_user of a page, and an
+    // unrelated event in between.
+    const FTRACE_LOG: &str = "\
+# tracer: nop
+#
+#           TASK-PID     CPU#  |||||  TIMESTAMP  FUNCTION
+#              | |         |   |||||     |         |
+             cat-1234    [000] d..1.  1234.500000: probe_copy_to_user: (_copy_to_user+0x0/0x60) to=0x7f0000010000 from=0xffff888000010000 n=4096
+             cat-1234    [000] d..1.  1234.500100: sched_switch: prev_comm=cat prev_pid=1234
+              dd-1250    [002] d..1.  1234.600000: probe_copy_from_user: (_copy_from_user+0x0/0x60) to=0xffff888000030000 from=0x7f0000030000 n=4096
+";
+
+    // The same copies as FTRACE_LOG in the printk format.
+    const LEGACY_LOG: &str = "\
+N=cat,r,0,4096,0x0,0xffff888000010000,0x0,0x7f0000010000
+N=dd,w,2,4096,0x0,0xffff888000030000,0x0,0x7f0000030000
+";
+
+    #[test]
+    fn ftrace_and_legacy_logs_match_the_same_copies() {
+        let parse = |log: &str| {
+            let mut stats = Stats::default();
+            let mut kernel_log = KernelLog::new(
+                log.lines().map(|line| Ok(line.to_string())),
+                None,
+                Subarray::new(SUBARRAY_LSB, SUBARRAY_BITS),
+            );
+            let mut records = vec![];
+            while let Some(record) = next_kernel_line(&mut kernel_log, &mut stats) {
+                records.push((
+                    record.command,
+                    record.cpu,
+                    record.size,
+                    record.operation,
+                    record.kernel_address,
+                    record.user_address,
+                ));
+            }
+            (records, kernel_log.format, stats.unknown_events)
+        };
+        let (ftrace, format, unknown_events) = parse(FTRACE_LOG);
+        assert_eq!(format, Some(KernelLogFormat::Ftrace));
+        assert_eq!(unknown_events, 1);
+        let (legacy, format, _) = parse(LEGACY_LOG);
+        assert_eq!(format, Some(KernelLogFormat::Legacy));
+        assert_eq!(ftrace, legacy);
+
+        let mem_log = copy_accesses(0xffff_8880_0001_0000, 0x7f00_0001_0000, 0)
+            + &copy_accesses(0x7f00_0003_0000, 0xffff_8880_0003_0000, 5000);
+        let (from_ftrace, ftrace_trace) = annotate(FTRACE_LOG, &mem_log, config(), options());
+        let (from_legacy, legacy_trace) = annotate(LEGACY_LOG, &mem_log, config(), options());
+        assert_eq!(from_ftrace.rowclones_matched, 2);
+        assert_eq!(from_legacy.rowclones_matched, 2);
+        assert_eq!(ftrace_trace, legacy_trace);
+        assert_eq!(ftrace_trace.lines().count(), 2);
+    }
+
+    #[test]
+    fn indexed_matches_agree_with_a_linear_scan() {
+        let copy = |rec_id, from, to| MemCpy {
+            rec_id,
+            cpu: 0,
+            insn_count: 0,
+            from,
+            to,
+            size: 256,
+            current_from: from,
+            current_to: to,
+        };
+        // two copies share a source, and the third stores to where the first loads
+        let mut copies = CopyList::default();
+        copies.push(copy(0, 0x1000, 0x8000));
+        copies.push(copy(1, 0x1000, 0x9000));
+        copies.push(copy(2, 0x3000, 0x1000));
+        let scanned = |copies: &CopyList, access: &log_parser::LogRecord| -> Vec<u64> {
+            copies
+                .copies
+                .iter()
+                .filter(|(_, copy)| mem_copy_match(access, copy, 0))
+                .map(|(key, _)| *key)
+                .collect()
+        };
+        let access = |address, store| log_parser::LogRecord {
+            logical_clock: 0,
+            insn_count: 0,
+            cpu: 0,
+            store,
+            size: 3,
+            address,
+        };
+        // the copies advance in turns, so their expected addresses keep overlapping
+        let mut stats = Stats::default();
+        let mut matched = 0;
+        for offset in (0..256).step_by(8) {
+            for (from, to) in [(0x1000, 0x8000), (0x3000, 0x1000), (0x1000, 0x9000)] {
+                for access in [access(from + offset, 0), access(to + offset, 1)] {
+                    let keys = copies.matches(&access, 0);
+                    assert_eq!(keys, scanned(&copies, &access));
+                    matched += keys.len();
+                    for key in keys {
+                        copies.update(key, &access, &mut stats);
+                    }
+                }
+            }
+        }
+        // a shared load continues both copies at once, the repeated one none
+        assert_eq!(matched, 6 * 32);
+        assert!(copies.iter().all(copy_done));
+    }
+
+    #[test]
+    fn copies_missing_an_access_match_within_the_tolerance() {
+        let user = 0x7f00_0001_0000;
+        let (kernel_log, mem_log) = copy_to_user(user);
+        // drop the store at offset 64, before the copy is confirmed
+        let missing = format!("0x{:016x}", user + 64);
+        let mem_log: String = mem_log
+            .lines()
+            .filter(|line| !line.ends_with(&missing))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let subarray = Subarray::new(SUBARRAY_LSB, SUBARRAY_BITS);
+        let run = |match_tolerance| {
+            let config = DetectionConfig {
+                match_tolerance,
+                ..config()
+            };
+            detect_with(&kernel_log, &mem_log, subarray, config)
+        };
+
+        // the copy never gets past the missing store, whose successors stay regular
+        let (detection, stats, trace) = run(0);
+        assert_eq!(detection.rowclones_matched, 0);
+        assert_eq!(detection.potential_copies, 1);
+        assert_eq!(stats.skipped_bytes, 0);
+        assert_eq!(trace.lines().count(), 503);
+
+        let (detection, stats, trace) = run(8);
+        assert_eq!(detection.rowclones_matched, 1);
+        assert_eq!(
+            (detection.potential_copies, detection.unfinished_copies),
+            (0, 0)
+        );
+        assert_eq!(stats.skipped_bytes, 8);
+        assert_eq!(trace.lines().count(), 1);
+    }
+
+    #[test]
+    fn confidence_threshold_shrinks_for_small_copies() {
+        // whether a copy of `size` is confirmed after `bytes` of loads and stores
+        let confirmed = |size, bytes| {
+            let copy = MemCpy {
+                rec_id: 0,
+                cpu: 0,
+                insn_count: 0,
+                from: 0x1000,
+                to: 0x8000,
+                size,
+                current_from: 0x1000 + bytes,
+                current_to: 0x8000 + bytes,
+            };
+            copy_matched(&copy, COPY_CONFIDENCE_THRESHOLD)
+        };
+        // a tiny copy needs more than half of its bytes
+        assert!(!confirmed(64, 32));
+        assert!(confirmed(64, 40));
+        // twice the threshold is the smallest copy that needs the whole threshold
+        assert!(!confirmed(
+            2 * COPY_CONFIDENCE_THRESHOLD,
+            COPY_CONFIDENCE_THRESHOLD
+        ));
+        assert!(confirmed(
+            2 * COPY_CONFIDENCE_THRESHOLD,
+            COPY_CONFIDENCE_THRESHOLD + 8
+        ));
+        // larger copies are never confirmed before the threshold
+        assert!(!confirmed(1 << 21, COPY_CONFIDENCE_THRESHOLD));
+        assert!(confirmed(1 << 21, COPY_CONFIDENCE_THRESHOLD + 8));
+    }
+
+    #[test]
+    fn thresholds_change_the_copies_matched() {
+        let user = 0x7f00_0001_0000;
+        let (kernel_log, mem_log) = copy_to_user(user);
+        // the trace ends after the first 256 bytes of the copy
+        let mem_log: String = mem_log
+            .lines()
+            .take(64)
+            .map(|l| format!("{}\n", l))
+            .collect();
+        let subarray = Subarray::new(SUBARRAY_LSB, SUBARRAY_BITS);
+        let run = |confidence_threshold| {
+            let config = DetectionConfig {
+                confidence_threshold,
+                ..config()
+            };
+            detect_with(&kernel_log, &mem_log, subarray, config).0
+        };
+        let confident = run(COPY_CONFIDENCE_THRESHOLD);
+        assert_eq!(
+            (confident.rowclones_matched, confident.unfinished_copies),
+            (1, 1)
+        );
+        let cautious = run(512);
+        assert_eq!(
+            (cautious.rowclones_matched, cautious.potential_copies),
+            (0, 1)
+        );
+
+        // a window too small to hold the record can't match the copy either
+        let config = DetectionConfig {
+            copy_window: 1,
+            ..config()
+        };
+        let second_copy = format!(
+            "N=cat,r,0,4096,0x0,0x{:x},0x0,0x{:x}\n",
+            KERNEL + 0x2000,
+            user
+        );
+        let kernel_log = second_copy + &kernel_log;
+        let (narrow, _, _) = detect_with(&kernel_log, &mem_log, subarray, config);
+        assert_eq!(narrow.rowclones_matched, 0);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn stats_json_holds_the_detection_counts() {
+        let user = 0x7f00_0001_0000;
+        let (mut kernel_log, mut mem_log) = copy_to_user(user);
+        // a record no access matches and a regular load
+        kernel_log += &format!(
+            "N=cat,r,0,4096,0x0,0x{:x},0x0,0x{:x}\n",
+            KERNEL + 0x4000,
+            user
+        );
+        mem_log += "5000,5000,0,0,3,0x0000000000001000\n";
+        let path = std::env::temp_dir().join(format!("rowclone_stats_{}.json", std::process::id()));
+        let options = OutputOptions {
+            stats_json: path.to_str(),
+            ..options()
+        };
+        let (detection, _) = annotate(&kernel_log, &mem_log, config(), options);
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "rowclones_matched": 1,
+                "non_rowclonable": 0,
+                "potential_copies": 0,
+                "unfinished_copies": 0,
+                "unmatched_kernel_records": 1,
+                "accesses": 1025,
+            })
+        );
+        assert_eq!(json["accesses"], detection.accesses);
+    }
+
+    #[test]
+    fn unmatched_records_and_unfinished_copies_are_dumped() {
+        let user = 0x7f00_0001_0000;
+        let (mut kernel_log, mem_log) = copy_to_user(user);
+        kernel_log += &format!(
+            "N=dd,w,1,8192,0x0,0x{:x},0x0,0x7f0000020000\n",
+            KERNEL + 0x4000
+        );
+        // the trace ends after the first 256 bytes of the copy
+        let mem_log: String = mem_log
+            .lines()
+            .take(64)
+            .map(|l| format!("{}\n", l))
+            .collect();
+        let path =
+            std::env::temp_dir().join(format!("rowclone_unmatched_{}.csv", std::process::id()));
+        let options = OutputOptions {
+            dump_unmatched: path.to_str(),
+            ..options()
+        };
+        let (detection, _) = annotate(&kernel_log, &mem_log, config(), options);
+        assert_eq!(detection.unfinished_copies, 1);
+        let dump = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            dump.lines().collect::<Vec<_>>(),
+            [
+                "kind,command,cpu,size,op,kernel_address,user_address,from,to,current_from,current_to,remaining",
+                "kernel,\"dd\",1,8192,w,0xffff888000014000,0x00007f0000020000,,,,,",
+                "ongoing,,0,4096,,,,0xffff888000010000,0x00007f0000010000,0xffff888000010100,0x00007f0000010100,3840",
+            ]
+        );
     }
 }
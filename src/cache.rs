@@ -0,0 +1,3158 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::ops::Range;
+
+use clap::ValueEnum;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug)]
+pub struct Cache {
+    block_size: usize, // in bytes
+    page_size: u64,    // granularity of invalidate_page
+    sets: Vec<CacheSet>,
+    policy: Box<dyn ReplacementPolicy>,
+    prefetcher_kind: PrefetcherKind,
+    prefetcher: Option<Box<dyn Prefetcher>>,
+    // maximum number of blocks fetched per prefetch trigger
+    prefetch_degree: usize,
+    allocation: AllocationPolicy,
+    write_policy: WritePolicy,
+    index_hash: IndexHash,
+    victim: Option<VictimCache>,
+    classifier: Option<MissClassifier>,
+    mshrs: Option<Mshr>,
+    // ways each CPU may fill, indexed by CPU; CPUs without an entry use all ways
+    partition: Vec<Range<usize>>,
+    stats: CacheStats,
+    // demand accesses per set
+    set_accesses: Vec<u64>,
+}
+
+/// Decides which way of a full set gets replaced. A single policy instance keeps the
+/// replacement state of all sets of a cache.
+pub trait ReplacementPolicy: fmt::Debug {
+    fn on_hit(&mut self, set: usize, way: usize);
+    fn on_fill(&mut self, set: usize, way: usize);
+    fn on_invalidate(&mut self, set: usize, way: usize);
+    fn victim(&mut self, set: usize) -> usize;
+    /// Like victim, but only ways in the range may be chosen, e.g. for way partitioning.
+    fn victim_in(&mut self, set: usize, ways: Range<usize>) -> usize {
+        let way = self.victim(set);
+        if ways.contains(&way) { way } else { ways.start }
+    }
+    /// Restarts the policy's random number generator, if it has one.
+    fn reseed(&mut self, _seed: u64) {}
+    /// Ways of the set from most to least recently used, if the policy tracks recency.
+    fn recency(&self, _set: usize) -> Option<Vec<usize>> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PolicyKind {
+    Lru,
+    Fifo,
+    Random,
+    Srrip,
+    TreePlru,
+}
+
+impl PolicyKind {
+    pub fn build(self, num_sets: usize, associativity: usize) -> Box<dyn ReplacementPolicy> {
+        match self {
+            PolicyKind::Lru => Box::new(Lru::new(num_sets, associativity)),
+            PolicyKind::Fifo => Box::new(Fifo(Lru::new(num_sets, associativity))),
+            PolicyKind::Random => Box::new(Random::new(associativity, XorShift64::DEFAULT_SEED)),
+            PolicyKind::Srrip => Box::new(Srrip::new(num_sets, associativity)),
+            PolicyKind::TreePlru => Box::new(TreePlru::new(num_sets, associativity)),
+        }
+    }
+}
+
+/// Whether a store miss installs the block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AllocationPolicy {
+    WriteAllocate,
+    // store misses go to the next level without installing a line
+    NoWriteAllocate,
+}
+
+/// Whether stores are buffered in the cache or passed on to memory right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WritePolicy {
+    // stores mark the line dirty and reach memory when it is written back
+    #[value(name = "writeback")]
+    WriteBack,
+    // stores update the line but leave it clean
+    #[value(name = "writethrough")]
+    WriteThrough,
+}
+
+/// How a block address is mapped to its set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum IndexHash {
+    // the low bits of the block address
+    None,
+    // the low bits XORed with every higher group of as many bits, spreading
+    // power-of-two strides over all sets
+    Xor,
+}
+
+/// Hardware prefetcher issuing non-demand fills alongside demand accesses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PrefetcherKind {
+    None,
+    // on a demand miss to block N, also fetch the blocks following it
+    NextLine,
+    // per-page stride detection, since the logs carry no PC to key on
+    Stride,
+    // on a demand miss, also fetch the other block of its aligned pair (the buddy)
+    Adjacent,
+}
+
+/// Decides which blocks to fetch ahead of the demand accesses it is trained on.
+pub trait Prefetcher: fmt::Debug {
+    /// Trains on a demand access and returns the addresses of the blocks to prefetch.
+    fn on_access(&mut self, address: u64, hit: bool) -> Vec<u64>;
+}
+
+impl PrefetcherKind {
+    pub fn build(self, block_size: usize, degree: usize) -> Option<Box<dyn Prefetcher>> {
+        let block_size = block_size as u64;
+        match self {
+            PrefetcherKind::None => None,
+            PrefetcherKind::NextLine => Some(Box::new(NextLinePrefetcher { block_size, degree })),
+            PrefetcherKind::Stride => Some(Box::new(StridePrefetcher {
+                block_size,
+                degree,
+                table: StrideTable::default(),
+            })),
+            PrefetcherKind::Adjacent => Some(Box::new(AdjacentLinePrefetcher { block_size })),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct NextLinePrefetcher {
+    block_size: u64,
+    degree: usize,
+}
+
+impl Prefetcher for NextLinePrefetcher {
+    fn on_access(&mut self, address: u64, hit: bool) -> Vec<u64> {
+        if hit {
+            return vec![];
+        }
+        let block_addr = address / self.block_size;
+        (1..=self.degree as u64)
+            .map(|i| (block_addr + i) * self.block_size)
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct AdjacentLinePrefetcher {
+    block_size: u64,
+}
+
+impl Prefetcher for AdjacentLinePrefetcher {
+    fn on_access(&mut self, address: u64, hit: bool) -> Vec<u64> {
+        if hit {
+            return vec![];
+        }
+        let block_addr = address / self.block_size;
+        vec![(block_addr ^ 1) * self.block_size]
+    }
+}
+
+#[derive(Debug)]
+pub struct StridePrefetcher {
+    block_size: u64,
+    degree: usize,
+    table: StrideTable,
+}
+
+impl Prefetcher for StridePrefetcher {
+    fn on_access(&mut self, address: u64, _hit: bool) -> Vec<u64> {
+        let block_addr = address / self.block_size;
+        let blocks_per_page = (PAGE_SIZE / self.block_size).max(1);
+        let Some(stride) = self.table.train(block_addr, blocks_per_page) else {
+            return vec![];
+        };
+        // stay within the page that trained the stride
+        let page = block_addr / blocks_per_page;
+        (1..=self.degree as i64)
+            .map(|i| block_addr as i64 + stride * i)
+            .take_while(|&block| block >= 0 && block as u64 / blocks_per_page == page)
+            .map(|block| block as u64 * self.block_size)
+            .collect()
+    }
+}
+
+// number of pages the stride prefetcher tracks at once
+const STRIDE_TABLE_ENTRIES: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+struct StrideEntry {
+    page: u64,
+    last_block: u64,
+    // in blocks
+    stride: i64,
+    // number of consecutive accesses that repeated the stride
+    confidence: u32,
+}
+
+// Last block and stride of recently accessed pages, most recently used first.
+#[derive(Debug, Default)]
+struct StrideTable {
+    entries: VecDeque<StrideEntry>,
+}
+
+impl StrideTable {
+    /// Trains on an access to the block and returns the stride once it has been
+    /// observed twice in a row.
+    fn train(&mut self, block_addr: u64, blocks_per_page: u64) -> Option<i64> {
+        let page = block_addr / blocks_per_page;
+        let Some(pos) = self.entries.iter().position(|entry| entry.page == page) else {
+            if self.entries.len() == STRIDE_TABLE_ENTRIES {
+                self.entries.pop_back();
+            }
+            self.entries.push_front(StrideEntry {
+                page,
+                last_block: block_addr,
+                stride: 0,
+                confidence: 0,
+            });
+            return None;
+        };
+        let mut entry = self.entries.remove(pos)?;
+        let stride = block_addr as i64 - entry.last_block as i64;
+        // repeated accesses to the same block say nothing about the stride
+        if stride != 0 {
+            if stride == entry.stride {
+                entry.confidence += 1;
+            } else {
+                entry.stride = stride;
+                entry.confidence = 0;
+            }
+            entry.last_block = block_addr;
+        }
+        self.entries.push_front(entry);
+        (stride != 0 && entry.confidence > 0).then_some(entry.stride)
+    }
+}
+
+// Sentinel for "no way" in the LRU lists.
+const NIL: usize = usize::MAX;
+
+// LRU ordering kept as an intrusive doubly linked list over the ways of each set, so
+// hits, fills and victim selection are all O(1).
+#[derive(Debug)]
+pub struct Lru {
+    associativity: usize,
+    // least-recently used way of each set
+    head: Vec<usize>,
+    // most-recently used way of each set
+    tail: Vec<usize>,
+    // list links, indexed by set * associativity + way
+    prev: Vec<usize>,
+    next: Vec<usize>,
+    linked: Vec<bool>,
+}
+
+impl Lru {
+    pub fn new(num_sets: usize, associativity: usize) -> Self {
+        let lines = num_sets * associativity;
+        Lru {
+            associativity,
+            head: vec![NIL; num_sets],
+            tail: vec![NIL; num_sets],
+            prev: vec![NIL; lines],
+            next: vec![NIL; lines],
+            linked: vec![false; lines],
+        }
+    }
+
+    fn unlink(&mut self, set: usize, way: usize) {
+        let base = set * self.associativity;
+        let i = base + way;
+        if !self.linked[i] {
+            return;
+        }
+        let (prev, next) = (self.prev[i], self.next[i]);
+        if prev == NIL {
+            self.head[set] = next;
+        } else {
+            self.next[base + prev] = next;
+        }
+        if next == NIL {
+            self.tail[set] = prev;
+        } else {
+            self.prev[base + next] = prev;
+        }
+        self.linked[i] = false;
+    }
+
+    fn push_back(&mut self, set: usize, way: usize) {
+        let base = set * self.associativity;
+        let i = base + way;
+        // a way linked twice would corrupt the recency order
+        debug_assert!(
+            !self.linked[i],
+            "way {} of set {} is already linked",
+            way, set
+        );
+        let tail = self.tail[set];
+        self.prev[i] = tail;
+        self.next[i] = NIL;
+        if tail == NIL {
+            self.head[set] = way;
+        } else {
+            self.next[base + tail] = way;
+        }
+        self.tail[set] = way;
+        self.linked[i] = true;
+    }
+}
+
+impl ReplacementPolicy for Lru {
+    fn on_hit(&mut self, set: usize, way: usize) {
+        self.unlink(set, way);
+        self.push_back(set, way);
+    }
+
+    fn on_fill(&mut self, set: usize, way: usize) {
+        self.on_hit(set, way);
+    }
+
+    fn on_invalidate(&mut self, set: usize, way: usize) {
+        self.unlink(set, way);
+    }
+
+    fn victim(&mut self, set: usize) -> usize {
+        self.head[set]
+    }
+
+    fn victim_in(&mut self, set: usize, ways: Range<usize>) -> usize {
+        let base = set * self.associativity;
+        let mut way = self.head[set];
+        while way != NIL && !ways.contains(&way) {
+            way = self.next[base + way];
+        }
+        if way == NIL { ways.start } else { way }
+    }
+
+    fn recency(&self, set: usize) -> Option<Vec<usize>> {
+        let base = set * self.associativity;
+        let mut order = vec![];
+        let mut way = self.tail[set];
+        while way != NIL {
+            order.push(way);
+            way = self.prev[base + way];
+        }
+        Some(order)
+    }
+}
+
+// Replaces lines in the order they were filled, hits don't matter.
+#[derive(Debug)]
+pub struct Fifo(Lru);
+
+impl ReplacementPolicy for Fifo {
+    fn on_hit(&mut self, _set: usize, _way: usize) {}
+
+    fn on_fill(&mut self, set: usize, way: usize) {
+        self.0.on_fill(set, way);
+    }
+
+    fn on_invalidate(&mut self, set: usize, way: usize) {
+        self.0.on_invalidate(set, way);
+    }
+
+    fn victim(&mut self, set: usize) -> usize {
+        self.0.victim(set)
+    }
+
+    fn victim_in(&mut self, set: usize, ways: Range<usize>) -> usize {
+        self.0.victim_in(set, ways)
+    }
+
+    // fill order, newest first
+    fn recency(&self, set: usize) -> Option<Vec<usize>> {
+        self.0.recency(set)
+    }
+}
+
+#[derive(Debug)]
+pub struct Random {
+    associativity: usize,
+    rng: XorShift64,
+}
+
+impl Random {
+    pub fn new(associativity: usize, seed: u64) -> Self {
+        Random {
+            associativity,
+            rng: XorShift64::new(seed),
+        }
+    }
+}
+
+impl ReplacementPolicy for Random {
+    fn on_hit(&mut self, _set: usize, _way: usize) {}
+
+    fn on_fill(&mut self, _set: usize, _way: usize) {}
+
+    fn on_invalidate(&mut self, _set: usize, _way: usize) {}
+
+    fn victim(&mut self, _set: usize) -> usize {
+        (self.rng.next() % self.associativity as u64) as usize
+    }
+
+    fn victim_in(&mut self, _set: usize, ways: Range<usize>) -> usize {
+        ways.start + (self.rng.next() % ways.len() as u64) as usize
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.rng = XorShift64::new(seed);
+    }
+}
+
+// Static re-reference interval prediction (Jaleel et al., ISCA 2010) with 2-bit
+// re-reference prediction values. Fills are predicted to be re-referenced in the
+// long (not distant) future and hits promote to near-immediate, so a streaming scan
+// ages out before the lines that are actually reused.
+#[derive(Debug)]
+pub struct Srrip {
+    associativity: usize,
+    rrpv: Vec<u8>,
+}
+
+impl Srrip {
+    const MAX_RRPV: u8 = 3;
+
+    pub fn new(num_sets: usize, associativity: usize) -> Self {
+        Srrip {
+            associativity,
+            rrpv: vec![Self::MAX_RRPV; num_sets * associativity],
+        }
+    }
+
+    fn set_rrpv(&mut self, set: usize) -> &mut [u8] {
+        let start = set * self.associativity;
+        &mut self.rrpv[start..start + self.associativity]
+    }
+}
+
+impl ReplacementPolicy for Srrip {
+    fn on_hit(&mut self, set: usize, way: usize) {
+        self.set_rrpv(set)[way] = 0;
+    }
+
+    fn on_fill(&mut self, set: usize, way: usize) {
+        self.set_rrpv(set)[way] = Self::MAX_RRPV - 1;
+    }
+
+    fn on_invalidate(&mut self, set: usize, way: usize) {
+        self.set_rrpv(set)[way] = Self::MAX_RRPV;
+    }
+
+    fn victim(&mut self, set: usize) -> usize {
+        self.victim_in(set, 0..self.associativity)
+    }
+
+    fn victim_in(&mut self, set: usize, ways: Range<usize>) -> usize {
+        let rrpv = &mut self.set_rrpv(set)[ways.clone()];
+        loop {
+            if let Some(way) = rrpv.iter().position(|&v| v == Self::MAX_RRPV) {
+                return ways.start + way;
+            }
+            // age the whole set until some line is predicted distant
+            rrpv.iter_mut().for_each(|v| *v += 1);
+        }
+    }
+}
+
+// Tree pseudo-LRU: a binary tree of bits per set whose bits point away from the most
+// recently used half. Ways beyond a non power of two associativity are never chosen.
+#[derive(Debug)]
+pub struct TreePlru {
+    associativity: usize,
+    leaves: usize,
+    // heap-ordered tree nodes of each set, true means the victim is in the right half
+    bits: Vec<bool>,
+}
+
+impl TreePlru {
+    pub fn new(num_sets: usize, associativity: usize) -> Self {
+        let leaves = associativity.next_power_of_two();
+        TreePlru {
+            associativity,
+            leaves,
+            bits: vec![false; num_sets * (leaves - 1)],
+        }
+    }
+
+    // Walks from the root to the way, pointing every node away from it or towards it.
+    fn point(&mut self, set: usize, way: usize, towards: bool) {
+        let base = set * (self.leaves - 1);
+        let (mut node, mut lo, mut size) = (0, 0, self.leaves);
+        while size > 1 {
+            size /= 2;
+            let right = way >= lo + size;
+            self.bits[base + node] = right == towards;
+            if right {
+                lo += size;
+                node = 2 * node + 2;
+            } else {
+                node = 2 * node + 1;
+            }
+        }
+    }
+}
+
+impl ReplacementPolicy for TreePlru {
+    fn on_hit(&mut self, set: usize, way: usize) {
+        self.point(set, way, false);
+    }
+
+    fn on_fill(&mut self, set: usize, way: usize) {
+        self.point(set, way, false);
+    }
+
+    fn on_invalidate(&mut self, set: usize, way: usize) {
+        self.point(set, way, true);
+    }
+
+    fn victim(&mut self, set: usize) -> usize {
+        self.victim_in(set, 0..self.associativity)
+    }
+
+    fn victim_in(&mut self, set: usize, ways: Range<usize>) -> usize {
+        let base = set * (self.leaves - 1);
+        let (mut node, mut lo, mut size) = (0, 0, self.leaves);
+        while size > 1 {
+            size /= 2;
+            // a half may consist of padding ways or ways outside the range only
+            let left = lo < ways.end && ways.start < lo + size;
+            let right = lo + size < ways.end && ways.start < lo + 2 * size;
+            if right && (self.bits[base + node] || !left) {
+                lo += size;
+                node = 2 * node + 2;
+            } else {
+                node = 2 * node + 1;
+            }
+        }
+        lo
+    }
+}
+
+// Small deterministic PRNG so random replacement is reproducible between runs.
+#[derive(Debug, Clone)]
+struct XorShift64(u64);
+
+impl XorShift64 {
+    const DEFAULT_SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+    fn new(seed: u64) -> Self {
+        // xorshift gets stuck at zero
+        XorShift64(if seed == 0 { Self::DEFAULT_SEED } else { seed })
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CacheStats {
+    pub accesses: u64,
+    pub hits: u64,
+    pub misses: u64,
+    // lines evicted to make room for a fill
+    pub evictions: u64,
+    // lines removed by rowclone invalidation
+    pub invalidations: u64,
+    // lines removed because another CPU stored to them
+    pub coherence_invalidations: u64,
+    // modified lines written back and shared because another CPU loaded them
+    pub coherence_downgrades: u64,
+    // prefetch fills, not counted as accesses
+    pub prefetches: u64,
+    // prefetch fills of the buddy of a missing block, included in prefetches
+    pub buddy_prefetches: u64,
+    // breakdown of misses, only tracked with a miss classifier
+    pub compulsory_misses: u64,
+    pub capacity_misses: u64,
+    pub conflict_misses: u64,
+    // hits served by the victim cache, included in hits
+    pub victim_hits: u64,
+    // demand hits on lines brought in by a prefetch
+    pub useful_prefetches: u64,
+    // prefetched lines evicted or invalidated before their first use
+    pub useless_prefetches: u64,
+    // evictions of a line filled by another CPU than the one causing them
+    pub cross_cpu_evictions: u64,
+    // misses to a block already being filled, also counted as hits since the primary
+    // miss installed the line; only tracked with MSHRs
+    pub secondary_misses: u64,
+    // misses that found every MSHR busy
+    pub mshr_full: u64,
+}
+
+impl CacheStats {
+    pub fn miss_rate(&self) -> f64 {
+        if self.accesses == 0 {
+            0.0
+        } else {
+            self.misses as f64 / self.accesses as f64
+        }
+    }
+
+    pub fn merge(&mut self, other: &CacheStats) {
+        self.accesses += other.accesses;
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.evictions += other.evictions;
+        self.invalidations += other.invalidations;
+        self.coherence_invalidations += other.coherence_invalidations;
+        self.coherence_downgrades += other.coherence_downgrades;
+        self.victim_hits += other.victim_hits;
+        self.compulsory_misses += other.compulsory_misses;
+        self.capacity_misses += other.capacity_misses;
+        self.conflict_misses += other.conflict_misses;
+        self.prefetches += other.prefetches;
+        self.buddy_prefetches += other.buddy_prefetches;
+        self.useful_prefetches += other.useful_prefetches;
+        self.useless_prefetches += other.useless_prefetches;
+        self.cross_cpu_evictions += other.cross_cpu_evictions;
+        self.secondary_misses += other.secondary_misses;
+        self.mshr_full += other.mshr_full;
+    }
+}
+
+/// Result of a cache access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessResult {
+    pub hit: bool,
+    // address of the block evicted by this access, if any
+    pub evicted: Option<u64>,
+    // address of a dirty block evicted by this access that has to be written back
+    pub writeback: Option<u64>,
+}
+
+/// Result of invalidating a range of blocks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invalidated<A> {
+    // valid lines dropped, counted once per level holding them
+    pub lines: u64,
+    // dirty blocks that have to be written back, each reported once
+    pub dirty: Vec<A>,
+}
+
+impl<A> Default for Invalidated<A> {
+    fn default() -> Self {
+        Invalidated {
+            lines: 0,
+            dirty: vec![],
+        }
+    }
+}
+
+/// Result of an access that may span several blocks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizedAccessResult {
+    // true only if every block touched hit
+    pub hit: bool,
+    // block address and result for each block touched, in address order
+    pub blocks: Vec<(u64, AccessResult)>,
+}
+
+/// Contents of one way of a set, as reported by `Cache::dump_set`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheLineInfo {
+    pub way: usize,
+    // block address of the cached line, 0 if the way is invalid
+    pub tag: u64,
+    pub valid: bool,
+    pub dirty: bool,
+    // 0 for the most recently used line, None if the policy doesn't track recency
+    pub lru_position: Option<usize>,
+}
+
+impl fmt::Display for CacheLineInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.valid {
+            return write!(f, "way {:>2}: invalid", self.way);
+        }
+        write!(
+            f,
+            "way {:>2}: tag 0x{:012x} {}",
+            self.way,
+            self.tag,
+            if self.dirty { "dirty" } else { "clean" }
+        )?;
+        match self.lru_position {
+            Some(position) => write!(f, " lru {}", position),
+            None => Ok(()),
+        }
+    }
+}
+
+// Outcome of a lookup in a single set.
+enum SetAccess {
+    // whether the line was brought in by a prefetch and not used since
+    Hit { prefetched: bool },
+    // the line evicted to make room, if any
+    Miss(Option<Line>),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Line {
+    tag: u64,
+    dirty: bool,
+    prefetched: bool,
+    // CPU whose access or prefetch filled the line
+    owner: usize,
+}
+
+#[derive(Debug)]
+struct CacheSet {
+    // Each cache line stores an optional tag (here, a u64 representing the block address)
+    // together with its dirty bit
+    lines: Vec<Option<Line>>,
+    // lookup structures replacing linear scans in highly associative sets
+    index: Option<SetIndex>,
+}
+
+// Sets with more ways than this keep a tag index, e.g. fully associative caches.
+// Up to it a hit scans the contiguous lines, which benches/set_lookup.rs measures to
+// be at least as fast as hashing the tag; at 64 ways the index is clearly faster.
+const INDEXED_SET_WAYS: usize = 32;
+
+#[derive(Debug)]
+struct SetIndex {
+    ways: HashMap<u64, usize>,
+    // free ways, lowest first like the linear scan
+    free: BTreeSet<usize>,
+}
+
+impl CacheSet {
+    pub fn new(associativity: usize) -> Self {
+        Self::with_index(associativity, associativity > INDEXED_SET_WAYS)
+    }
+
+    fn with_index(associativity: usize, indexed: bool) -> Self {
+        let index = indexed.then(|| SetIndex {
+            ways: HashMap::with_capacity(associativity),
+            free: (0..associativity).collect(),
+        });
+        CacheSet {
+            lines: vec![None; associativity],
+            index,
+        }
+    }
+
+    fn find(&self, tag: u64) -> Option<usize> {
+        if let Some(index) = &self.index {
+            return index.ways.get(&tag).copied();
+        }
+        self.lines
+            .iter()
+            .position(|line| line.is_some_and(|line| line.tag == tag))
+    }
+
+    fn free_way(&mut self, ways: Range<usize>) -> Option<usize> {
+        match self.index.as_mut() {
+            Some(index) => {
+                let way = index.free.range(ways).next().copied()?;
+                index.free.remove(&way);
+                Some(way)
+            }
+            None => {
+                let start = ways.start;
+                self.lines[ways]
+                    .iter()
+                    .position(|line| line.is_none())
+                    .map(|way| start + way)
+            }
+        }
+    }
+
+    pub fn access(
+        &mut self,
+        index: usize,
+        tag: u64,
+        store: bool,
+        owner: usize,
+        ways: Range<usize>,
+        policy: &mut dyn ReplacementPolicy,
+    ) -> SetAccess {
+        if let Some(pos) = self.find(tag) {
+            policy.on_hit(index, pos);
+            let mut prefetched = false;
+            if let Some(line) = self.lines[pos].as_mut() {
+                line.dirty |= store;
+                prefetched = std::mem::take(&mut line.prefetched);
+            }
+            SetAccess::Hit { prefetched }
+        } else {
+            let line = Line {
+                tag,
+                dirty: store,
+                prefetched: false,
+                owner,
+            };
+            SetAccess::Miss(self.fill(index, line, ways, policy))
+        }
+    }
+
+    // Installs a line that isn't present yet into one of the given ways and returns
+    // the evicted line, if any.
+    fn fill(
+        &mut self,
+        index: usize,
+        line: Line,
+        ways: Range<usize>,
+        policy: &mut dyn ReplacementPolicy,
+    ) -> Option<Line> {
+        let tag = line.tag;
+        let all_ways = ways.len() == self.lines.len();
+        let (way, evicted) = if let Some(free_pos) = self.free_way(ways.clone()) {
+            // Found a free line, so use it.
+            self.lines[free_pos] = Some(line);
+            (free_pos, None)
+        } else {
+            // No free line: evict the victim chosen by the replacement policy.
+            let evict_index = if all_ways {
+                policy.victim(index)
+            } else {
+                policy.victim_in(index, ways)
+            };
+            (evict_index, self.lines[evict_index].replace(line))
+        };
+        policy.on_fill(index, way);
+        if let Some(set_index) = self.index.as_mut() {
+            if let Some(evicted) = evicted {
+                set_index.ways.remove(&evicted.tag);
+            }
+            set_index.ways.insert(tag, way);
+        }
+        evicted
+    }
+    // Invalidate a specific block tag in this set (if present).
+    // Returns the removed line.
+    pub fn invalidate(
+        &mut self,
+        index: usize,
+        tag: u64,
+        policy: &mut dyn ReplacementPolicy,
+    ) -> Option<Line> {
+        let pos = self.find(tag)?;
+        // Remove from replacement tracking
+        policy.on_invalidate(index, pos);
+        if let Some(set_index) = self.index.as_mut() {
+            set_index.ways.remove(&tag);
+            set_index.free.insert(pos);
+        }
+        // Remove the line
+        self.lines[pos].take()
+    }
+}
+
+// Small fully associative buffer holding lines evicted from the sets, oldest first.
+// Lines only leave the cache once they drop out of it.
+#[derive(Debug)]
+struct VictimCache {
+    capacity: usize,
+    lines: VecDeque<Line>,
+}
+
+impl VictimCache {
+    fn new(capacity: usize) -> Self {
+        VictimCache {
+            capacity,
+            lines: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn find_mut(&mut self, tag: u64) -> Option<&mut Line> {
+        self.lines.iter_mut().find(|line| line.tag == tag)
+    }
+
+    fn take(&mut self, tag: u64) -> Option<Line> {
+        let pos = self.lines.iter().position(|line| line.tag == tag)?;
+        self.lines.remove(pos)
+    }
+
+    /// Buffers an evicted line and returns the oldest one if it had to make room.
+    fn insert(&mut self, line: Line) -> Option<Line> {
+        let displaced = if self.lines.len() == self.capacity {
+            self.lines.pop_front()
+        } else {
+            None
+        };
+        self.lines.push_back(line);
+        displaced
+    }
+}
+
+enum MissKind {
+    Compulsory,
+    Capacity,
+    Conflict,
+}
+
+// Classifies misses by remembering every block ever accessed (compulsory) and
+// simulating a fully associative LRU cache of the same capacity: misses it would
+// have hit are conflict misses, the others capacity misses.
+#[derive(Debug)]
+struct MissClassifier {
+    seen: HashSet<u64>,
+    capacity: usize,
+    clock: u64,
+    // last access time of every block in the shadow cache, and the reverse mapping
+    last_use: HashMap<u64, u64>,
+    by_age: BTreeMap<u64, u64>,
+}
+
+impl MissClassifier {
+    fn new(capacity: usize) -> Self {
+        MissClassifier {
+            seen: HashSet::new(),
+            capacity,
+            clock: 0,
+            last_use: HashMap::new(),
+            by_age: BTreeMap::new(),
+        }
+    }
+
+    // Accesses the shadow cache and returns whether it hit.
+    fn shadow_access(&mut self, block_addr: u64) -> bool {
+        self.clock += 1;
+        let hit = match self.last_use.insert(block_addr, self.clock) {
+            Some(previous) => {
+                self.by_age.remove(&previous);
+                true
+            }
+            None => false,
+        };
+        self.by_age.insert(self.clock, block_addr);
+        if self.last_use.len() > self.capacity
+            && let Some((_, oldest)) = self.by_age.pop_first()
+        {
+            self.last_use.remove(&oldest);
+        }
+        hit
+    }
+
+    /// Records a demand access and classifies it if the real cache missed.
+    fn classify(&mut self, block_addr: u64, hit: bool) -> Option<MissKind> {
+        let first_touch = self.seen.insert(block_addr);
+        let shadow_hit = self.shadow_access(block_addr);
+        if hit {
+            None
+        } else if first_touch {
+            Some(MissKind::Compulsory)
+        } else if shadow_hit {
+            Some(MissKind::Conflict)
+        } else {
+            Some(MissKind::Capacity)
+        }
+    }
+}
+
+/// Outcome of `Cache::access_with_mshr` and `Mshr::miss`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissStatus {
+    Hit,
+    // the block is fetched and occupies an MSHR until the fill completes
+    PrimaryMiss,
+    // the block is already being fetched, the miss waits for that fill
+    SecondaryMiss,
+    // no MSHR is free: the access has to be retried at a later cycle
+    Full,
+}
+
+/// Miss status holding registers: the blocks being fetched and the time their fill
+/// completes. Time is whatever clock the caller advances, cycles for
+/// `Cache::access_with_mshr` and instruction counts for the trace filter.
+#[derive(Debug)]
+pub struct Mshr {
+    entries: usize,
+    fill_latency: u64,
+    // block address and the time its fill completes
+    in_flight: HashMap<u64, u64>,
+    misses: u64,
+    secondary_misses: u64,
+    full: u64,
+}
+
+impl Mshr {
+    /// Panics if `entries` is 0.
+    pub fn new(entries: usize, fill_latency: u64) -> Self {
+        assert!(entries > 0, "a cache needs at least one MSHR");
+        Mshr {
+            entries,
+            fill_latency,
+            in_flight: HashMap::with_capacity(entries),
+            misses: 0,
+            secondary_misses: 0,
+            full: 0,
+        }
+    }
+
+    fn retire(&mut self, time: u64) {
+        self.in_flight.retain(|_, ready| *ready > time);
+    }
+
+    /// Returns whether the block is still being fetched at `time`.
+    pub fn in_flight(&mut self, block_addr: u64, time: u64) -> bool {
+        self.retire(time);
+        self.in_flight.contains_key(&block_addr)
+    }
+
+    /// Returns whether a miss at `time` would find no MSHR free.
+    pub fn is_full(&mut self, time: u64) -> bool {
+        self.retire(time);
+        self.in_flight.len() >= self.entries
+    }
+
+    /// Records a miss to the block at `time`. A primary miss occupies an MSHR until
+    /// its fill completes; a `Full` one isn't tracked.
+    pub fn miss(&mut self, block_addr: u64, time: u64) -> MissStatus {
+        self.misses += 1;
+        if self.in_flight(block_addr, time) {
+            self.secondary_misses += 1;
+            return MissStatus::SecondaryMiss;
+        }
+        if self.is_full(time) {
+            self.full += 1;
+            return MissStatus::Full;
+        }
+        self.in_flight
+            .insert(block_addr, time.saturating_add(self.fill_latency));
+        MissStatus::PrimaryMiss
+    }
+
+    /// Drops the fills of blocks `first..=last`, whose data is now stale.
+    pub fn invalidate_blocks(&mut self, first: u64, last: u64) {
+        self.in_flight
+            .retain(|block, _| *block < first || *block > last);
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    pub fn secondary_misses(&self) -> u64 {
+        self.secondary_misses
+    }
+
+    /// Misses that found every MSHR busy.
+    pub fn full(&self) -> u64 {
+        self.full
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.misses = 0;
+        self.secondary_misses = 0;
+        self.full = 0;
+    }
+}
+
+/// Why a cache geometry can't be built.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheConfigError {
+    ZeroParameter,
+    BlockSizeNotPowerOfTwo { block_size: usize },
+    SizeNotMultipleOfBlock { size: usize, block_size: usize },
+    // also covers caches with fewer lines than ways, which have no set at all
+    LinesNotMultipleOfWays { lines: usize, associativity: usize },
+}
+
+impl fmt::Display for CacheConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            CacheConfigError::ZeroParameter => {
+                write!(
+                    f,
+                    "cache size, block size and associativity must be non-zero"
+                )
+            }
+            CacheConfigError::BlockSizeNotPowerOfTwo { block_size } => {
+                write!(f, "block size {} is not a power of two", block_size)
+            }
+            CacheConfigError::SizeNotMultipleOfBlock { size, block_size } => write!(
+                f,
+                "cache size {} is not divisible by block size {}",
+                size, block_size
+            ),
+            CacheConfigError::LinesNotMultipleOfWays {
+                lines,
+                associativity,
+            } => write!(
+                f,
+                "{} cache lines cannot be split into sets of {} ways",
+                lines, associativity
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CacheConfigError {}
+
+impl Cache {
+    pub fn new(size: usize, block_size: usize, associativity: usize) -> Self {
+        Self::with_policy(size, block_size, associativity, PolicyKind::Lru)
+    }
+
+    /// A cache with a single set, backed by a tag index instead of linear scans.
+    /// Panics if the geometry is invalid, see try_new_fully_associative.
+    pub fn new_fully_associative(size: usize, block_size: usize) -> Self {
+        Self::try_new_fully_associative(size, block_size)
+            .unwrap_or_else(|e| panic!("invalid cache geometry: {}", e))
+    }
+
+    pub fn try_new_fully_associative(
+        size: usize,
+        block_size: usize,
+    ) -> Result<Self, CacheConfigError> {
+        // the ways follow from the other two, so they can't be zero or uneven
+        Self::check_geometry(size, block_size, 1)?;
+        Self::try_new(size, block_size, size / block_size, PolicyKind::Lru)
+    }
+
+    /// Panics if the geometry is invalid, see try_new.
+    pub fn with_policy(
+        size: usize,
+        block_size: usize,
+        associativity: usize,
+        policy: PolicyKind,
+    ) -> Self {
+        Self::try_new(size, block_size, associativity, policy)
+            .unwrap_or_else(|e| panic!("invalid cache geometry: {}", e))
+    }
+
+    /// Checks that the cache splits into at least one set of power-of-two sized blocks.
+    pub fn check_geometry(
+        size: usize,
+        block_size: usize,
+        associativity: usize,
+    ) -> Result<(), CacheConfigError> {
+        if size == 0 || block_size == 0 || associativity == 0 {
+            return Err(CacheConfigError::ZeroParameter);
+        }
+        if !block_size.is_power_of_two() {
+            return Err(CacheConfigError::BlockSizeNotPowerOfTwo { block_size });
+        }
+        if !size.is_multiple_of(block_size) {
+            return Err(CacheConfigError::SizeNotMultipleOfBlock { size, block_size });
+        }
+        let lines = size / block_size;
+        if !lines.is_multiple_of(associativity) {
+            return Err(CacheConfigError::LinesNotMultipleOfWays {
+                lines,
+                associativity,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn try_new(
+        size: usize,
+        block_size: usize,
+        associativity: usize,
+        policy: PolicyKind,
+    ) -> Result<Self, CacheConfigError> {
+        Self::check_geometry(size, block_size, associativity)?;
+        // total number of cache lines = size / block_size
+        // number of sets = (size / block_size) / associativity
+        let num_lines = size / block_size;
+        let num_sets = num_lines / associativity;
+        let sets = (0..num_sets)
+            .map(|_| CacheSet::new(associativity))
+            .collect();
+        Ok(Cache {
+            block_size,
+            page_size: PAGE_SIZE,
+            sets,
+            policy: policy.build(num_sets, associativity),
+            prefetcher_kind: PrefetcherKind::None,
+            prefetcher: None,
+            prefetch_degree: 1,
+            allocation: AllocationPolicy::WriteAllocate,
+            write_policy: WritePolicy::WriteBack,
+            index_hash: IndexHash::None,
+            victim: None,
+            classifier: None,
+            mshrs: None,
+            partition: vec![],
+            stats: CacheStats::default(),
+            set_accesses: vec![0; num_sets],
+        })
+    }
+
+    /// Sets the page size used by invalidate_page, e.g. 2MB for huge pages.
+    pub fn with_page_size(mut self, page_size: u64) -> Self {
+        assert!(
+            page_size.is_power_of_two(),
+            "page size {} is not a power of two",
+            page_size
+        );
+        self.page_size = page_size;
+        self
+    }
+
+    pub fn with_prefetcher(mut self, prefetcher: PrefetcherKind) -> Self {
+        self.prefetcher_kind = prefetcher;
+        self.prefetcher = prefetcher.build(self.block_size, self.prefetch_degree);
+        self
+    }
+
+    /// Sets how many blocks the prefetcher fetches per trigger.
+    pub fn with_prefetch_degree(mut self, degree: usize) -> Self {
+        self.prefetch_degree = degree;
+        self.prefetcher = self.prefetcher_kind.build(self.block_size, degree);
+        self
+    }
+
+    pub fn with_allocation(mut self, allocation: AllocationPolicy) -> Self {
+        self.allocation = allocation;
+        self
+    }
+
+    pub fn with_write_policy(mut self, write_policy: WritePolicy) -> Self {
+        self.write_policy = write_policy;
+        self
+    }
+
+    /// Seeds the random replacement policy so runs are reproducible. A seed of 0
+    /// selects the default seed.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.policy.reseed(seed);
+        self
+    }
+
+    pub fn with_index_hash(mut self, index_hash: IndexHash) -> Self {
+        self.index_hash = index_hash;
+        self
+    }
+
+    /// Splits the ways of every set between CPUs: CPU i only fills the i-th group of
+    /// `ways[i]` ways but hits in any way.
+    pub fn with_way_partition(mut self, ways: &[usize]) -> Self {
+        let associativity = self.sets[0].lines.len();
+        assert!(
+            ways.iter().sum::<usize>() == associativity && ways.iter().all(|&n| n > 0),
+            "way partition {:?} doesn't split {} ways",
+            ways,
+            associativity
+        );
+        let mut start = 0;
+        self.partition = ways
+            .iter()
+            .map(|&n| {
+                start += n;
+                start - n..start
+            })
+            .collect();
+        self
+    }
+
+    // Ways the CPU may fill.
+    fn ways(&self, cpu: usize) -> Range<usize> {
+        match self.partition.get(cpu) {
+            Some(ways) => ways.clone(),
+            None => 0..self.sets[0].lines.len(),
+        }
+    }
+
+    /// Looks tags up through a hash map in every set, which is otherwise only done
+    /// for sets of more than 32 ways. Meant for comparing the two lookups.
+    pub fn with_indexed_sets(mut self) -> Self {
+        let associativity = self.sets[0].lines.len();
+        for set in self.sets.iter_mut() {
+            *set = CacheSet::with_index(associativity, true);
+        }
+        self
+    }
+
+    /// Adds a fully associative victim cache of the given number of lines (0 disables it).
+    pub fn with_victim_entries(mut self, entries: usize) -> Self {
+        self.victim = (entries > 0).then(|| VictimCache::new(entries));
+        self
+    }
+
+    /// Tracks outstanding misses in `entries` MSHRs that are busy for `fill_latency`
+    /// cycles, for use with `access_with_mshr`.
+    pub fn with_mshrs(mut self, entries: usize, fill_latency: u64) -> Self {
+        self.mshrs = Some(Mshr::new(entries, fill_latency));
+        self
+    }
+
+    /// Classifies misses as compulsory, capacity or conflict misses. This remembers
+    /// every block ever accessed, so it is off by default.
+    pub fn with_miss_classification(mut self) -> Self {
+        let lines = self.sets.len() * self.sets[0].lines.len();
+        self.classifier = Some(MissClassifier::new(lines));
+        self
+    }
+
+    fn set_index(&self, block_addr: u64) -> usize {
+        let num_sets = self.sets.len() as u64;
+        let hashed = match self.index_hash {
+            IndexHash::None => block_addr,
+            IndexHash::Xor if num_sets == 1 => 0,
+            IndexHash::Xor => {
+                let bits = num_sets.next_power_of_two().trailing_zeros();
+                let mut hashed = 0;
+                let mut rest = block_addr;
+                while rest != 0 {
+                    hashed ^= rest;
+                    rest >>= bits;
+                }
+                hashed
+            }
+        };
+        (hashed % num_sets) as usize
+    }
+
+    /// Returns true if the block containing the address is cached.
+    pub fn contains(&self, address: u64) -> bool {
+        let block_addr = address / (self.block_size as u64);
+        let set_index = self.set_index(block_addr);
+        self.sets[set_index].find(block_addr).is_some()
+            || self
+                .victim
+                .as_ref()
+                .is_some_and(|victim| victim.lines.iter().any(|line| line.tag == block_addr))
+    }
+
+    /// Simulate an access to the cache. Stores mark the line dirty unless the cache
+    /// is write-through.
+    pub fn access(&mut self, address: u64, store: bool) -> AccessResult {
+        self.access_as(0, address, store)
+    }
+
+    /// Like access, but lines filled are owned by the given CPU. Only useful for
+    /// caches shared between CPUs.
+    pub fn access_as(&mut self, cpu: usize, address: u64, store: bool) -> AccessResult {
+        let result = self.lookup(cpu, address, store);
+        if let Some(classifier) = self.classifier.as_mut() {
+            match classifier.classify(address / (self.block_size as u64), result.hit) {
+                Some(MissKind::Compulsory) => self.stats.compulsory_misses += 1,
+                Some(MissKind::Capacity) => self.stats.capacity_misses += 1,
+                Some(MissKind::Conflict) => self.stats.conflict_misses += 1,
+                None => {}
+            }
+        }
+        result
+    }
+
+    fn lookup(&mut self, cpu: usize, address: u64, store: bool) -> AccessResult {
+        let block_addr = address / (self.block_size as u64);
+        let set_index = self.set_index(block_addr);
+        // The tag can simply be the block_addr
+        self.stats.accesses += 1;
+        self.set_accesses[set_index] += 1;
+        if store && self.allocation == AllocationPolicy::NoWriteAllocate && !self.contains(address)
+        {
+            self.stats.misses += 1;
+            return AccessResult {
+                hit: false,
+                evicted: None,
+                writeback: None,
+            };
+        }
+        let dirty = store && self.write_policy == WritePolicy::WriteBack;
+        // a block found in the victim cache is swapped back into its set
+        if self.sets[set_index].find(block_addr).is_none()
+            && let Some(mut line) = self
+                .victim
+                .as_mut()
+                .and_then(|victim| victim.take(block_addr))
+        {
+            self.stats.hits += 1;
+            self.stats.victim_hits += 1;
+            if std::mem::take(&mut line.prefetched) {
+                self.stats.useful_prefetches += 1;
+            }
+            line.dirty |= dirty;
+            line.owner = cpu;
+            let ways = self.ways(cpu);
+            let evicted = self.sets[set_index].fill(set_index, line, ways, self.policy.as_mut());
+            return AccessResult {
+                hit: true,
+                ..self.miss_result(cpu, evicted)
+            };
+        }
+        let ways = self.ways(cpu);
+        match self.sets[set_index].access(
+            set_index,
+            block_addr,
+            dirty,
+            cpu,
+            ways,
+            self.policy.as_mut(),
+        ) {
+            SetAccess::Hit { prefetched } => {
+                self.stats.hits += 1;
+                if prefetched {
+                    self.stats.useful_prefetches += 1;
+                }
+                AccessResult {
+                    hit: true,
+                    evicted: None,
+                    writeback: None,
+                }
+            }
+            SetAccess::Miss(evicted) => {
+                self.stats.misses += 1;
+                self.miss_result(cpu, evicted)
+            }
+        }
+    }
+
+    /// Block addresses the prefetcher wants fetched after a demand access.
+    pub fn prefetch_targets(&mut self, address: u64, hit: bool) -> Vec<u64> {
+        match self.prefetcher.as_mut() {
+            Some(prefetcher) => prefetcher.on_access(address, hit),
+            None => vec![],
+        }
+    }
+
+    /// Installs the block containing the address without counting a demand access.
+    /// Returns None if the block was already cached.
+    pub fn prefetch(&mut self, address: u64) -> Option<AccessResult> {
+        self.prefetch_as(0, address)
+    }
+
+    /// Like prefetch, but the line is owned by the given CPU.
+    pub fn prefetch_as(&mut self, cpu: usize, address: u64) -> Option<AccessResult> {
+        if self.contains(address) {
+            return None;
+        }
+        let block_addr = address / (self.block_size as u64);
+        let set_index = self.set_index(block_addr);
+        let line = Line {
+            tag: block_addr,
+            dirty: false,
+            prefetched: true,
+            owner: cpu,
+        };
+        let ways = self.ways(cpu);
+        let evicted = self.sets[set_index].fill(set_index, line, ways, self.policy.as_mut());
+        self.stats.prefetches += 1;
+        if self.prefetcher_kind == PrefetcherKind::Adjacent {
+            self.stats.buddy_prefetches += 1;
+        }
+        Some(self.miss_result(cpu, evicted))
+    }
+
+    // Moves a line evicted from its set into the victim cache, if any, and reports
+    // the line that actually left the cache.
+    fn miss_result(&mut self, cpu: usize, evicted: Option<Line>) -> AccessResult {
+        let evicted = match self.victim.as_mut() {
+            Some(victim) => evicted.and_then(|line| victim.insert(line)),
+            None => evicted,
+        };
+        if let Some(line) = evicted {
+            self.stats.evictions += 1;
+            if line.owner != cpu {
+                self.stats.cross_cpu_evictions += 1;
+            }
+            self.count_unused(&line);
+        }
+        let block_size = self.block_size as u64;
+        AccessResult {
+            hit: false,
+            evicted: evicted.map(|line| line.tag * block_size),
+            writeback: evicted
+                .filter(|line| line.dirty)
+                .map(|line| line.tag * block_size),
+        }
+    }
+
+    /// Simulate an access of `size` bytes starting at the address, touching every
+    /// block in `[address, address + size)`. A zero size touches one block.
+    pub fn access_sized(&mut self, address: u64, size: u64, store: bool) -> SizedAccessResult {
+        let block_size = self.block_size as u64;
+        let first = address / block_size;
+        let last = address.saturating_add(size.max(1) - 1) / block_size;
+        let blocks: Vec<(u64, AccessResult)> = (first..=last)
+            .map(|block| (block * block_size, self.access(block * block_size, store)))
+            .collect();
+        SizedAccessResult {
+            hit: blocks.iter().all(|(_, result)| result.hit),
+            blocks,
+        }
+    }
+
+    /// Simulate an access at the given cycle, telling primary misses from those that
+    /// wait for a fill already in flight. Without MSHRs every miss is primary. A
+    /// `Full` access leaves the cache untouched.
+    pub fn access_with_mshr(&mut self, address: u64, store: bool, cycle: u64) -> MissStatus {
+        let block_addr = address / (self.block_size as u64);
+        let cached = self.contains(address);
+        let Some(mshrs) = self.mshrs.as_mut() else {
+            return if self.access(address, store).hit {
+                MissStatus::Hit
+            } else {
+                MissStatus::PrimaryMiss
+            };
+        };
+        if mshrs.in_flight(block_addr, cycle) {
+            mshrs.miss(block_addr, cycle);
+            self.stats.secondary_misses += 1;
+            // the line was installed by the primary miss, this marks it dirty if needed
+            self.access(address, store);
+            return MissStatus::SecondaryMiss;
+        }
+        if !cached && mshrs.is_full(cycle) {
+            mshrs.miss(block_addr, cycle);
+            self.stats.mshr_full += 1;
+            return MissStatus::Full;
+        }
+        if self.access(address, store).hit {
+            return MissStatus::Hit;
+        }
+        match self.mshrs.as_mut() {
+            Some(mshrs) => mshrs.miss(block_addr, cycle),
+            None => MissStatus::PrimaryMiss,
+        }
+    }
+
+    /// Removes the block containing the address.
+    /// Returns whether the block was dirty, or None if it wasn't cached.
+    pub fn invalidate(&mut self, address: u64) -> Option<bool> {
+        let line = self.remove(address / (self.block_size as u64))?;
+        self.count_unused(&line);
+        Some(line.dirty)
+    }
+
+    // Removes the block from its set or the victim cache.
+    fn remove(&mut self, block_addr: u64) -> Option<Line> {
+        let set_index = self.set_index(block_addr);
+        self.sets[set_index]
+            .invalidate(set_index, block_addr, self.policy.as_mut())
+            .or_else(|| self.victim.as_mut()?.take(block_addr))
+    }
+
+    // Counts a line leaving the cache that was prefetched but never used.
+    fn count_unused(&mut self, line: &Line) {
+        if line.prefetched {
+            self.stats.useless_prefetches += 1;
+        }
+    }
+
+    /// Removes the block containing the address because another CPU stored to it.
+    /// Returns whether the block was dirty, or None if it wasn't cached.
+    pub fn invalidate_coherent(&mut self, address: u64) -> Option<bool> {
+        let dirty = self.invalidate(address)?;
+        self.stats.coherence_invalidations += 1;
+        Some(dirty)
+    }
+
+    /// Cleans the block containing the address because another CPU loaded it, leaving
+    /// it shared. Returns whether the block was dirty, or None if it wasn't cached.
+    pub fn downgrade_coherent(&mut self, address: u64) -> Option<bool> {
+        let line = self.line_mut(address / (self.block_size as u64))?;
+        let dirty = std::mem::take(&mut line.dirty);
+        if dirty {
+            self.stats.coherence_downgrades += 1;
+        }
+        Some(dirty)
+    }
+
+    /// Returns true if the block containing the address is cached and dirty.
+    pub fn is_dirty(&self, address: u64) -> bool {
+        let block_addr = address / (self.block_size as u64);
+        let set_index = self.set_index(block_addr);
+        let set = &self.sets[set_index];
+        let line = match set.find(block_addr) {
+            Some(pos) => set.lines[pos].as_ref(),
+            None => self
+                .victim
+                .as_ref()
+                .and_then(|victim| victim.lines.iter().find(|line| line.tag == block_addr)),
+        };
+        line.is_some_and(|line| line.dirty)
+    }
+
+    /// Marks the block containing the address dirty without counting an access.
+    /// Returns false if the block isn't cached.
+    pub fn mark_dirty(&mut self, address: u64) -> bool {
+        match self.line_mut(address / (self.block_size as u64)) {
+            Some(line) => {
+                line.dirty = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The cached line of the block, in its set or the victim cache.
+    fn line_mut(&mut self, block_addr: u64) -> Option<&mut Line> {
+        let set_index = self.set_index(block_addr);
+        let set = &mut self.sets[set_index];
+        match set.find(block_addr) {
+            Some(pos) => set.lines[pos].as_mut(),
+            None => self
+                .victim
+                .as_mut()
+                .and_then(|victim| victim.find_mut(block_addr)),
+        }
+    }
+
+    pub fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+
+    /// Index of the set the address maps to.
+    pub fn set_of(&self, address: u64) -> usize {
+        self.set_index(address / (self.block_size as u64))
+    }
+
+    /// Contents of the set the address maps to, one entry per way.
+    pub fn dump_set(&self, address: u64) -> Vec<CacheLineInfo> {
+        let set_index = self.set_of(address);
+        let recency = self.policy.recency(set_index);
+        self.sets[set_index]
+            .lines
+            .iter()
+            .enumerate()
+            .map(|(way, line)| CacheLineInfo {
+                way,
+                tag: line.map_or(0, |line| line.tag),
+                valid: line.is_some(),
+                dirty: line.is_some_and(|line| line.dirty),
+                lru_position: recency
+                    .as_ref()
+                    .filter(|_| line.is_some())
+                    .and_then(|order| order.iter().position(|&w| w == way)),
+            })
+            .collect()
+    }
+
+    /// Number of cached lines filled by each CPU, indexed by CPU.
+    pub fn occupancy(&self) -> Vec<usize> {
+        let mut lines = vec![];
+        let cached = self.sets.iter().flat_map(|set| set.lines.iter().flatten());
+        let buffered = self.victim.iter().flat_map(|victim| victim.lines.iter());
+        for line in cached.chain(buffered) {
+            if lines.len() <= line.owner {
+                lines.resize(line.owner + 1, 0);
+            }
+            lines[line.owner] += 1;
+        }
+        lines
+    }
+
+    /// Clears the statistics without touching the cached lines, e.g. after a warmup.
+    pub fn reset_stats(&mut self) {
+        self.stats = CacheStats::default();
+        self.set_accesses.fill(0);
+    }
+
+    /// Number of demand accesses to each set, indexed by set.
+    pub fn set_access_histogram(&self) -> Vec<u64> {
+        self.set_accesses.clone()
+    }
+
+    /// How addresses map to sets, e.g. `(address >> 6) % 1024`.
+    pub fn set_index_formula(&self) -> String {
+        let block = format!("address >> {}", self.block_size.trailing_zeros());
+        match self.index_hash {
+            IndexHash::None => format!("({}) % {}", block, self.sets.len()),
+            IndexHash::Xor => format!("xor_fold({}) % {}", block, self.sets.len()),
+        }
+    }
+
+    pub fn miss_rate(&self) -> f64 {
+        self.stats.miss_rate()
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn page_size(&self) -> u64 {
+        self.page_size
+    }
+
+    /// Invalidates all blocks of the page containing the address.
+    pub fn invalidate_page(&mut self, address: u64) -> Invalidated<u64> {
+        self.invalidate_range(address & !(self.page_size - 1), self.page_size)
+    }
+
+    /// Invalidates all blocks overlapping `[start, start + len)`. The range doesn't
+    /// have to be aligned.
+    pub fn invalidate_range(&mut self, start: u64, len: u64) -> Invalidated<u64> {
+        let mut invalidated = Invalidated::default();
+        if len == 0 {
+            return invalidated;
+        }
+        let block_size = self.block_size as u64;
+        let start_block = start / block_size;
+        let end_block = start.saturating_add(len - 1) / block_size;
+        for block_addr in start_block..=end_block {
+            if let Some(line) = self.remove(block_addr) {
+                self.stats.invalidations += 1;
+                self.count_unused(&line);
+                invalidated.lines += 1;
+                if line.dirty {
+                    invalidated.dirty.push(block_addr * block_size);
+                }
+            }
+        }
+        invalidated
+    }
+
+    /// Cleans all blocks overlapping `[start, start + len)`, keeping them cached,
+    /// and returns the addresses of the ones that were dirty.
+    pub fn clean_range(&mut self, start: u64, len: u64) -> Vec<u64> {
+        let mut dirty = vec![];
+        if len == 0 {
+            return dirty;
+        }
+        let block_size = self.block_size as u64;
+        let start_block = start / block_size;
+        let end_block = start.saturating_add(len - 1) / block_size;
+        for block_addr in start_block..=end_block {
+            if let Some(line) = self.line_mut(block_addr)
+                && line.dirty
+            {
+                line.dirty = false;
+                dirty.push(block_addr * block_size);
+            }
+        }
+        dirty
+    }
+}
+
+/// Translation lookaside buffer of 4KB pages, modelled as an LRU cache of page
+/// numbers.
+#[derive(Debug)]
+pub struct Tlb {
+    pages: Cache,
+}
+
+impl Tlb {
+    pub fn new(entries: usize, associativity: usize) -> Result<Self, CacheConfigError> {
+        let page_size = PAGE_SIZE as usize;
+        let pages = Cache::try_new(
+            entries * page_size,
+            page_size,
+            associativity,
+            PolicyKind::Lru,
+        )?;
+        Ok(Tlb { pages })
+    }
+
+    /// Looks up the page containing the address and caches its translation.
+    /// Returns true on a TLB hit.
+    pub fn translate(&mut self, address: u64) -> bool {
+        self.pages.access(address, false).hit
+    }
+
+    pub fn stats(&self) -> &CacheStats {
+        &self.pages.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.pages.reset_stats();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HierarchyResult {
+    L1Hit,
+    L2Hit,
+    L3Hit,
+    Miss,
+}
+
+/// Outcome of an access to a cache hierarchy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HierarchyAccess {
+    pub level: HierarchyResult,
+    // dirty blocks that have to be written back to memory
+    pub writebacks: Vec<u64>,
+    // blocks prefetched from memory on behalf of this access
+    pub prefetches: Vec<u64>,
+}
+
+// Inclusive two-level private hierarchy of a CPU. Blocks evicted from the L2 are
+// back-invalidated in the L1, so the L1 never holds a block the L2 doesn't.
+#[derive(Debug)]
+pub struct PrivateCaches {
+    l1: Option<Cache>,
+    l2: Cache,
+}
+
+impl PrivateCaches {
+    pub fn new(l1: Option<Cache>, l2: Cache) -> Self {
+        PrivateCaches { l1, l2 }
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.l2.block_size
+    }
+
+    pub fn l1(&self) -> Option<&Cache> {
+        self.l1.as_ref()
+    }
+
+    pub fn l2(&self) -> &Cache {
+        &self.l2
+    }
+
+    pub fn reset_stats(&mut self) {
+        if let Some(l1) = self.l1.as_mut() {
+            l1.reset_stats();
+        }
+        self.l2.reset_stats();
+    }
+
+    /// Probes the L1 first and only forwards misses to the L2, whose prefetcher may
+    /// bring in further blocks.
+    pub fn access(&mut self, address: u64, store: bool) -> HierarchyAccess {
+        let mut writebacks = vec![];
+        if let Some(l1) = self.l1.as_mut() {
+            let l1_result = l1.access(address, store);
+            if l1_result.hit {
+                return HierarchyAccess {
+                    level: HierarchyResult::L1Hit,
+                    writebacks,
+                    prefetches: vec![],
+                };
+            }
+            // the L1 victim is still held by the L2, which now owns the dirty data
+            if let Some(victim) = l1_result.writeback
+                && !self.l2.mark_dirty(victim)
+            {
+                writebacks.push(victim);
+            }
+        }
+        // an L1 holding the block keeps track of its dirty state
+        let l1_holds = self.l1.as_ref().is_some_and(|l1| l1.contains(address));
+        let l2_result = self.l2.access(address, store && !l1_holds);
+        self.l2_evicted(&l2_result, &mut writebacks);
+        let mut prefetches = vec![];
+        for target in self.l2.prefetch_targets(address, l2_result.hit) {
+            if let Some(result) = self.l2.prefetch(target) {
+                self.l2_evicted(&result, &mut writebacks);
+                prefetches.push(target);
+            }
+        }
+        let level = if l2_result.hit {
+            HierarchyResult::L2Hit
+        } else {
+            HierarchyResult::Miss
+        };
+        HierarchyAccess {
+            level,
+            writebacks,
+            prefetches,
+        }
+    }
+
+    // Back-invalidates a block evicted from the L2 in the L1 and records its
+    // writeback if either level held it dirty.
+    fn l2_evicted(&mut self, result: &AccessResult, writebacks: &mut Vec<u64>) {
+        let Some(evicted) = result.evicted else {
+            return;
+        };
+        let l1_dirty = self
+            .l1
+            .as_mut()
+            .and_then(|l1| l1.invalidate(evicted))
+            .unwrap_or(false);
+        if l1_dirty || result.writeback.is_some() {
+            writebacks.push(evicted);
+        }
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        self.l2.contains(address)
+    }
+
+    /// Removes the block from both levels because another CPU stored to it.
+    /// Returns true if either level held it dirty.
+    pub fn invalidate_coherent(&mut self, address: u64) -> bool {
+        let l1_dirty = self
+            .l1
+            .as_mut()
+            .and_then(|l1| l1.invalidate_coherent(address))
+            .unwrap_or(false);
+        let l2_dirty = self.l2.invalidate_coherent(address).unwrap_or(false);
+        l1_dirty || l2_dirty
+    }
+
+    /// Cleans the block in both levels because another CPU loaded it. Returns true if
+    /// either level held it dirty.
+    pub fn downgrade_coherent(&mut self, address: u64) -> bool {
+        let l1_dirty = self
+            .l1
+            .as_mut()
+            .and_then(|l1| l1.downgrade_coherent(address))
+            .unwrap_or(false);
+        let l2_dirty = self.l2.downgrade_coherent(address).unwrap_or(false);
+        l1_dirty || l2_dirty
+    }
+
+    pub fn is_dirty(&self, address: u64) -> bool {
+        self.l1.as_ref().is_some_and(|l1| l1.is_dirty(address)) || self.l2.is_dirty(address)
+    }
+
+    /// Invalidates the range in both levels. A block dirty in both is reported once.
+    pub fn invalidate_range(&mut self, start: u64, len: u64) -> Invalidated<u64> {
+        let mut invalidated = self.l2.invalidate_range(start, len);
+        if let Some(l1) = self.l1.as_mut() {
+            let l1_invalidated = l1.invalidate_range(start, len);
+            invalidated.lines += l1_invalidated.lines;
+            for block in l1_invalidated.dirty {
+                if !invalidated.dirty.contains(&block) {
+                    invalidated.dirty.push(block);
+                }
+            }
+        }
+        invalidated
+    }
+
+    /// Cleans the range in both levels and returns the addresses of dirty blocks.
+    pub fn clean_range(&mut self, start: u64, len: u64) -> Vec<u64> {
+        let mut dirty = self.l2.clean_range(start, len);
+        if let Some(l1) = self.l1.as_mut() {
+            for block in l1.clean_range(start, len) {
+                if !dirty.contains(&block) {
+                    dirty.push(block);
+                }
+            }
+        }
+        dirty
+    }
+}
+
+/// How stores are kept coherent between the private caches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CoherenceMode {
+    // private caches may keep stale copies
+    None,
+    // a store invalidates the block in every other CPU's private caches
+    Invalidate,
+    // MESI: like invalidate, and a load of a block modified by another CPU writes it
+    // back, leaving both copies shared
+    Mesi,
+}
+
+/// State of a block in one CPU's private caches. The states are derived from the
+/// caches, the `CoherenceDirectory` only knows who may hold a block: a dirty copy is
+/// modified, a clean one exclusive unless another CPU holds the block too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MesiState {
+    Modified,
+    Exclusive,
+    Shared,
+    Invalid,
+}
+
+/// Sharers of every block cached privately, so that coherence only probes the
+/// private caches of CPUs that may hold it. Private caches evict silently, so the
+/// sharers of a block are a superset of its holders; probes drop the stale ones.
+#[derive(Debug, Default)]
+pub struct CoherenceDirectory {
+    // block number and a mask with bit i set if CPU i may hold the block
+    sharers: HashMap<u64, u64>,
+}
+
+impl CoherenceDirectory {
+    /// The most CPUs a directory tracks.
+    pub const MAX_CPUS: usize = 64;
+
+    /// CPUs that may hold the block, in ascending order.
+    pub fn sharers(&self, block: u64) -> impl Iterator<Item = usize> + use<> {
+        let mut mask = self.sharers.get(&block).copied().unwrap_or(0);
+        std::iter::from_fn(move || {
+            if mask == 0 {
+                return None;
+            }
+            let cpu = mask.trailing_zeros() as usize;
+            mask &= mask - 1;
+            Some(cpu)
+        })
+    }
+
+    pub fn add_sharer(&mut self, block: u64, cpu: usize) {
+        *self.sharers.entry(block).or_default() |= 1 << cpu;
+    }
+
+    /// Makes `cpus` the only sharers of the block.
+    pub fn set_sharers(&mut self, block: u64, cpus: impl IntoIterator<Item = usize>) {
+        let mask = cpus.into_iter().fold(0, |mask, cpu| mask | 1 << cpu);
+        if mask == 0 {
+            self.sharers.remove(&block);
+        } else {
+            self.sharers.insert(block, mask);
+        }
+    }
+
+    /// Forgets blocks `first..=last`, which no private cache holds anymore.
+    pub fn remove_blocks(&mut self, first: u64, last: u64) {
+        for block in first..=last {
+            self.sharers.remove(&block);
+        }
+    }
+
+    /// Drops `cpu` from the sharers of blocks `first..=last`.
+    pub fn remove_sharer(&mut self, first: u64, last: u64, cpu: usize) {
+        for block in first..=last {
+            if let Some(mask) = self.sharers.get_mut(&block) {
+                *mask &= !(1 << cpu);
+                if *mask == 0 {
+                    self.sharers.remove(&block);
+                }
+            }
+        }
+    }
+
+    /// Number of blocks with sharers.
+    pub fn len(&self) -> usize {
+        self.sharers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sharers.is_empty()
+    }
+}
+
+// Private caches of every CPU, optionally backed by a single shared L3. The L3 is
+// non-inclusive by default: it is filled on private misses and absorbs dirty private
+// victims it still holds, but doesn't back-invalidate the private caches. A shared
+// hierarchy has no private caches and sends every access straight to the L3.
+#[derive(Debug)]
+pub struct CacheHierarchy {
+    cpus: usize,
+    private: Vec<PrivateCaches>,
+    l3: Option<Cache>,
+    inclusive: bool,
+    coherence: CoherenceMode,
+    directory: CoherenceDirectory,
+}
+
+impl CacheHierarchy {
+    pub fn new(private: Vec<PrivateCaches>, l3: Option<Cache>) -> Self {
+        CacheHierarchy {
+            cpus: private.len(),
+            private,
+            l3,
+            inclusive: false,
+            coherence: CoherenceMode::None,
+            directory: CoherenceDirectory::default(),
+        }
+    }
+
+    /// A single cache shared by all CPUs, whose lines are tagged with the CPU that
+    /// filled them.
+    pub fn shared(cpus: usize, llc: Cache) -> Self {
+        CacheHierarchy {
+            cpus,
+            private: vec![],
+            l3: Some(llc),
+            inclusive: false,
+            coherence: CoherenceMode::None,
+            directory: CoherenceDirectory::default(),
+        }
+    }
+
+    /// Panics if coherence is enabled for more than `CoherenceDirectory::MAX_CPUS`.
+    pub fn with_coherence(mut self, coherence: CoherenceMode) -> Self {
+        assert!(
+            coherence == CoherenceMode::None || self.cpus <= CoherenceDirectory::MAX_CPUS,
+            "coherence supports at most {} CPUs",
+            CoherenceDirectory::MAX_CPUS
+        );
+        self.coherence = coherence;
+        self
+    }
+
+    /// Makes the L3 inclusive: blocks it evicts are back-invalidated in every private
+    /// cache, and dirty private copies are written back.
+    pub fn with_inclusive_l3(mut self) -> Self {
+        self.inclusive = true;
+        self
+    }
+
+    pub fn cpus(&self) -> usize {
+        self.cpus
+    }
+
+    pub fn block_size(&self) -> usize {
+        match (self.private.first(), &self.l3) {
+            (Some(private), _) => private.block_size(),
+            (None, Some(l3)) => l3.block_size(),
+            (None, None) => panic!("cache hierarchy without caches"),
+        }
+    }
+
+    pub fn private(&self) -> &[PrivateCaches] {
+        &self.private
+    }
+
+    /// MESI state of the block in the private caches of `cpu`.
+    pub fn mesi_state(&self, cpu: usize, address: u64) -> MesiState {
+        let Some(caches) = self.private.get(cpu) else {
+            return MesiState::Invalid;
+        };
+        if !caches.contains(address) {
+            MesiState::Invalid
+        } else if caches.is_dirty(address) {
+            MesiState::Modified
+        } else if self
+            .private
+            .iter()
+            .enumerate()
+            .any(|(other, caches)| other != cpu && caches.contains(address))
+        {
+            MesiState::Shared
+        } else {
+            MesiState::Exclusive
+        }
+    }
+
+    pub fn l3(&self) -> Option<&Cache> {
+        self.l3.as_ref()
+    }
+
+    /// Sharers of the privately cached blocks, only kept with coherence enabled.
+    pub fn directory(&self) -> &CoherenceDirectory {
+        &self.directory
+    }
+
+    pub fn reset_stats(&mut self) {
+        for private in self.private.iter_mut() {
+            private.reset_stats();
+        }
+        if let Some(l3) = self.l3.as_mut() {
+            l3.reset_stats();
+        }
+    }
+
+    /// Returns where the access hit, the dirty blocks that have to be written back to
+    /// memory and the prefetched blocks that had to be fetched from memory.
+    pub fn access(&mut self, cpu: usize, address: u64, store: bool) -> HierarchyAccess {
+        if self.private.is_empty() {
+            return self.shared_access(cpu, address, store);
+        }
+        let block_size = self.block_size() as u64;
+        let block = address / block_size;
+        let mut remote_dirty = false;
+        let mut downgraded = false;
+        // other CPUs still holding the block after this access
+        let mut holders = vec![];
+        if self.coherence != CoherenceMode::None {
+            for other in self.directory.sharers(block) {
+                let caches = &mut self.private[other];
+                if other == cpu || !caches.contains(address) {
+                    continue;
+                }
+                if store {
+                    remote_dirty |= caches.invalidate_coherent(address);
+                    continue;
+                }
+                if self.coherence == CoherenceMode::Mesi {
+                    downgraded |= caches.downgrade_coherent(address);
+                }
+                holders.push(other);
+            }
+        }
+        let mut access = self.private[cpu].access(address, store);
+        if self.coherence != CoherenceMode::None {
+            holders.push(cpu);
+            self.directory.set_sharers(block, holders);
+            for &target in access.prefetches.iter() {
+                self.directory.add_sharer(target / block_size, cpu);
+            }
+        }
+        // dirty data of other CPUs moves into the storing CPU's line, unless it has none;
+        // data of a downgraded line always reaches memory
+        if (remote_dirty && !self.private[cpu].contains(address)) || downgraded {
+            access.writebacks.push(address - address % block_size);
+        }
+        let Some(l3) = self.l3.as_mut() else {
+            return access;
+        };
+        let mut l3_evicted = vec![];
+        access.writebacks.retain(|&victim| !l3.mark_dirty(victim));
+        if access.level == HierarchyResult::Miss {
+            // the L3 only owns the dirty data if the private caches didn't allocate
+            let private_holds = self.private[cpu].contains(address);
+            let l3_result = l3.access(address, store && !private_holds);
+            access.writebacks.extend(l3_result.writeback);
+            l3_evicted.extend(l3_result.evicted);
+            if l3_result.hit {
+                access.level = HierarchyResult::L3Hit;
+            }
+        }
+        // prefetches the L3 already holds don't reach memory
+        let mut prefetches = vec![];
+        for target in access.prefetches {
+            if let Some(result) = l3.prefetch(target) {
+                access.writebacks.extend(result.writeback);
+                l3_evicted.extend(result.evicted);
+                prefetches.push(target);
+            }
+        }
+        access.prefetches = prefetches;
+        if self.inclusive {
+            for victim in l3_evicted {
+                self.back_invalidate(victim, &mut access.writebacks);
+            }
+        }
+        access
+    }
+
+    // Removes a block the inclusive L3 evicted from every private cache and records
+    // its writeback if a private copy was dirty.
+    fn back_invalidate(&mut self, block_address: u64, writebacks: &mut Vec<u64>) {
+        let mut dirty = false;
+        for caches in self.private.iter_mut() {
+            dirty |= !caches.invalidate_range(block_address, 1).dirty.is_empty();
+        }
+        if !self.directory.is_empty() {
+            let block = block_address / self.block_size() as u64;
+            self.directory.remove_blocks(block, block);
+        }
+        if dirty && !writebacks.contains(&block_address) {
+            writebacks.push(block_address);
+        }
+    }
+
+    fn shared_access(&mut self, cpu: usize, address: u64, store: bool) -> HierarchyAccess {
+        let llc = self.l3.as_mut().expect("shared hierarchy without a cache");
+        let result = llc.access_as(cpu, address, store);
+        let mut writebacks: Vec<u64> = result.writeback.into_iter().collect();
+        let mut prefetches = vec![];
+        for target in llc.prefetch_targets(address, result.hit) {
+            if let Some(result) = llc.prefetch_as(cpu, target) {
+                writebacks.extend(result.writeback);
+                prefetches.push(target);
+            }
+        }
+        let level = if result.hit {
+            HierarchyResult::L3Hit
+        } else {
+            HierarchyResult::Miss
+        };
+        HierarchyAccess {
+            level,
+            writebacks,
+            prefetches,
+        }
+    }
+
+    /// Invalidates the range in every level. The dirty blocks come with the CPU whose
+    /// trace the writeback belongs to; dirty L3 blocks are attributed to `cpu`.
+    pub fn invalidate_range(
+        &mut self,
+        cpu: usize,
+        start: u64,
+        len: u64,
+    ) -> Invalidated<(usize, u64)> {
+        self.invalidate_in(cpu, start, len, true)
+    }
+
+    /// Like `invalidate_range`, but leaves the private caches of the other CPUs
+    /// untouched, even if they hold blocks of the range.
+    pub fn invalidate_range_of(
+        &mut self,
+        cpu: usize,
+        start: u64,
+        len: u64,
+    ) -> Invalidated<(usize, u64)> {
+        self.invalidate_in(cpu, start, len, false)
+    }
+
+    fn invalidate_in(
+        &mut self,
+        cpu: usize,
+        start: u64,
+        len: u64,
+        every_cpu: bool,
+    ) -> Invalidated<(usize, u64)> {
+        let mut invalidated = Invalidated::default();
+        if len > 0 && !self.directory.is_empty() {
+            let block_size = self.block_size() as u64;
+            let (first, last) = (start / block_size, (start + len - 1) / block_size);
+            if every_cpu {
+                self.directory.remove_blocks(first, last);
+            } else {
+                self.directory.remove_sharer(first, last, cpu);
+            }
+        }
+        for (owner, caches) in self.private.iter_mut().enumerate() {
+            if !every_cpu && owner != cpu {
+                continue;
+            }
+            let private = caches.invalidate_range(start, len);
+            invalidated.lines += private.lines;
+            invalidated
+                .dirty
+                .extend(private.dirty.into_iter().map(|a| (owner, a)));
+        }
+        if let Some(l3) = self.l3.as_mut() {
+            let l3_invalidated = l3.invalidate_range(start, len);
+            invalidated.lines += l3_invalidated.lines;
+            for block in l3_invalidated.dirty {
+                if !invalidated.dirty.iter().any(|&(_, a)| a == block) {
+                    invalidated.dirty.push((cpu, block));
+                }
+            }
+        }
+        invalidated
+    }
+
+    /// Cleans the range in every level, leaving the blocks cached. Dirty blocks are
+    /// attributed like in `invalidate_range`.
+    pub fn clean_range(&mut self, cpu: usize, start: u64, len: u64) -> Vec<(usize, u64)> {
+        let mut dirty = vec![];
+        for (owner, caches) in self.private.iter_mut().enumerate() {
+            dirty.extend(
+                caches
+                    .clean_range(start, len)
+                    .into_iter()
+                    .map(|a| (owner, a)),
+            );
+        }
+        if let Some(l3) = self.l3.as_mut() {
+            for block in l3.clean_range(start, len) {
+                if !dirty.iter().any(|&(_, a)| a == block) {
+                    dirty.push((cpu, block));
+                }
+            }
+        }
+        dirty
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Random mix of loads and stores over twice the size of a 16KB cache.
+    fn accesses() -> impl Iterator<Item = (u64, bool)> {
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        (0..20_000).map(move |_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            ((state % (32 * 1024)) & !63, state & 1 == 0)
+        })
+    }
+
+    #[test]
+    fn indexed_sets_match_scanned_sets() {
+        for ways in [4, 16, 32] {
+            let mut scanned = Cache::new(16 * 1024, 64, ways);
+            let mut indexed = Cache::new(16 * 1024, 64, ways).with_indexed_sets();
+            for (address, store) in accesses() {
+                let expected = scanned.access(address, store);
+                let result = indexed.access(address, store);
+                assert_eq!(result.hit, expected.hit);
+                assert_eq!(result.evicted, expected.evicted);
+                assert_eq!(result.writeback, expected.writeback);
+            }
+        }
+    }
+
+    #[test]
+    fn mshrs_merge_misses_to_a_block_being_fetched() {
+        let mut cache = Cache::new(4096, 64, 4).with_mshrs(4, 10);
+        assert_eq!(
+            cache.access_with_mshr(0x1000, false, 0),
+            MissStatus::PrimaryMiss
+        );
+        assert_eq!(
+            cache.access_with_mshr(0x1008, true, 5),
+            MissStatus::SecondaryMiss
+        );
+        // the fill completes at cycle 10
+        assert_eq!(cache.access_with_mshr(0x1010, false, 10), MissStatus::Hit);
+        assert_eq!(cache.stats().secondary_misses, 1);
+    }
+
+    #[test]
+    fn misses_without_a_free_mshr_are_full() {
+        let mut cache = Cache::new(4096, 64, 4).with_mshrs(2, 100);
+        assert_eq!(
+            cache.access_with_mshr(0x0, false, 0),
+            MissStatus::PrimaryMiss
+        );
+        assert_eq!(
+            cache.access_with_mshr(0x40, false, 0),
+            MissStatus::PrimaryMiss
+        );
+        assert_eq!(cache.access_with_mshr(0x80, false, 1), MissStatus::Full);
+        assert!(!cache.contains(0x80));
+        // hits don't need an MSHR
+        assert_eq!(cache.access_with_mshr(0x0, false, 200), MissStatus::Hit);
+        assert_eq!(
+            cache.access_with_mshr(0x80, false, 200),
+            MissStatus::PrimaryMiss
+        );
+        assert_eq!(cache.stats().mshr_full, 1);
+    }
+
+    #[test]
+    fn store_to_a_shared_block_invalidates_the_other_copies() {
+        let private = (0..2)
+            .map(|_| PrivateCaches::new(None, Cache::new(4096, 64, 4)))
+            .collect();
+        let mut caches = CacheHierarchy::new(private, None).with_coherence(CoherenceMode::Mesi);
+        let block = 0x1000 / 64;
+
+        caches.access(0, 0x1000, false);
+        assert_eq!(caches.mesi_state(0, 0x1000), MesiState::Exclusive);
+        caches.access(1, 0x1000, false);
+        assert_eq!(caches.mesi_state(0, 0x1000), MesiState::Shared);
+        assert_eq!(caches.mesi_state(1, 0x1000), MesiState::Shared);
+        assert_eq!(
+            caches.directory().sharers(block).collect::<Vec<_>>(),
+            [0, 1]
+        );
+
+        let access = caches.access(1, 0x1000, true);
+        assert!(access.writebacks.is_empty());
+        assert_eq!(caches.mesi_state(0, 0x1000), MesiState::Invalid);
+        assert_eq!(caches.mesi_state(1, 0x1000), MesiState::Modified);
+        assert_eq!(caches.directory().sharers(block).collect::<Vec<_>>(), [1]);
+        assert_eq!(caches.private()[0].l2().stats().coherence_invalidations, 1);
+
+        // reading the modified block writes it back and leaves both copies shared
+        let access = caches.access(0, 0x1000, false);
+        assert_eq!(access.writebacks, [0x1000]);
+        assert_eq!(caches.mesi_state(0, 0x1000), MesiState::Shared);
+        assert_eq!(caches.mesi_state(1, 0x1000), MesiState::Shared);
+        assert_eq!(caches.private()[1].l2().stats().coherence_downgrades, 1);
+    }
+
+    #[test]
+    fn stats_count_hits_and_misses() {
+        // 8 sets of 2 ways, 0x0, 0x200 and 0x400 all map to set 0
+        let mut cache = Cache::new(1024, 64, 2);
+        let hits: Vec<bool> = [0x0, 0x8, 0x200, 0x0, 0x400, 0x200]
+            .into_iter()
+            .map(|address| cache.access(address, false).hit)
+            .collect();
+        assert_eq!(hits, [false, true, false, true, false, false]);
+        let stats = cache.stats();
+        assert_eq!((stats.accesses, stats.hits, stats.misses), (6, 2, 4));
+        assert_eq!(stats.evictions, 2);
+        assert!((cache.miss_rate() - 4.0 / 6.0).abs() < 1e-12);
+    }
+
+    // Blocks evicted by A, B, a hit on A, then C in one 2-way set.
+    fn evictions_after_a_hit(policy: PolicyKind) -> Vec<u64> {
+        let mut cache = Cache::with_policy(1024, 64, 2, policy);
+        [0x0, 0x200, 0x0, 0x400]
+            .into_iter()
+            .filter_map(|address| cache.access(address, false).evicted)
+            .collect()
+    }
+
+    #[test]
+    fn policies_choose_different_victims() {
+        // LRU keeps the block that was hit, FIFO evicts it since it was filled first
+        assert_eq!(evictions_after_a_hit(PolicyKind::Lru), [0x200]);
+        assert_eq!(evictions_after_a_hit(PolicyKind::Fifo), [0x0]);
+
+        let random = |seed| {
+            let mut cache =
+                Cache::with_policy(16 * 1024, 64, 4, PolicyKind::Random).with_seed(seed);
+            accesses()
+                .map(|(address, store)| cache.access(address, store).evicted)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(random(1), random(1));
+        assert_ne!(random(1), random(2));
+    }
+
+    #[test]
+    fn policy_trait_objects_pick_their_own_victims() {
+        // fill a 4-way set in order, then hit ways 0 and 1
+        let victim = |kind: PolicyKind| {
+            let mut policy = kind.build(1, 4);
+            (0..4).for_each(|way| policy.on_fill(0, way));
+            policy.on_hit(0, 0);
+            policy.on_hit(0, 1);
+            policy.victim(0)
+        };
+        assert_eq!(victim(PolicyKind::Lru), 2);
+        assert_eq!(victim(PolicyKind::Fifo), 0);
+        // the first draw of the default seed
+        assert_eq!(victim(PolicyKind::Random), 1);
+    }
+
+    #[test]
+    fn srrip_keeps_the_hot_blocks_through_a_scan() {
+        let hot_hits_after_scan = |policy| {
+            // a single set of 4 ways
+            let mut cache = Cache::with_policy(256, 64, 4, policy);
+            for address in [0x0, 0x0, 0x40, 0x40] {
+                cache.access(address, false);
+            }
+            for block in 2..6 {
+                cache.access(block * 0x40, false);
+            }
+            [0x0, 0x40]
+                .into_iter()
+                .filter(|&address| cache.access(address, false).hit)
+                .count()
+        };
+        assert_eq!(hot_hits_after_scan(PolicyKind::Srrip), 2);
+        assert_eq!(hot_hits_after_scan(PolicyKind::Lru), 0);
+
+        // an invalidated way loses its promotion and is the next victim
+        let mut srrip = Srrip::new(1, 4);
+        (0..4).for_each(|way| srrip.on_fill(0, way));
+        srrip.on_hit(0, 2);
+        srrip.on_invalidate(0, 2);
+        assert_eq!(srrip.victim(0), 2);
+    }
+
+    #[test]
+    fn invalidate_removes_present_blocks_only() {
+        let mut cache = Cache::new(16 * 1024, 64, 4);
+        cache.access(0x1000, true);
+        cache.access(0x1040, false);
+        assert_eq!(cache.invalidate(0x1008), Some(true));
+        assert_eq!(cache.invalidate(0x1040), Some(false));
+        assert!(!cache.contains(0x1000));
+        assert_eq!(cache.invalidate(0x1000), None);
+        assert_eq!(cache.invalidate(0x2000), None);
+    }
+
+    #[test]
+    fn invalidate_page_spans_every_set_of_the_page() {
+        // 64 sets, so the 64 blocks of a page each sit in their own set
+        let mut cache = Cache::new(16 * 1024, 64, 4);
+        for block in 0..64 {
+            cache.access(0x3000 + block * 64, block % 2 == 0);
+        }
+        cache.access(0x4000, false);
+        let invalidated = cache.invalidate_page(0x3000);
+        assert_eq!(invalidated.lines, 64);
+        assert_eq!(invalidated.dirty.len(), 32);
+        assert!((0..64).all(|block| !cache.contains(0x3000 + block * 64)));
+        assert!(cache.contains(0x4000));
+        assert_eq!(cache.stats().invalidations, 64);
+    }
+
+    #[test]
+    fn touched_line_survives_filling_a_full_set() {
+        // a single set of 4 ways
+        let mut cache = Cache::new(256, 64, 4);
+        for block in 0..4 {
+            assert!(!cache.access(block * 0x40, false).hit);
+        }
+        assert!(cache.access(0x0, false).hit);
+        assert_eq!(cache.access(0x100, false).evicted, Some(0x40));
+        assert!(cache.contains(0x0));
+        assert_eq!(cache.access(0x140, false).evicted, Some(0x80));
+        assert!(cache.contains(0x0));
+    }
+
+    #[test]
+    fn invalidate_page_masks_unaligned_addresses() {
+        let mut cache = Cache::new(16 * 1024, 64, 4);
+        for address in [0x5000, 0x5fc0, 0x6000] {
+            cache.access(address, false);
+        }
+        assert_eq!(cache.invalidate_page(0x5123).lines, 2);
+        assert!(!cache.contains(0x5000) && !cache.contains(0x5fc0));
+        assert!(cache.contains(0x6000));
+
+        let mut huge = Cache::new(16 * 1024, 64, 4).with_page_size(2 * 1024 * 1024);
+        for address in [0x20_0000, 0x3f_ffc0, 0x40_0000] {
+            huge.access(address, false);
+        }
+        assert_eq!(huge.invalidate_page(0x20_0123).lines, 2);
+        assert!(huge.contains(0x40_0000));
+    }
+
+    #[test]
+    fn sized_accesses_touch_every_block_they_cover() {
+        let mut cache = Cache::new(16 * 1024, 64, 4);
+        let blocks = |result: &SizedAccessResult| -> Vec<u64> {
+            result.blocks.iter().map(|&(block, _)| block).collect()
+        };
+        // straddling a boundary
+        let result = cache.access_sized(0x1038, 16, false);
+        assert_eq!(blocks(&result), [0x1000, 0x1040]);
+        assert!(!result.hit);
+        // starting exactly at a boundary and ending at the next one
+        let result = cache.access_sized(0x1040, 64, false);
+        assert_eq!(blocks(&result), [0x1040]);
+        assert!(result.hit);
+        // fully inside a block
+        let result = cache.access_sized(0x1008, 8, false);
+        assert_eq!(blocks(&result), [0x1000]);
+        assert!(result.hit);
+        // a hit only if every block hits
+        let result = cache.access_sized(0x1078, 16, false);
+        assert_eq!(blocks(&result), [0x1040, 0x1080]);
+        assert!(!result.hit);
+    }
+
+    #[test]
+    fn next_line_prefetches_are_invalidated_with_their_page() {
+        let l2 = Cache::new(16 * 1024, 64, 4).with_prefetcher(PrefetcherKind::NextLine);
+        let mut caches = PrivateCaches::new(None, l2);
+        let access = caches.access(0x1000, false);
+        assert_eq!(access.level, HierarchyResult::Miss);
+        assert_eq!(access.prefetches, [0x1040]);
+        // hits don't train the next-line prefetcher
+        assert!(caches.access(0x1008, false).prefetches.is_empty());
+
+        // the unused prefetch goes with the page, so its block misses again
+        assert_eq!(caches.invalidate_range(0x1000, PAGE_SIZE).lines, 2);
+        let access = caches.access(0x1040, false);
+        assert_eq!(access.level, HierarchyResult::Miss);
+        assert_eq!(access.prefetches, [0x1080]);
+        assert_eq!(caches.access(0x1080, false).level, HierarchyResult::L2Hit);
+
+        let stats = caches.l2().stats();
+        // prefetch fills aren't demand accesses
+        assert_eq!((stats.accesses, stats.hits, stats.misses), (4, 2, 2));
+        assert_eq!(stats.prefetches, 2);
+        assert_eq!(stats.useless_prefetches, 1);
+        assert_eq!(stats.useful_prefetches, 1);
+    }
+
+    #[test]
+    fn invalidate_range_covers_exactly_the_touched_blocks() {
+        let cached = || {
+            let mut cache = Cache::new(16 * 1024, 64, 4);
+            for block in 0..5 {
+                cache.access(0x1000 + block * 64, false);
+            }
+            cache
+        };
+        let remaining = |cache: &Cache| -> Vec<u64> {
+            (0..5)
+                .map(|block| 0x1000 + block * 64)
+                .filter(|&address| cache.contains(address))
+                .collect()
+        };
+
+        let mut cache = cached();
+        assert_eq!(cache.invalidate_range(0x1048, 16).lines, 1);
+        assert_eq!(remaining(&cache), [0x1000, 0x1080, 0x10c0, 0x1100]);
+
+        // from the middle of the first block to the middle of the third
+        let mut cache = cached();
+        assert_eq!(cache.invalidate_range(0x1020, 0x80).lines, 3);
+        assert_eq!(remaining(&cache), [0x10c0, 0x1100]);
+
+        let mut cache = cached();
+        assert_eq!(cache.invalidate_range(0x1000, 0), Invalidated::default());
+        assert_eq!(remaining(&cache).len(), 5);
+        assert_eq!(cache.stats().invalidations, 0);
+    }
+
+    #[test]
+    fn invalidate_coherence_makes_consumers_miss() {
+        // cpu 0 produces a block that cpu 1 consumes, ten times
+        let consumer_misses = |coherence| {
+            let private = (0..2)
+                .map(|_| PrivateCaches::new(None, Cache::new(4096, 64, 4)))
+                .collect();
+            let mut caches = CacheHierarchy::new(private, None).with_coherence(coherence);
+            for _ in 0..10 {
+                caches.access(0, 0x1000, true);
+                caches.access(1, 0x1000, false);
+            }
+            let stats = *caches.private()[1].l2().stats();
+            (stats.misses, stats.coherence_invalidations)
+        };
+        assert_eq!(consumer_misses(CoherenceMode::None), (1, 0));
+        // every store but the first finds the consumer's copy
+        assert_eq!(consumer_misses(CoherenceMode::Invalidate), (10, 9));
+    }
+
+    #[test]
+    fn victim_cache_stops_ping_pong_misses() {
+        // direct mapped, 0x0 and 0x400 share set 0
+        let ping_pong = |cache: &mut Cache| {
+            (0..10)
+                .filter(|i| {
+                    !cache
+                        .access(if i % 2 == 0 { 0x0 } else { 0x400 }, false)
+                        .hit
+                })
+                .count()
+        };
+        assert_eq!(ping_pong(&mut Cache::new(1024, 64, 1)), 10);
+
+        let mut cache = Cache::new(1024, 64, 1).with_victim_entries(4);
+        assert_eq!(ping_pong(&mut cache), 2);
+        assert_eq!(cache.stats().victim_hits, 8);
+        // rowclone invalidation reaches the victim cache too
+        assert_eq!(cache.invalidate_range(0x0, 0x800).lines, 2);
+        assert!(!cache.contains(0x0) && !cache.contains(0x400));
+    }
+
+    #[test]
+    fn fully_associative_caches_evict_the_least_recently_used_line() {
+        let mut cache = Cache::new_fully_associative(256, 64);
+        assert_eq!(cache.set_of(0x1234_5000), 0);
+        for address in [0x0, 0x1000, 0x2000, 0x3000] {
+            assert!(!cache.access(address, false).hit);
+        }
+        assert!(cache.access(0x0, false).hit);
+        assert_eq!(cache.access(0x4000, false).evicted, Some(0x1000));
+        assert_eq!(cache.access(0x5000, false).evicted, Some(0x2000));
+
+        // large enough to use the tag index
+        let mut large = Cache::new_fully_associative(64 * 1024, 64);
+        for block in 0..1024 {
+            large.access(block * 0x1000, false);
+        }
+        assert!(large.access(0x0, false).hit);
+        assert_eq!(large.access(1024 * 0x1000, false).evicted, Some(0x1000));
+    }
+
+    #[test]
+    fn impossible_geometries_are_errors() {
+        let error = |size, block_size, associativity| {
+            Cache::try_new(size, block_size, associativity, PolicyKind::Lru).unwrap_err()
+        };
+        // more ways than lines would leave no set
+        assert_eq!(
+            error(256, 64, 8),
+            CacheConfigError::LinesNotMultipleOfWays {
+                lines: 4,
+                associativity: 8
+            }
+        );
+        assert_eq!(error(256, 64, 0), CacheConfigError::ZeroParameter);
+        assert_eq!(
+            error(256, 48, 1),
+            CacheConfigError::BlockSizeNotPowerOfTwo { block_size: 48 }
+        );
+        assert_eq!(
+            error(100, 64, 1),
+            CacheConfigError::SizeNotMultipleOfBlock {
+                size: 100,
+                block_size: 64
+            }
+        );
+    }
+
+    #[test]
+    fn xor_indexing_spreads_a_power_of_two_stride() {
+        // 4 sets, so a stride of 4 blocks maps every access to set 0 under modulo
+        let run = |index_hash| {
+            let mut cache = Cache::new(1024, 64, 4).with_index_hash(index_hash);
+            let stream = (0..8).map(|i| i * 4 * 64);
+            stream.clone().for_each(|address| {
+                cache.access(address, false);
+            });
+            let misses = stream
+                .clone()
+                .filter(|&address| !cache.access(address, false).hit)
+                .count();
+            // invalidations look in the same set the accesses filled
+            let invalidated = stream
+                .clone()
+                .filter(|&address| cache.invalidate(address).is_some())
+                .count();
+            stream.clone().for_each(|address| {
+                cache.access(address, false);
+            });
+            cache.invalidate_page(0);
+            assert!(stream.clone().all(|address| !cache.contains(address)));
+            (misses, invalidated)
+        };
+        assert_eq!(run(IndexHash::None), (8, 4));
+        assert_eq!(run(IndexHash::Xor), (0, 8));
+    }
+
+    #[test]
+    fn random_victims_follow_the_seed() {
+        let cache = |seed| Cache::with_policy(256, 64, 4, PolicyKind::Random).with_seed(seed);
+        let script = (0..32).map(|block| block * 64);
+        let victims = |cache: &mut Cache| {
+            script
+                .clone()
+                .map(|address| cache.access(address, false).evicted)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(victims(&mut cache(7)), victims(&mut cache(7)));
+        assert_ne!(victims(&mut cache(7)), victims(&mut cache(8)));
+
+        // each cache draws from its own generator, so interleaving two of them
+        // leaves their victims unchanged
+        let (mut first, mut second) = (cache(7), cache(7));
+        let interleaved = script
+            .clone()
+            .map(|address| {
+                let evicted = first.access(address, false).evicted;
+                second.access(address + 0x1000, false);
+                evicted
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(interleaved, victims(&mut cache(7)));
+    }
+
+    #[test]
+    fn geometries_without_a_set_are_rejected() {
+        assert!(Cache::check_geometry(256 * 1024, 64, 8).is_ok());
+        for (size, block_size, associativity) in [(0, 64, 4), (1024, 0, 4), (1024, 64, 0)] {
+            assert_eq!(
+                Cache::check_geometry(size, block_size, associativity),
+                Err(CacheConfigError::ZeroParameter)
+            );
+        }
+        // a block larger than the cache would leave no set to index
+        let error = Cache::check_geometry(64, 128, 1).unwrap_err();
+        assert_eq!(
+            error,
+            CacheConfigError::SizeNotMultipleOfBlock {
+                size: 64,
+                block_size: 128
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "cache size 64 is not divisible by block size 128"
+        );
+        assert_eq!(
+            Cache::check_geometry(1024, 64, 32).unwrap_err().to_string(),
+            "16 cache lines cannot be split into sets of 32 ways"
+        );
+    }
+
+    #[test]
+    fn dump_set_shows_the_fills_of_a_set() {
+        // 2 sets of 2 ways: blocks 0 and 2 share set 0
+        let mut cache = Cache::new(256, 64, 2);
+        cache.access(0x0, true);
+        cache.access(0x80, false);
+        let dump: Vec<_> = cache
+            .dump_set(0x0)
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+        assert_eq!(
+            dump,
+            [
+                "way  0: tag 0x000000000000 dirty lru 1",
+                "way  1: tag 0x000000000002 clean lru 0",
+            ]
+        );
+        assert!(cache.dump_set(0x40).iter().all(|line| !line.valid));
+
+        cache.access(0x0, false);
+        let lines = cache.dump_set(0x80);
+        assert_eq!(lines[0].lru_position, Some(0));
+        assert_eq!(lines[1].lru_position, Some(1));
+        // the least recently used line makes room for block 4
+        cache.access(0x100, false);
+        let lines = cache.dump_set(0x0);
+        assert_eq!((lines[1].tag, lines[1].dirty), (4, false));
+        assert_eq!(lines[1].lru_position, Some(0));
+    }
+
+    #[test]
+    fn prefetches_turn_sequential_misses_into_hits() {
+        let misses = |prefetcher| {
+            let mut cache = Cache::new(16 * 1024, 64, 4).with_prefetcher(prefetcher);
+            let missed: Vec<_> = (0..8)
+                .filter(|block| {
+                    let hit = cache.access(block * 64, false).hit;
+                    for target in cache.prefetch_targets(block * 64, hit) {
+                        cache.prefetch(target);
+                    }
+                    !hit
+                })
+                .collect();
+            // prefetch fills aren't demand accesses
+            assert_eq!(cache.stats().accesses, 8);
+            assert_eq!(cache.stats().misses, missed.len() as u64);
+            missed
+        };
+        assert_eq!(misses(PrefetcherKind::None), [0, 1, 2, 3, 4, 5, 6, 7]);
+        // only misses train the next-line prefetcher, so every other block misses
+        assert_eq!(misses(PrefetcherKind::NextLine), [0, 2, 4, 6]);
+        assert_eq!(misses(PrefetcherKind::Stride), [0, 1, 2]);
+    }
+
+    #[test]
+    fn stride_prefetches_stay_within_the_trained_page() {
+        let mut stride = PrefetcherKind::Stride.build(64, 2).unwrap();
+        assert!(stride.on_access(0x1000, false).is_empty());
+        assert!(stride.on_access(0x1100, false).is_empty());
+        assert_eq!(stride.on_access(0x1200, true), [0x1300, 0x1400]);
+
+        let mut stride = PrefetcherKind::Stride.build(64, 2).unwrap();
+        stride.on_access(0x1c00, false);
+        stride.on_access(0x1d00, false);
+        // 0x2000 would cross into the next page
+        assert_eq!(stride.on_access(0x1e00, false), [0x1f00]);
+    }
+
+    #[test]
+    fn l1_hits_never_reach_the_l2() {
+        let l1 = Cache::new(1024, 64, 2);
+        let mut caches = PrivateCaches::new(Some(l1), Cache::new(16 * 1024, 64, 4));
+        // a miss fills both levels
+        assert_eq!(caches.access(0x1000, false).level, HierarchyResult::Miss);
+        assert!(caches.l1().unwrap().contains(0x1000));
+        assert!(caches.l2().contains(0x1000));
+        assert_eq!(caches.access(0x1008, true).level, HierarchyResult::L1Hit);
+        assert_eq!(caches.access(0x1010, false).level, HierarchyResult::L1Hit);
+        let l2 = caches.l2().stats();
+        assert_eq!((l2.accesses, l2.misses), (1, 1));
+        // the store hit only dirtied the L1 copy
+        assert!(!caches.l2().is_dirty(0x1000));
+        assert!(caches.is_dirty(0x1000));
+    }
+
+    #[test]
+    fn tree_plru_victims_follow_the_tree() {
+        let mut plru = PolicyKind::TreePlru.build(1, 4);
+        // the last fill of each half points the root and that half's node to the left
+        (0..4).for_each(|way| plru.on_fill(0, way));
+        assert_eq!(plru.victim(0), 0);
+        // the root now points right and the right node still to way 2
+        plru.on_hit(0, 0);
+        assert_eq!(plru.victim(0), 2);
+        plru.on_hit(0, 2);
+        assert_eq!(plru.victim(0), 1);
+        // an invalidated way is pointed at
+        plru.on_invalidate(0, 3);
+        assert_eq!(plru.victim(0), 3);
+        // ways beyond a non power of two associativity are never chosen
+        let mut plru = PolicyKind::TreePlru.build(1, 3);
+        (0..3).for_each(|way| plru.on_fill(0, way));
+        plru.on_hit(0, 0);
+        assert_eq!(plru.victim(0), 2);
+    }
+
+    #[test]
+    fn tree_plru_approximates_lru() {
+        // with two ways the tree is a single bit and exactly LRU
+        assert_eq!(evictions_after_a_hit(PolicyKind::TreePlru), [0x200]);
+        let hits = |policy, ways| {
+            let mut cache = Cache::with_policy(16 * 1024, 64, ways, policy);
+            accesses()
+                .filter(|&(address, store)| cache.access(address, store).hit)
+                .count()
+        };
+        assert_eq!(hits(PolicyKind::TreePlru, 2), hits(PolicyKind::Lru, 2));
+        // with more ways the tree only remembers the most recent half at each level:
+        // after fills of 0..4 and a hit on 0, LRU evicts 1 and the tree 2
+        let victim_after_hit = |kind: PolicyKind| {
+            let mut policy = kind.build(1, 4);
+            (0..4).for_each(|way| policy.on_fill(0, way));
+            policy.on_hit(0, 0);
+            policy.victim(0)
+        };
+        assert_eq!(victim_after_hit(PolicyKind::Lru), 1);
+        assert_eq!(victim_after_hit(PolicyKind::TreePlru), 2);
+        // but over a random stream it stays within 2% of LRU's hits
+        let (plru, lru) = (hits(PolicyKind::TreePlru, 16), hits(PolicyKind::Lru, 16));
+        assert!(plru.abs_diff(lru) * 50 < lru, "{} vs {} hits", plru, lru);
+    }
+
+    #[test]
+    fn store_misses_only_fill_under_write_allocate() {
+        let store_then_load = |allocation| {
+            let mut cache = Cache::new(16 * 1024, 64, 4).with_allocation(allocation);
+            let store = cache.access(0x1000, true);
+            assert!(!store.hit);
+            let filled = cache.contains(0x1000);
+            (filled, cache.access(0x1000, false).hit)
+        };
+        assert_eq!(
+            store_then_load(AllocationPolicy::WriteAllocate),
+            (true, true)
+        );
+        assert_eq!(
+            store_then_load(AllocationPolicy::NoWriteAllocate),
+            (false, false)
+        );
+
+        // a store hit still dirties the cached line
+        let mut cache =
+            Cache::new(16 * 1024, 64, 4).with_allocation(AllocationPolicy::NoWriteAllocate);
+        cache.access(0x1000, false);
+        assert!(cache.access(0x1000, true).hit);
+        assert!(cache.is_dirty(0x1000));
+    }
+
+    #[test]
+    fn partitioned_cpus_only_evict_their_own_ways() {
+        // one set of 8 ways, 2 for CPU 0 and 6 for CPU 1
+        let mut cache = Cache::new(512, 64, 8).with_way_partition(&[2, 6]);
+        for block in 0..2 {
+            cache.access_as(0, block * 64, false);
+        }
+        for block in 100..120 {
+            cache.access_as(1, block * 64, false);
+        }
+        assert_eq!(cache.occupancy(), vec![2, 6]);
+        for block in 0..2 {
+            assert!(cache.access_as(0, block * 64, false).hit);
+        }
+        assert_eq!(cache.stats().cross_cpu_evictions, 0);
+    }
+
+    #[test]
+    fn misses_are_classified_by_what_a_fully_associative_cache_would_hold() {
+        // 2 sets of 2 ways, even blocks map to set 0
+        let mut cache = Cache::new(256, 64, 2).with_miss_classification();
+        let mut kind_of = |block: u64| {
+            let before = *cache.stats();
+            assert!(!cache.access(block * 64, false).hit);
+            let after = cache.stats();
+            (
+                after.compulsory_misses - before.compulsory_misses,
+                after.capacity_misses - before.capacity_misses,
+                after.conflict_misses - before.conflict_misses,
+            )
+        };
+        for block in [0, 2, 4] {
+            assert_eq!(kind_of(block), (1, 0, 0));
+        }
+        // block 4 evicted block 0 from set 0, though 4 lines would hold all three
+        assert_eq!(kind_of(0), (0, 0, 1));
+        for block in [1, 3] {
+            assert_eq!(kind_of(block), (1, 0, 0));
+        }
+        // five distinct blocks don't fit 4 lines, block 2 is the least recently used
+        assert_eq!(kind_of(2), (0, 1, 0));
+    }
+
+    #[test]
+    fn cpus_sharing_the_llc_share_its_lines() {
+        let mut caches = CacheHierarchy::shared(2, Cache::new(4096, 64, 4));
+        assert_eq!(caches.access(0, 0x1000, false).level, HierarchyResult::Miss);
+        assert_eq!(
+            caches.access(1, 0x1008, false).level,
+            HierarchyResult::L3Hit
+        );
+        let llc = caches.l3().unwrap();
+        assert_eq!(llc.stats().misses, 1);
+        // the line belongs to the CPU that filled it
+        assert_eq!(llc.occupancy(), vec![1]);
+    }
+
+    // CPUs with a private 2-way, 2-set L2 in front of a shared L3 of the given size.
+    fn with_l3(cpus: usize, l3_size: usize) -> CacheHierarchy {
+        let private = (0..cpus)
+            .map(|_| PrivateCaches::new(None, Cache::new(256, 64, 2)))
+            .collect();
+        CacheHierarchy::new(private, Some(Cache::new(l3_size, 64, 4)))
+    }
+
+    #[test]
+    fn l2_misses_hit_in_the_l3() {
+        let mut caches = with_l3(2, 4096);
+        assert_eq!(caches.access(0, 0x0, false).level, HierarchyResult::Miss);
+        // CPU 1 misses in its own L2, but CPU 0's miss filled the L3
+        assert_eq!(caches.access(1, 0x0, false).level, HierarchyResult::L3Hit);
+        // blocks 0, 2 and 4 share a set of CPU 0's L2, which evicts block 0
+        caches.access(0, 0x80, false);
+        caches.access(0, 0x100, false);
+        assert_eq!(caches.access(0, 0x0, false).level, HierarchyResult::L3Hit);
+        let l3 = caches.l3().unwrap();
+        assert_eq!((l3.stats().hits, l3.stats().misses), (2, 3));
+    }
+
+    #[test]
+    fn inclusive_l3_evictions_back_invalidate_the_private_caches() {
+        // a single set of 4 ways, smaller than the two L2s together
+        let mut caches = with_l3(2, 256).with_inclusive_l3();
+        caches.access(0, 0x0, true);
+        for block in 1..4 {
+            caches.access(1, block * 64, false);
+        }
+        // the fifth block evicts block 0 from the L3 and from CPU 0's L2, and the
+        // dirty private copy is written back
+        let access = caches.access(1, 0x100, false);
+        assert_eq!(access.writebacks, vec![0x0]);
+        assert!(!caches.private()[0].contains(0x0));
+        assert_eq!(caches.access(0, 0x0, false).level, HierarchyResult::Miss);
+
+        // a non-inclusive L3 leaves the private copy alone
+        let mut caches = with_l3(2, 256);
+        caches.access(0, 0x0, true);
+        for block in 1..5 {
+            caches.access(1, block * 64, false);
+        }
+        assert_eq!(caches.access(0, 0x0, false).level, HierarchyResult::L2Hit);
+    }
+
+    #[test]
+    fn only_dirty_evictions_are_written_back() {
+        // one set of 2 ways
+        let mut cache = Cache::new(128, 64, 2);
+        cache.access(0x0, true);
+        cache.access(0x40, false);
+        let dirty = cache.access(0x80, false);
+        assert_eq!((dirty.evicted, dirty.writeback), (Some(0x0), Some(0x0)));
+        let clean = cache.access(0xc0, false);
+        assert_eq!((clean.evicted, clean.writeback), (Some(0x40), None));
+    }
+
+    #[test]
+    fn adjacent_prefetches_fill_the_buddy_block() {
+        let mut cache = Cache::new(16 * 1024, 64, 4).with_prefetcher(PrefetcherKind::Adjacent);
+        // block 5's buddy is block 4, the other half of the 128 byte pair
+        let hit = cache.access(5 * 64, false).hit;
+        assert_eq!(cache.prefetch_targets(5 * 64, hit), [4 * 64]);
+        cache.prefetch(4 * 64);
+        assert!(cache.access(4 * 64, false).hit);
+        assert_eq!(cache.stats().buddy_prefetches, 1);
+        assert_eq!(cache.stats().useful_prefetches, 1);
+    }
+
+    #[test]
+    fn set_histogram_counts_demand_accesses_per_set() {
+        // 4 direct mapped sets
+        let mut cache = Cache::new(256, 64, 1);
+        for block in [0, 1, 5, 9, 2] {
+            cache.access(block * 64, false);
+        }
+        // prefetch fills aren't demand accesses
+        cache.prefetch(3 * 64);
+        assert_eq!(cache.set_access_histogram(), [1, 3, 1, 0]);
+        assert_eq!(cache.set_index_formula(), "(address >> 6) % 4");
+    }
+
+    #[test]
+    fn fully_associative_geometries_are_checked_before_dividing() {
+        let error =
+            |size, block_size| Cache::try_new_fully_associative(size, block_size).unwrap_err();
+        assert_eq!(error(256, 0), CacheConfigError::ZeroParameter);
+        assert_eq!(error(0, 64), CacheConfigError::ZeroParameter);
+        assert_eq!(
+            error(32, 64),
+            CacheConfigError::SizeNotMultipleOfBlock {
+                size: 32,
+                block_size: 64
+            }
+        );
+        let cache = Cache::try_new_fully_associative(256, 64).unwrap();
+        assert_eq!(cache.set_index_formula(), "(address >> 6) % 1");
+    }
+}
@@ -1,3 +1,7 @@
+pub mod cache;
 pub mod log_parser;
 pub mod lookahead_iter;
 pub mod memory_access;
+pub mod progress;
+pub mod trace_filter;
+pub mod trace_format;
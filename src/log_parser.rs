@@ -1,35 +1,59 @@
+use flate2::read::GzDecoder;
 use std::cmp;
 use std::fmt;
 use std::fs::File;
-use std::io::SeekFrom;
-use std::io::{self, BufReader, Read, Seek};
-use std::mem;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::str::FromStr;
 
-#[repr(C)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LogRecord {
     pub logical_clock: u64,
     pub insn_count: u64,
     pub cpu: u8,
     pub store: u8,
     pub size: u8,
+    #[cfg_attr(feature = "serde", serde(with = "crate::memory_access::hex_address"))]
     pub address: u64,
 }
 
+// On-disk layout of a record, all integers little-endian. The offsets match the
+// padded C struct written by the QEMU plugin so existing logs stay readable.
+const LOGICAL_CLOCK_OFFSET: usize = 0;
+const INSN_COUNT_OFFSET: usize = 8;
+const CPU_OFFSET: usize = 16;
+const STORE_OFFSET: usize = 17;
+const SIZE_OFFSET: usize = 18;
+const ADDRESS_OFFSET: usize = 24;
+
+fn read_u64(buffer: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buffer[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
 impl LogRecord {
-    pub const SIZE: usize = mem::size_of::<LogRecord>();
+    pub const SIZE: usize = 8 + 8 + 1 + 1 + 1 + 5 + 8; // 5 bytes of padding before address
 
-    pub fn deserialize(buffer: &mut [u8; Self::SIZE]) -> LogRecord {
-        unsafe { std::ptr::read_unaligned(buffer.as_ptr() as *const _) }
+    pub fn deserialize(buffer: &[u8; Self::SIZE]) -> LogRecord {
+        LogRecord {
+            logical_clock: read_u64(buffer, LOGICAL_CLOCK_OFFSET),
+            insn_count: read_u64(buffer, INSN_COUNT_OFFSET),
+            cpu: buffer[CPU_OFFSET],
+            store: buffer[STORE_OFFSET],
+            size: buffer[SIZE_OFFSET],
+            address: read_u64(buffer, ADDRESS_OFFSET),
+        }
     }
     pub fn serialize(&self, buffer: &mut [u8; Self::SIZE]) {
-        unsafe {
-            std::ptr::copy_nonoverlapping(
-                self as *const LogRecord as *const u8,
-                buffer.as_mut_ptr(),
-                Self::SIZE,
-            );
-        }
+        buffer.fill(0);
+        buffer[LOGICAL_CLOCK_OFFSET..LOGICAL_CLOCK_OFFSET + 8]
+            .copy_from_slice(&self.logical_clock.to_le_bytes());
+        buffer[INSN_COUNT_OFFSET..INSN_COUNT_OFFSET + 8]
+            .copy_from_slice(&self.insn_count.to_le_bytes());
+        buffer[CPU_OFFSET] = self.cpu;
+        buffer[STORE_OFFSET] = self.store;
+        buffer[SIZE_OFFSET] = self.size;
+        buffer[ADDRESS_OFFSET..ADDRESS_OFFSET + 8].copy_from_slice(&self.address.to_le_bytes());
     }
 }
 
@@ -80,7 +104,7 @@ impl Eq for LogRecord {}
 
 impl PartialOrd for LogRecord {
     fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
-        Some(self.logical_clock.cmp(&other.logical_clock))
+        Some(self.cmp(other))
     }
 }
 impl Ord for LogRecord {
@@ -89,31 +113,259 @@ impl Ord for LogRecord {
     }
 }
 
-pub struct LogParser {
-    reader: BufReader<File>,
+// Header written once at the start of logs produced by LogWriter: the magic,
+// then the format version and record size as little-endian u16s. Logs written
+// directly by the QEMU plugin have no header.
+pub const LOG_MAGIC: [u8; 8] = *b"QEMULOG\0";
+pub const LOG_VERSION: u16 = 1;
+pub const LOG_HEADER_SIZE: usize = LOG_MAGIC.len() + 2 + 2;
+
+fn log_header() -> [u8; LOG_HEADER_SIZE] {
+    let mut header = [0u8; LOG_HEADER_SIZE];
+    header[..8].copy_from_slice(&LOG_MAGIC);
+    header[8..10].copy_from_slice(&LOG_VERSION.to_le_bytes());
+    header[10..12].copy_from_slice(&(LogRecord::SIZE as u16).to_le_bytes());
+    header
+}
+
+fn invalid_header(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn check_header(header: &[u8; LOG_HEADER_SIZE]) -> io::Result<()> {
+    if header[..8] != LOG_MAGIC {
+        return Err(invalid_header(format!(
+            "bad log magic {:02x?}, expected {:02x?}",
+            &header[..8],
+            LOG_MAGIC
+        )));
+    }
+    let version = u16::from_le_bytes([header[8], header[9]]);
+    if version != LOG_VERSION {
+        return Err(invalid_header(format!(
+            "unsupported log version {}, expected {}",
+            version, LOG_VERSION
+        )));
+    }
+    let record_size = u16::from_le_bytes([header[10], header[11]]);
+    if record_size as usize != LogRecord::SIZE {
+        return Err(invalid_header(format!(
+            "log records are {} bytes, expected {}",
+            record_size,
+            LogRecord::SIZE
+        )));
+    }
+    Ok(())
+}
+
+pub struct LogParser<R: Read> {
+    reader: BufReader<R>,
     buffer: [u8; LogRecord::SIZE],
+    // offset of the first record, past the header if there is one
+    data_start: u64,
+    // records are CSV lines in LogRecord's Display form instead of binary frames
+    text: bool,
+    line: String,
+    // lines read so far in text mode
+    line_number: u64,
 }
 
-impl LogParser {
-    pub fn new(filename: &str) -> io::Result<Self> {
-        File::open(filename).map(|file| LogParser {
-            reader: BufReader::new(file),
-            buffer: [0u8; mem::size_of::<LogRecord>()],
+// Whether the first line in the buffer parses as a CSV record.
+fn looks_like_text(start: &[u8]) -> bool {
+    let first_line = start.split(|&b| b == b'\n').next().unwrap_or_default();
+    start.first().is_some_and(u8::is_ascii_digit)
+        && std::str::from_utf8(first_line).is_ok_and(|line| line.parse::<LogRecord>().is_ok())
+}
+
+impl<R: Read> LogParser<R> {
+    // Reads a headerless log, as written by the QEMU plugin.
+    pub fn from_reader(reader: R) -> Self {
+        LogParser {
+            reader: BufReader::new(reader),
+            buffer: [0u8; LogRecord::SIZE],
+            data_start: 0,
+            text: false,
+            line: String::new(),
+            line_number: 0,
+        }
+    }
+
+    // Reads CSV records, one per line, e.g. the output of log_merger.
+    pub fn from_text(reader: R) -> Self {
+        LogParser {
+            text: true,
+            ..Self::from_reader(reader)
+        }
+    }
+
+    // Reads a log starting with the LogWriter header, failing if the header is
+    // missing or doesn't match this build's format.
+    pub fn with_header(reader: R) -> io::Result<Self> {
+        let mut parser = Self::from_reader(reader);
+        parser.read_header()?;
+        Ok(parser)
+    }
+
+    fn read_header(&mut self) -> io::Result<()> {
+        let mut header = [0u8; LOG_HEADER_SIZE];
+        self.reader
+            .read_exact(&mut header)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => {
+                    invalid_header("log is shorter than its header".into())
+                }
+                _ => e,
+            })?;
+        check_header(&header)?;
+        self.data_start = LOG_HEADER_SIZE as u64;
+        Ok(())
+    }
+
+    // Validates the LogWriter header if the log starts with its magic and reads a
+    // headerless log otherwise.
+    fn detect_header(mut self) -> io::Result<Self> {
+        if self.reader.fill_buf()?.starts_with(&LOG_MAGIC) {
+            self.read_header()?;
+        }
+        Ok(self)
+    }
+
+    // Picks the format from the first bytes: a log with the LogWriter header, CSV
+    // lines if the first one parses as a record, and a headerless log otherwise.
+    fn detect_format(mut self) -> io::Result<Self> {
+        let start = self.reader.fill_buf()?;
+        if start.starts_with(&LOG_MAGIC) {
+            self.read_header()?;
+        } else if looks_like_text(start) {
+            self.text = true;
+        }
+        Ok(self)
+    }
+
+    fn next_text(&mut self) -> Option<io::Result<LogRecord>> {
+        loop {
+            self.line.clear();
+            let read = self.reader.read_line(&mut self.line);
+            if !matches!(read, Ok(0)) {
+                self.line_number += 1;
+            }
+            match read {
+                Ok(0) => return None,
+                Ok(_) if self.line.trim().is_empty() => continue,
+                Ok(_) => {
+                    return Some(self.line.parse().map_err(|e| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "invalid record on line {} {:?}: {}",
+                                self.line_number,
+                                self.line.trim(),
+                                e
+                            ),
+                        )
+                    }));
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl<R: Read> LogParser<R> {
+    /// Whether records are read as CSV lines. An invalid line only fails its own
+    /// record, reading can go on with the next one.
+    pub fn is_text(&self) -> bool {
+        self.text
+    }
+
+    /// Keeps only records with `lo <= address < hi`: the lower bound is inclusive,
+    /// the upper one exclusive. Read errors are passed through.
+    pub fn filter_range(self, lo: u64, hi: u64) -> impl Iterator<Item = io::Result<LogRecord>> {
+        self.filter(move |record| match record {
+            Ok(record) => (lo..hi).contains(&record.address),
+            Err(_) => true,
         })
     }
+}
+
+impl LogParser<File> {
+    // Reads a binary log, with or without the LogWriter header.
+    pub fn new(filename: &str) -> io::Result<Self> {
+        Self::from_reader(File::open(filename)?).detect_header()
+    }
+}
+
+impl LogParser<GzDecoder<File>> {
+    // Reads a gzip-compressed log; records are framed the same way on the
+    // decompressed stream.
+    pub fn new_gzip(filename: &str) -> io::Result<Self> {
+        File::open(filename).map(|file| Self::from_reader(GzDecoder::new(file)))
+    }
+}
+
+impl LogParser<Box<dyn Read>> {
+    // Picks the gzip decoder for files ending in .gz and accepts logs with or
+    // without a header as well as CSV logs.
+    pub fn open_auto(filename: &str) -> io::Result<Self> {
+        let file = File::open(filename)?;
+        let reader: Box<dyn Read> = if filename.ends_with(".gz") {
+            Box::new(GzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+        Self::from_reader(reader).detect_format()
+    }
+}
+
+impl<R: Read + Seek> LogParser<R> {
     pub fn reset(&mut self) {
         self.reader
-            .seek(SeekFrom::Start(0))
+            .seek(SeekFrom::Start(self.data_start))
             .expect("failed to reset");
     }
 }
 
-impl Iterator for LogParser {
+pub struct LogWriter<W: Write> {
+    writer: BufWriter<W>,
+    buffer: [u8; LogRecord::SIZE],
+}
+
+impl<W: Write> LogWriter<W> {
+    // Writes the header up front so LogParser can validate the log.
+    pub fn from_writer(writer: W) -> io::Result<Self> {
+        let mut writer = BufWriter::new(writer);
+        writer.write_all(&log_header())?;
+        Ok(LogWriter {
+            writer,
+            buffer: [0u8; LogRecord::SIZE],
+        })
+    }
+
+    pub fn write(&mut self, record: &LogRecord) -> io::Result<()> {
+        record.serialize(&mut self.buffer);
+        self.writer.write_all(&self.buffer)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl LogWriter<File> {
+    pub fn new(filename: &str) -> io::Result<Self> {
+        Self::from_writer(File::create(filename)?)
+    }
+}
+
+impl<R: Read> Iterator for LogParser<R> {
     type Item = io::Result<LogRecord>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.text {
+            return self.next_text();
+        }
         match self.reader.read_exact(&mut self.buffer) {
-            Ok(_) => Some(Ok(LogRecord::deserialize(&mut self.buffer))),
+            Ok(_) => Some(Ok(LogRecord::deserialize(&self.buffer))),
             Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
             Err(e) => {
                 eprintln!("error: {}", e);
@@ -122,3 +374,214 @@ impl Iterator for LogParser {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(logical_clock: u64) -> LogRecord {
+        LogRecord {
+            logical_clock,
+            insn_count: 100 + logical_clock,
+            cpu: 1,
+            store: 1,
+            size: 3,
+            address: 0x7fff_0000 + logical_clock * 8,
+        }
+    }
+
+    fn written_log(records: &[LogRecord]) -> Vec<u8> {
+        let mut log = vec![];
+        let mut writer = LogWriter::from_writer(&mut log).unwrap();
+        for record in records {
+            writer.write(record).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+        log
+    }
+
+    fn header_error(log: Vec<u8>) -> String {
+        match LogParser::with_header(log.as_slice()) {
+            Ok(_) => panic!("header accepted"),
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+                e.to_string()
+            }
+        }
+    }
+
+    fn assert_same_fields(a: &LogRecord, b: &LogRecord) {
+        assert_eq!(a.logical_clock, b.logical_clock);
+        assert_eq!(a.insn_count, b.insn_count);
+        assert_eq!(a.cpu, b.cpu);
+        assert_eq!(a.store, b.store);
+        assert_eq!(a.size, b.size);
+        assert_eq!(a.address, b.address);
+    }
+
+    #[test]
+    fn records_round_trip_through_bytes() {
+        let original = record(7);
+        let mut buffer = [0u8; LogRecord::SIZE];
+        original.serialize(&mut buffer);
+        let bytes = buffer.to_vec();
+        let copied: [u8; LogRecord::SIZE] = bytes.try_into().unwrap();
+        assert_same_fields(&LogRecord::deserialize(&copied), &original);
+    }
+
+    #[test]
+    fn records_have_a_little_endian_layout() {
+        let bytes: [u8; LogRecord::SIZE] = [
+            0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, // logical_clock
+            0x18, 0x17, 0x16, 0x15, 0x14, 0x13, 0x12, 0x11, // insn_count
+            0x02, 0x01, 0x03, // cpu, store, size
+            0x00, 0x00, 0x00, 0x00, 0x00, // padding
+            0x34, 0x12, 0x00, 0x00, 0xff, 0x7f, 0x00, 0x00, // address
+        ];
+        let expected = LogRecord {
+            logical_clock: 0x0102_0304_0506_0708,
+            insn_count: 0x1112_1314_1516_1718,
+            cpu: 2,
+            store: 1,
+            size: 3,
+            address: 0x7fff_0000_1234,
+        };
+        assert_same_fields(&LogRecord::deserialize(&bytes), &expected);
+        let mut buffer = [0xaau8; LogRecord::SIZE];
+        expected.serialize(&mut buffer);
+        assert_eq!(buffer, bytes);
+    }
+
+    #[test]
+    fn valid_header_is_skipped() {
+        let log = written_log(&[record(1), record(2)]);
+        assert_eq!(&log[..8], b"QEMULOG\0");
+        assert_eq!(log.len(), LOG_HEADER_SIZE + 2 * LogRecord::SIZE);
+        let records: Vec<LogRecord> = LogParser::with_header(log.as_slice())
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].logical_clock, 2);
+        assert_eq!(records[1].address, record(2).address);
+    }
+
+    #[test]
+    fn bad_magic_is_rejected() {
+        let mut log = written_log(&[record(1)]);
+        log[..8].copy_from_slice(b"NOTALOG\0");
+        assert!(header_error(log).contains("bad log magic"));
+    }
+
+    #[test]
+    fn unsupported_version_is_rejected() {
+        let mut log = written_log(&[record(1)]);
+        log[8..10].copy_from_slice(&(LOG_VERSION + 1).to_le_bytes());
+        assert!(header_error(log).contains("unsupported log version 2"));
+    }
+
+    #[test]
+    fn truncated_header_is_rejected() {
+        assert!(header_error(LOG_MAGIC[..5].to_vec()).contains("shorter than its header"));
+    }
+
+    #[test]
+    fn header_is_optional_when_detected() {
+        let mut headerless = [0u8; LogRecord::SIZE];
+        record(7).serialize(&mut headerless);
+        for log in [headerless.to_vec(), written_log(&[record(7)])] {
+            let records: Vec<LogRecord> = LogParser::from_reader(log.as_slice())
+                .detect_header()
+                .unwrap()
+                .collect::<io::Result<_>>()
+                .unwrap();
+            assert_eq!(records.len(), 1);
+            assert_eq!(records[0].insn_count, 107);
+        }
+    }
+
+    #[test]
+    fn text_and_binary_logs_parse_the_same() {
+        let records: Vec<_> = (0..4).map(record).collect();
+        let text: String = records.iter().map(|r| format!("{}\n", r)).collect();
+        let binary = written_log(&records);
+        let from_text = LogParser::from_reader(text.as_bytes())
+            .detect_format()
+            .unwrap();
+        let from_binary = LogParser::from_reader(&binary[..]).detect_format().unwrap();
+        assert!(from_text.is_text());
+        assert!(!from_binary.is_text());
+        // records only compare their logical clock, their Debug form has every field
+        let expected: Vec<_> = records.iter().map(|r| format!("{:?}", r)).collect();
+        let from_text: Vec<_> = from_text.map(|r| format!("{:?}", r.unwrap())).collect();
+        let from_binary: Vec<_> = from_binary.map(|r| format!("{:?}", r.unwrap())).collect();
+        assert_eq!(from_text, expected);
+        assert_eq!(from_binary, expected);
+    }
+
+    #[test]
+    fn invalid_lines_report_their_line_number() {
+        let log = format!("{}\n\n{}\n", record(1), "1,2,x");
+        let mut parser = LogParser::from_text(log.as_bytes());
+        assert!(parser.next().unwrap().is_ok());
+        let e = parser.next().unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().starts_with("invalid record on line 3 "));
+        assert!(parser.next().is_none());
+    }
+
+    #[test]
+    fn gzip_logs_read_like_plain_ones() {
+        // enough frames to straddle the decoder's and the BufReader's buffers
+        let records: Vec<_> = (0..1000).map(record).collect();
+        let mut raw = vec![];
+        for record in &records {
+            let mut buffer = [0u8; LogRecord::SIZE];
+            record.serialize(&mut buffer);
+            raw.extend_from_slice(&buffer);
+        }
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(&raw).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let parser = LogParser::from_reader(GzDecoder::new(compressed.as_slice()));
+        let parsed: Vec<_> = parser.map(|r| format!("{:?}", r.unwrap())).collect();
+        let expected: Vec<_> = records.iter().map(|r| format!("{:?}", r)).collect();
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn cursor_logs_reset_to_their_first_record() {
+        let records: Vec<_> = (0..3).map(record).collect();
+        let log = io::Cursor::new(written_log(&records));
+        let mut parser = LogParser::with_header(log).unwrap();
+        let clocks = |parser: &mut LogParser<io::Cursor<Vec<u8>>>, n| -> Vec<u64> {
+            parser.take(n).map(|r| r.unwrap().logical_clock).collect()
+        };
+        assert_eq!(clocks(&mut parser, 2), [0, 1]);
+        // back to the first record, past the header
+        parser.reset();
+        assert_eq!(clocks(&mut parser, 5), [0, 1, 2]);
+    }
+
+    #[test]
+    fn filter_range_keeps_the_lower_bound_and_drops_the_upper_one() {
+        // addresses 0x7fff_0000 + 8 * clock
+        let records: Vec<_> = (0..6).map(record).collect();
+        let mut log: String = records.iter().map(|r| format!("{}\n", r)).collect();
+        log.push_str("1,2,x\n");
+        let kept: Vec<_> = LogParser::from_text(log.as_bytes())
+            .filter_range(0x7fff_0008, 0x7fff_0020)
+            .map(|r| r.map(|r| r.logical_clock).map_err(|e| e.kind()))
+            .collect();
+        // the invalid line is passed through for the caller to report
+        assert_eq!(kept, [Ok(1), Ok(2), Ok(3), Err(io::ErrorKind::InvalidData)]);
+
+        let binary = written_log(&records);
+        let parser = LogParser::with_header(binary.as_slice()).unwrap();
+        assert_eq!(parser.filter_range(0x7fff_0028, u64::MAX).count(), 1);
+        let parser = LogParser::with_header(binary.as_slice()).unwrap();
+        assert_eq!(parser.filter_range(0x7fff_0010, 0x7fff_0010).count(), 0);
+    }
+}
@@ -1,9 +1,11 @@
+use std::collections::VecDeque;
+
 pub struct LookaheadIterator<I>
 where
     I: Iterator,
 {
     iter: I,
-    buffer: Vec<I::Item>,
+    buffer: VecDeque<I::Item>,
 }
 
 impl<I> LookaheadIterator<I>
@@ -13,21 +15,43 @@ where
     pub fn new(iter: I) -> Self {
         LookaheadIterator {
             iter,
-            buffer: Vec::new(),
+            buffer: VecDeque::new(),
         }
     }
 
-    /// Ensures that at least `n` items are buffered and returns a slice of them.
-    pub fn peek_n(&mut self, n: usize) -> &[I::Item] {
-        // Keep filling the buffer until we have n items or the iterator is exhausted.
+    // Keep filling the buffer until we have n items or the iterator is exhausted.
+    fn fill(&mut self, n: usize) {
         while self.buffer.len() < n {
             if let Some(item) = self.iter.next() {
-                self.buffer.push(item);
+                self.buffer.push_back(item);
             } else {
                 break;
             }
         }
-        &self.buffer[..]
+    }
+
+    /// Ensures that at least `n` items are buffered and returns a slice of them.
+    pub fn peek_n(&mut self, n: usize) -> &[I::Item] {
+        self.fill(n);
+        self.buffer.make_contiguous()
+    }
+
+    /// Returns the next item without consuming it.
+    pub fn peek(&mut self) -> Option<&I::Item> {
+        self.peek_at(0)
+    }
+
+    /// Returns the item `i` positions ahead without consuming anything.
+    pub fn peek_at(&mut self, i: usize) -> Option<&I::Item> {
+        self.fill(i.saturating_add(1));
+        self.buffer.get(i)
+    }
+
+    /// Advances past up to `n` items and returns how many were skipped.
+    pub fn skip_n(&mut self, n: usize) -> usize {
+        let buffered = n.min(self.buffer.len());
+        self.buffer.drain(..buffered);
+        buffered + self.iter.by_ref().take(n - buffered).count()
     }
 }
 
@@ -38,11 +62,42 @@ where
     type Item = I::Item;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if !self.buffer.is_empty() {
-            // Remove the first buffered item.
-            Some(self.buffer.remove(0))
-        } else {
-            self.iter.next()
-        }
+        // Hand out buffered items first.
+        self.buffer.pop_front().or_else(|| self.iter.next())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peek_does_not_consume() {
+        let mut iter = LookaheadIterator::new(1..=3);
+        assert_eq!(iter.peek(), Some(&1));
+        assert_eq!(iter.peek(), Some(&1));
+        assert_eq!(iter.next(), Some(1));
+        assert_eq!(iter.peek_at(1), Some(&3));
+        assert_eq!(iter.peek_at(2), None);
+        assert_eq!(iter.peek_at(usize::MAX), None);
+        assert_eq!(iter.collect::<Vec<_>>(), [2, 3]);
+    }
+
+    #[test]
+    fn skip_n_drains_the_buffer_first() {
+        let mut iter = LookaheadIterator::new(0..10);
+        assert_eq!(iter.peek_n(3), [0, 1, 2]);
+        // fewer than available, partly buffered
+        assert_eq!(iter.skip_n(4), 4);
+        assert_eq!(iter.next(), Some(4));
+        // exactly what is left
+        assert_eq!(iter.skip_n(5), 5);
+        assert_eq!(iter.next(), None);
+
+        let mut iter = LookaheadIterator::new(0..3);
+        iter.peek();
+        // more than available
+        assert_eq!(iter.skip_n(10), 3);
+        assert_eq!(iter.peek(), None);
     }
 }
@@ -1,44 +1,73 @@
 use std;
 use std::fmt;
+use std::io::{self, Read, Write};
 use std::str::FromStr;
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", rename_all = "lowercase"))]
 pub enum MemoryAccess {
     Regular(MemRecord),
     Rowclone(RowcloneRecord),
 }
 
+/// Access size assumed for records written before sizes were recorded: a 64B block.
+pub const DEFAULT_ACCESS_SIZE: u8 = 6;
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MemRecord {
     pub insn_count: u64,
+    #[cfg_attr(feature = "serde", serde(with = "hex_address"))]
     pub address: u64,
     pub store: bool,
     pub cpu: usize,
+    // log2 of the access size in bytes, as in LogRecord
+    pub size: u8,
+    // store that bypasses the caches; written as a store field of 2
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub non_temporal: bool,
+}
+
+// store field of a regular record, in the text and binary forms
+const LOAD: u8 = 0;
+const STORE: u8 = 1;
+const NON_TEMPORAL_STORE: u8 = 2;
+
+impl MemRecord {
+    fn store_field(&self) -> u8 {
+        match (self.store, self.non_temporal) {
+            (false, _) => LOAD,
+            (true, false) => STORE,
+            (true, true) => NON_TEMPORAL_STORE,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RowcloneRecord {
     pub insn_count: u64,
+    #[cfg_attr(feature = "serde", serde(with = "hex_address"))]
     pub from: u64,
+    #[cfg_attr(feature = "serde", serde(with = "hex_address"))]
     pub to: u64,
     pub cpu: usize,
+    // bytes copied, if known; older traces only hold page-sized copies
+    pub size: Option<u64>,
 }
 
 impl fmt::Display for MemRecord {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if self.store {
-            write!(
-                f,
-                "{},0,1,{},0x{:016x}",
-                self.insn_count, self.cpu, self.address
-            )
-        } else {
-            write!(
-                f,
-                "{},0,0,{},0x{:016x}",
-                self.insn_count, self.cpu, self.address
-            )
-        }
+        write!(
+            f,
+            "{},0,{},{},0x{:016x},{}",
+            self.insn_count,
+            self.store_field(),
+            self.cpu,
+            self.address,
+            self.size
+        )
     }
 }
 
@@ -48,7 +77,20 @@ impl fmt::Display for RowcloneRecord {
             f,
             "{},1,0,{},0x{:016x},0x{:016x}",
             self.insn_count, self.cpu, self.from, self.to
-        )
+        )?;
+        match self.size {
+            Some(size) => write!(f, ",{}", size),
+            None => Ok(()),
+        }
+    }
+}
+
+impl MemoryAccess {
+    pub fn cpu(&self) -> usize {
+        match self {
+            MemoryAccess::Regular(rec) => rec.cpu,
+            MemoryAccess::Rowclone(rec) => rec.cpu,
+        }
     }
 }
 
@@ -61,8 +103,123 @@ impl fmt::Display for MemoryAccess {
     }
 }
 
-fn parse_hex_addr(addr: &str) -> u64 {
-    u64::from_str_radix(addr.trim_start_matches("0x"), 16).expect("Failed to parse hex address")
+// Binary frames start with a tag byte, followed by the fields of the record in
+// little-endian order:
+//   regular:  insn_count u64, cpu u8, store u8, size u8, address u64
+//   rowclone: insn_count u64, cpu u8, from u64, to u64, has_size u8, size u64
+const REGULAR_TAG: u8 = 0;
+const ROWCLONE_TAG: u8 = 1;
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0u8; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn cpu_byte(cpu: usize) -> io::Result<u8> {
+    u8::try_from(cpu).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cpu {} does not fit a binary frame", cpu),
+        )
+    })
+}
+
+impl MemoryAccess {
+    pub const REGULAR_FRAME_SIZE: usize = 1 + 8 + 1 + 1 + 1 + 8;
+    pub const ROWCLONE_FRAME_SIZE: usize = 1 + 8 + 1 + 8 + 8 + 1 + 8;
+
+    pub fn serialize(&self, writer: &mut impl Write) -> io::Result<()> {
+        match self {
+            MemoryAccess::Regular(rec) => {
+                let mut buffer = [0u8; Self::REGULAR_FRAME_SIZE];
+                buffer[0] = REGULAR_TAG;
+                buffer[1..9].copy_from_slice(&rec.insn_count.to_le_bytes());
+                buffer[9] = cpu_byte(rec.cpu)?;
+                buffer[10] = rec.store_field();
+                buffer[11] = rec.size;
+                buffer[12..20].copy_from_slice(&rec.address.to_le_bytes());
+                writer.write_all(&buffer)
+            }
+            MemoryAccess::Rowclone(rec) => {
+                let mut buffer = [0u8; Self::ROWCLONE_FRAME_SIZE];
+                buffer[0] = ROWCLONE_TAG;
+                buffer[1..9].copy_from_slice(&rec.insn_count.to_le_bytes());
+                buffer[9] = cpu_byte(rec.cpu)?;
+                buffer[10..18].copy_from_slice(&rec.from.to_le_bytes());
+                buffer[18..26].copy_from_slice(&rec.to.to_le_bytes());
+                buffer[26] = rec.size.is_some() as u8;
+                buffer[27..35].copy_from_slice(&rec.size.unwrap_or(0).to_le_bytes());
+                writer.write_all(&buffer)
+            }
+        }
+    }
+
+    /// Reads the next binary frame, or `None` at a clean end of input.
+    pub fn deserialize(reader: &mut impl Read) -> io::Result<Option<MemoryAccess>> {
+        let mut tag = [0u8; 1];
+        if reader.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+        let insn_count = read_u64(reader)?;
+        let cpu = read_u8(reader)?.into();
+        match tag[0] {
+            REGULAR_TAG => {
+                let store = read_u8(reader)?;
+                Ok(Some(MemoryAccess::Regular(MemRecord {
+                    insn_count,
+                    cpu,
+                    store: store == STORE || store == NON_TEMPORAL_STORE,
+                    size: read_u8(reader)?,
+                    address: read_u64(reader)?,
+                    non_temporal: store == NON_TEMPORAL_STORE,
+                })))
+            }
+            ROWCLONE_TAG => {
+                let from = read_u64(reader)?;
+                let to = read_u64(reader)?;
+                let has_size = read_u8(reader)? == 1;
+                let size = read_u64(reader)?;
+                Ok(Some(MemoryAccess::Rowclone(RowcloneRecord {
+                    insn_count,
+                    from,
+                    to,
+                    cpu,
+                    size: has_size.then_some(size),
+                })))
+            }
+            tag => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown record tag {}", tag),
+            )),
+        }
+    }
+}
+
+fn parse_hex_addr(field: &str, addr: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    u64::from_str_radix(addr.trim_start_matches("0x"), 16)
+        .map_err(|e| format!("invalid {} address {:?}: {}", field, addr, e).into())
+}
+
+// Addresses are serialized as 0x-prefixed hex strings, which read better in JSON.
+#[cfg(feature = "serde")]
+pub(crate) mod hex_address {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(address: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("0x{:016x}", address))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        super::parse_hex_addr("serialized", &text).map_err(D::Error::custom)
+    }
 }
 
 impl FromStr for MemoryAccess {
@@ -74,19 +231,147 @@ impl FromStr for MemoryAccess {
         }
         let insn_count = parts[0].parse::<u64>()?;
         if parts[1] == "1" {
+            // rowclones written before they carried a CPU lack its column
+            if parts.len() == 5 && parts[3].starts_with("0x") {
+                return Ok(MemoryAccess::Rowclone(RowcloneRecord {
+                    insn_count,
+                    cpu: 0,
+                    from: parse_hex_addr("from", parts[3])?,
+                    to: parse_hex_addr("to", parts[4])?,
+                    size: None,
+                }));
+            }
+            if parts.len() < 6 {
+                return Err("Rowclone record must have six fields".into());
+            }
             Ok(MemoryAccess::Rowclone(RowcloneRecord {
                 insn_count,
                 cpu: parts[3].parse::<usize>()?,
-                from: parse_hex_addr(parts[4]),
-                to: parse_hex_addr(parts[5]),
+                from: parse_hex_addr("from", parts[4])?,
+                to: parse_hex_addr("to", parts[5])?,
+                size: parts.get(6).map(|size| size.parse::<u64>()).transpose()?,
             }))
         } else {
             Ok(MemoryAccess::Regular(MemRecord {
                 insn_count,
-                address: parse_hex_addr(parts[4]),
-                store: parts[2] == "1",
+                address: parse_hex_addr("access", parts[4])?,
+                store: parts[2] == "1" || parts[2] == "2",
                 cpu: parts[3].parse::<usize>()?,
+                size: match parts.get(5) {
+                    Some(size) => size.parse::<u8>()?,
+                    None => DEFAULT_ACCESS_SIZE,
+                },
+                non_temporal: parts[2] == "2",
             }))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(line: &str) -> String {
+        match line.parse::<MemoryAccess>() {
+            Ok(access) => panic!("{:?} parsed as {}", line, access),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn malformed_lines_are_errors() {
+        assert_eq!(parse_error(""), "Record must have at least five fields");
+        assert_eq!(
+            parse_error("10,0,1"),
+            "Record must have at least five fields"
+        );
+        assert_eq!(
+            parse_error("10,1,0,0,0x1000"),
+            "Rowclone record must have six fields"
+        );
+        assert!(parse_error("x,0,0,0,0x1000").contains("invalid digit"));
+        assert!(parse_error("10,0,0,0,0xfoo").starts_with("invalid access address \"0xfoo\""));
+        assert!(parse_error("10,1,0,0,0x1000,zz").starts_with("invalid to address \"zz\""));
+    }
+
+    #[test]
+    fn records_parse_with_and_without_sizes() {
+        let Ok(MemoryAccess::Regular(mem)) = "10,0,2,1,0x1000".parse() else {
+            panic!("not a memory access");
+        };
+        assert_eq!((mem.insn_count, mem.cpu, mem.address), (10, 1, 0x1000));
+        assert!(mem.store && mem.non_temporal);
+        assert_eq!(mem.size, DEFAULT_ACCESS_SIZE);
+        let Ok(MemoryAccess::Rowclone(rc)) = "20,1,0,0,0x1000,0x2000,8192".parse() else {
+            panic!("not a rowclone");
+        };
+        assert_eq!((rc.from, rc.to, rc.size), (0x1000, 0x2000, Some(8192)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn rowclone_json_round_trip() {
+        let access = MemoryAccess::Rowclone(RowcloneRecord {
+            insn_count: 42,
+            from: 0x1000,
+            to: 0x20_0000,
+            cpu: 3,
+            size: Some(8192),
+        });
+        let json = serde_json::to_string(&access).unwrap();
+        assert_eq!(
+            json,
+            r#"{"kind":"rowclone","insn_count":42,"from":"0x0000000000001000","to":"0x0000000000200000","cpu":3,"size":8192}"#
+        );
+        let MemoryAccess::Rowclone(parsed) = serde_json::from_str(&json).unwrap() else {
+            panic!("not a rowclone");
+        };
+        assert_eq!(parsed.insn_count, 42);
+        assert_eq!(parsed.from, 0x1000);
+        assert_eq!(parsed.to, 0x20_0000);
+        assert_eq!(parsed.cpu, 3);
+        assert_eq!(parsed.size, Some(8192));
+    }
+
+    #[test]
+    fn records_round_trip_through_the_binary_codec() {
+        let accesses = [
+            "10,0,2,1,0x1000,3",
+            "11,0,0,255,0xffffffffffffffc0",
+            "20,1,0,0,0x1000,0x2000,8192",
+            "21,1,0,7,0x3000,0x4000",
+        ]
+        .map(|line| line.parse::<MemoryAccess>().unwrap());
+        let mut bytes = Vec::new();
+        for access in &accesses {
+            access.serialize(&mut bytes).unwrap();
+        }
+        assert_eq!(
+            bytes.len(),
+            2 * MemoryAccess::REGULAR_FRAME_SIZE + 2 * MemoryAccess::ROWCLONE_FRAME_SIZE
+        );
+
+        let mut reader = bytes.as_slice();
+        for access in &accesses {
+            let read = MemoryAccess::deserialize(&mut reader).unwrap().unwrap();
+            assert_eq!(format!("{:?}", read), format!("{:?}", access));
+        }
+        assert!(MemoryAccess::deserialize(&mut reader).unwrap().is_none());
+
+        let error = MemoryAccess::deserialize(&mut [9u8; 20].as_slice()).unwrap_err();
+        assert_eq!(error.to_string(), "unknown record tag 9");
+    }
+
+    #[test]
+    fn rowclones_without_a_cpu_belong_to_cpu_0() {
+        let Ok(MemoryAccess::Rowclone(rc)) = "20,1,0,0x1000,0x2000".parse() else {
+            panic!("not a rowclone");
+        };
+        assert_eq!((rc.cpu, rc.from, rc.to, rc.size), (0, 0x1000, 0x2000, None));
+        // they are written back in the current format
+        assert_eq!(
+            rc.to_string(),
+            "20,1,0,0,0x0000000000001000,0x0000000000002000"
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use std::time::{Duration, Instant};
+
+/// Reports on stderr how far a long pass over a trace has come, every `interval`
+/// records.
+pub struct Progress {
+    interval: u64,
+    // records left until the next report
+    countdown: u64,
+    processed: u64,
+    start: Instant,
+}
+
+impl Progress {
+    /// An interval of 0 disables the reports.
+    pub fn new(interval: u64) -> Self {
+        Progress {
+            interval,
+            countdown: interval,
+            processed: 0,
+            start: Instant::now(),
+        }
+    }
+
+    /// Counts one processed record. `rowclones` is only called when a report is due
+    /// and returns the rowclones matched so far.
+    #[inline]
+    pub fn tick(&mut self, rowclones: impl FnOnce() -> u64) {
+        self.processed += 1;
+        if self.interval == 0 {
+            return;
+        }
+        self.countdown -= 1;
+        if self.countdown == 0 {
+            self.countdown = self.interval;
+            eprintln!(
+                "{}",
+                report(self.processed, rowclones(), self.start.elapsed())
+            );
+        }
+    }
+}
+
+// The input is a stream of unknown length, so there is a rate but no ETA.
+fn report(processed: u64, rowclones: u64, elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs_f64();
+    let rate = if seconds > 0.0 {
+        processed as f64 / seconds
+    } else {
+        0.0
+    };
+    format!(
+        "Progress: {} records, {} rowclones, {:.1}s elapsed, {:.0} records/s",
+        processed, rowclones, seconds, rate
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_carry_the_rate() {
+        assert_eq!(
+            report(10_000_000, 42, Duration::from_millis(2500)),
+            "Progress: 10000000 records, 42 rowclones, 2.5s elapsed, 4000000 records/s"
+        );
+        assert_eq!(
+            report(0, 0, Duration::ZERO),
+            "Progress: 0 records, 0 rowclones, 0.0s elapsed, 0 records/s"
+        );
+    }
+
+    #[test]
+    fn a_report_is_due_every_interval() {
+        let reports = |interval, records| {
+            let mut progress = Progress::new(interval);
+            let mut reports = 0;
+            for _ in 0..records {
+                progress.tick(|| {
+                    reports += 1;
+                    0
+                });
+            }
+            reports
+        };
+        assert_eq!(reports(3, 7), 2);
+        assert_eq!(reports(3, 9), 3);
+        assert_eq!(reports(0, 9), 0);
+    }
+}
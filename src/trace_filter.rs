@@ -0,0 +1,1350 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+
+use crate::cache::{
+    CacheConfigError, CacheHierarchy, HierarchyResult, MissStatus, Mshr, PAGE_SIZE, Tlb,
+};
+use crate::memory_access::{MemRecord, MemoryAccess, RowcloneRecord};
+use crate::progress::Progress;
+use crate::trace_format::{OutputFormat, TraceKind, TraceRecord};
+
+/// What the warmup of a `TraceFilter` is counted in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WarmupUnit {
+    // input records
+    Records,
+    // instructions: the warmup lasts while records' insn_count is below it
+    Insns,
+}
+
+/// Write-combining store buffer sitting in front of the cache. Stores to the same
+/// entry-sized block are merged and only reach the cache when the entry drains.
+#[derive(Debug)]
+pub struct StoreBuffer {
+    capacity: usize,
+    entry_size: u64,
+    // block addresses of the pending entries, oldest first
+    entries: VecDeque<u64>,
+    stores: u64,
+    drained: u64,
+}
+
+impl StoreBuffer {
+    /// Panics unless `entry_size` is a power of two.
+    pub fn new(capacity: usize, entry_size: usize) -> Self {
+        assert!(
+            entry_size.is_power_of_two(),
+            "store buffer entries of {} bytes",
+            entry_size
+        );
+        StoreBuffer {
+            capacity,
+            entry_size: entry_size as u64,
+            entries: VecDeque::with_capacity(capacity),
+            stores: 0,
+            drained: 0,
+        }
+    }
+
+    fn entry_addr(&self, address: u64) -> u64 {
+        address - address % self.entry_size
+    }
+
+    /// Returns true if a pending entry covers the address.
+    pub fn contains(&self, address: u64) -> bool {
+        self.entries.contains(&self.entry_addr(address))
+    }
+
+    /// Buffers a store, merging it into a pending entry if possible.
+    /// Returns the address of the entry that had to be drained to make room.
+    pub fn store(&mut self, address: u64) -> Option<u64> {
+        self.stores += 1;
+        let entry = self.entry_addr(address);
+        if self.entries.contains(&entry) {
+            return None;
+        }
+        let evicted = if self.entries.len() == self.capacity {
+            self.drained += 1;
+            self.entries.pop_front()
+        } else {
+            None
+        };
+        self.entries.push_back(entry);
+        evicted
+    }
+
+    /// Drains all pending entries, oldest first.
+    pub fn drain(&mut self) -> Vec<u64> {
+        self.drained += self.entries.len() as u64;
+        self.entries.drain(..).collect()
+    }
+
+    pub fn stores(&self) -> u64 {
+        self.stores
+    }
+
+    pub fn drained(&self) -> u64 {
+        self.drained
+    }
+
+    pub fn coalescing_ratio(&self) -> f64 {
+        if self.drained == 0 {
+            0.0
+        } else {
+            self.stores as f64 / self.drained as f64
+        }
+    }
+}
+
+// Synthetic region page walks read from, with a separate slice per page table level.
+const PAGE_TABLE_BASE: u64 = 0xffff_f000_0000_0000;
+const PAGE_TABLE_LEVEL_SPAN: u64 = 1 << 40;
+// page number bits resolved per page table level, as on x86-64
+const PAGE_TABLE_LEVEL_BITS: u32 = 9;
+
+// Addresses of the page table entries read by a walk for the page, root first.
+fn page_walk(address: u64, levels: u32) -> Vec<u64> {
+    let page = address / PAGE_SIZE;
+    (0..levels)
+        .map(|level| {
+            let shift = PAGE_TABLE_LEVEL_BITS * (levels - 1 - level);
+            let entry = page.checked_shr(shift).unwrap_or(0);
+            PAGE_TABLE_BASE
+                .wrapping_add(level as u64 * PAGE_TABLE_LEVEL_SPAN)
+                .wrapping_add(entry * 8)
+        })
+        .collect()
+}
+
+// Instructions between two requests. Records can arrive slightly out of order after
+// merging, in which case the bubble is `out_of_order`.
+fn bubble(prev_insn_count: u64, insn_count: u64, out_of_order: u64) -> u64 {
+    insn_count
+        .checked_sub(prev_insn_count)
+        .unwrap_or(out_of_order)
+}
+
+/// Per-CPU statistics of an emitted trace.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TraceStats {
+    pub requests: u64,
+    pub bubble_sum: u64,
+    pub rowclones: u64,
+    pub writebacks: u64,
+    // dirty rowclone source lines written back before the copy
+    pub source_flushes: u64,
+    // accesses reaching the caches and those that hit in any level
+    pub accesses: u64,
+    pub hits: u64,
+    // the part of them at or above FilterOptions::kernel_threshold
+    pub kernel_accesses: u64,
+    pub kernel_hits: u64,
+    pub first_insn: Option<u64>,
+    pub last_insn: u64,
+    // records emitted with a lower insn_count than the previous one
+    pub out_of_order: u64,
+}
+
+impl TraceStats {
+    fn count_access(&mut self, kernel: bool, hit: bool) {
+        self.accesses += 1;
+        self.hits += hit as u64;
+        if kernel {
+            self.kernel_accesses += 1;
+            self.kernel_hits += hit as u64;
+        }
+    }
+}
+
+/// Everything about a simulated configuration besides the caches themselves.
+pub struct FilterOptions {
+    pub cpus: usize,
+    // write-combining store buffer entries per CPU (0 disables it) and their width
+    pub store_buffer_entries: usize,
+    pub store_buffer_entry_size: usize,
+    // MSHRs per CPU merging misses to a block being fetched (0 disables them), and
+    // the instructions a fill takes
+    pub mshr_entries: usize,
+    pub mshr_window: u64,
+    // per-CPU TLB entries (0 disables it) and associativity
+    pub tlb_entries: usize,
+    pub tlb_assoc: usize,
+    // loads emitted for the page walk of a TLB miss
+    pub page_walk_loads: u32,
+    // don't translate rowclone source and destination pages
+    pub tlb_bypass_rowclones: bool,
+    // perform rowclones as regular block loads and stores instead of emitting them
+    pub expand_rowclones: bool,
+    // emit writebacks for dirty lines evicted by an access
+    pub writebacks: bool,
+    // emit writebacks for dirty lines invalidated by a rowclone
+    pub rowclone_writebacks: bool,
+    // also invalidate a rowclone's destination in the private caches of the other
+    // CPUs, not just the issuing one
+    pub coherent_rowclones: bool,
+    // write back and clean the dirty lines of a rowclone's source before the copy
+    pub flush_rowclone_source: bool,
+    // emit prefetch fills that reach memory as loads
+    pub emit_prefetches: bool,
+    // emit every store, whether it hits or not
+    pub write_through: bool,
+    // size of rowclones whose record doesn't carry one
+    pub page_size: u64,
+    // how long accesses only warm up the caches, in warmup_unit
+    pub warmup: u64,
+    pub warmup_unit: WarmupUnit,
+    // print the sets holding this address's block to stderr on every access to it
+    pub dump_on: Option<u64>,
+    // accesses at or above this address are counted as kernel space
+    pub kernel_threshold: u64,
+    // all CPUs write to a single trace, prefixed with their id
+    pub merged: bool,
+    // write fixed-size binary records instead of text lines
+    pub binary_out: bool,
+    pub format: OutputFormat,
+    pub cpi: f64,
+    // prefix every text line with the cycles since the previous request of its CPU
+    pub ipc: Option<f64>,
+    // bubble of records whose insn_count is below the previous one of their CPU
+    pub out_of_order_bubble: u64,
+    // receives the lines every rowclone invalidated as CSV rows
+    pub rowclone_costs: Option<Box<dyn Write>>,
+    // records between two progress reports on stderr, 0 disables them
+    pub progress_interval: u64,
+}
+
+impl Default for FilterOptions {
+    fn default() -> Self {
+        FilterOptions {
+            cpus: 1,
+            store_buffer_entries: 0,
+            store_buffer_entry_size: 64,
+            mshr_entries: 0,
+            mshr_window: 1000,
+            tlb_entries: 0,
+            tlb_assoc: 4,
+            page_walk_loads: 4,
+            tlb_bypass_rowclones: false,
+            expand_rowclones: false,
+            writebacks: false,
+            rowclone_writebacks: false,
+            coherent_rowclones: false,
+            flush_rowclone_source: false,
+            emit_prefetches: false,
+            write_through: false,
+            page_size: PAGE_SIZE,
+            warmup: 0,
+            warmup_unit: WarmupUnit::Records,
+            dump_on: None,
+            kernel_threshold: 0xffff_8000_0000_0000,
+            merged: false,
+            binary_out: false,
+            format: OutputFormat::RamulatorInsn,
+            cpi: 1.0,
+            ipc: None,
+            out_of_order_bubble: 0,
+            rowclone_costs: None,
+            progress_interval: 0,
+        }
+    }
+}
+
+/// Runs the accesses through the caches and writes those reaching memory to
+/// `writers`, with the instructions since the previous request of their CPU as
+/// bubble. Stops at the first failed write.
+pub fn filter_through_cache<I, W>(
+    accesses: I,
+    caches: Option<CacheHierarchy>,
+    writers: Vec<W>,
+    options: FilterOptions,
+) -> io::Result<TraceFilter<W>>
+where
+    I: IntoIterator<Item = MemoryAccess>,
+    W: Write,
+{
+    let mut filter = TraceFilter::new(caches, writers, options)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    for access in accesses {
+        filter.process(&access)?;
+    }
+    filter.finish()?;
+    Ok(filter)
+}
+
+/// State of one simulated configuration: the caches, a store buffer per CPU and the
+/// bookkeeping needed to compute bubbles for the emitted traces.
+pub struct TraceFilter<W: Write> {
+    // None if every record reaches memory unchanged
+    caches: Option<CacheHierarchy>,
+    store_buffers: Vec<StoreBuffer>,
+    mshrs: Vec<Mshr>,
+    // empty if TLBs are disabled
+    tlbs: Vec<Tlb>,
+    writers: Vec<W>,
+    first: Vec<bool>,
+    prev_insn_count: Vec<u64>,
+    last_insn_count: Vec<u64>,
+    stats: Vec<TraceStats>,
+    // perform rowclones as regular block loads and stores instead of emitting them
+    expand_rowclones: bool,
+    writebacks: bool,
+    rowclone_writebacks: bool,
+    coherent_rowclones: bool,
+    flush_rowclone_source: bool,
+    emit_prefetches: bool,
+    write_through: bool,
+    page_size: u64,
+    page_walk_loads: u32,
+    tlb_bypass_rowclones: bool,
+    warmup: u64,
+    warmup_unit: WarmupUnit,
+    // records seen so far during the warmup
+    warmup_seen: u64,
+    warming_up: bool,
+    // block whose accesses are dumped to stderr
+    dump_on: Option<u64>,
+    kernel_threshold: u64,
+    // all CPUs write to the single writer, prefixed with their id
+    merged: bool,
+    binary_out: bool,
+    // first failed write to a trace, the simulation stops at it
+    write_error: Option<String>,
+    // receives the lines every rowclone invalidated
+    rowclone_costs: Option<Box<dyn Write>>,
+    format: OutputFormat,
+    cpi: f64,
+    // instructions per cycle of the cycle gap column, None without it
+    ipc: Option<f64>,
+    // rounding error of each CPU's last cycle gap, carried into the next one
+    cycle_carry: Vec<f64>,
+    out_of_order_bubble: u64,
+    progress: Progress,
+}
+
+impl<W: Write> TraceFilter<W> {
+    /// Without caches every record is written to the traces unchanged. `writers`
+    /// holds one trace per CPU, or a single one with `FilterOptions::merged`.
+    pub fn new(
+        caches: Option<CacheHierarchy>,
+        writers: Vec<W>,
+        options: FilterOptions,
+    ) -> Result<Self, CacheConfigError> {
+        let cpus = options.cpus;
+        let block_size = caches.as_ref().map_or(64, |caches| caches.block_size());
+        let store_buffers = (0..cpus)
+            .map(|_| {
+                StoreBuffer::new(
+                    options.store_buffer_entries,
+                    options.store_buffer_entry_size,
+                )
+            })
+            .collect();
+        let mshrs = if options.mshr_entries > 0 {
+            (0..cpus)
+                .map(|_| Mshr::new(options.mshr_entries, options.mshr_window))
+                .collect()
+        } else {
+            vec![]
+        };
+        let tlbs = if options.tlb_entries > 0 {
+            (0..cpus)
+                .map(|_| Tlb::new(options.tlb_entries, options.tlb_assoc))
+                .collect::<Result<_, _>>()?
+        } else {
+            vec![]
+        };
+        Ok(TraceFilter {
+            caches,
+            store_buffers,
+            mshrs,
+            tlbs,
+            writers,
+            first: vec![true; cpus],
+            prev_insn_count: vec![0; cpus],
+            last_insn_count: vec![0; cpus],
+            stats: vec![TraceStats::default(); cpus],
+            expand_rowclones: options.expand_rowclones,
+            writebacks: options.writebacks,
+            rowclone_writebacks: options.rowclone_writebacks,
+            coherent_rowclones: options.coherent_rowclones,
+            flush_rowclone_source: options.flush_rowclone_source,
+            emit_prefetches: options.emit_prefetches,
+            write_through: options.write_through,
+            page_size: options.page_size,
+            page_walk_loads: options.page_walk_loads,
+            tlb_bypass_rowclones: options.tlb_bypass_rowclones,
+            warmup: options.warmup,
+            warmup_unit: options.warmup_unit,
+            warmup_seen: 0,
+            warming_up: options.warmup > 0,
+            dump_on: options.dump_on.map(|address| address / block_size as u64),
+            kernel_threshold: options.kernel_threshold,
+            merged: options.merged,
+            binary_out: options.binary_out,
+            write_error: None,
+            rowclone_costs: options.rowclone_costs,
+            format: options.format,
+            cpi: options.cpi,
+            ipc: options.ipc,
+            cycle_carry: vec![0.0; cpus],
+            out_of_order_bubble: options.out_of_order_bubble,
+            progress: Progress::new(options.progress_interval),
+        })
+    }
+
+    pub fn stats(&self) -> &[TraceStats] {
+        &self.stats
+    }
+
+    pub fn caches(&self) -> Option<&CacheHierarchy> {
+        self.caches.as_ref()
+    }
+
+    pub fn store_buffers(&self) -> &[StoreBuffer] {
+        &self.store_buffers
+    }
+
+    pub fn mshrs(&self) -> &[Mshr] {
+        &self.mshrs
+    }
+
+    pub fn tlbs(&self) -> &[Tlb] {
+        &self.tlbs
+    }
+
+    // The first failed write, later ones are usually caused by it.
+    fn result(&self) -> io::Result<()> {
+        match &self.write_error {
+            Some(message) => Err(io::Error::other(message.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn observe(&mut self, cpu: usize, insn_count: u64) {
+        if self.warming_up {
+            let warm = match self.warmup_unit {
+                WarmupUnit::Records => self.warmup_seen < self.warmup,
+                WarmupUnit::Insns => insn_count < self.warmup,
+            };
+            if warm {
+                self.warmup_seen += 1;
+                self.first[cpu] = false;
+                self.last_insn_count[cpu] = insn_count;
+                return;
+            }
+            self.end_warmup();
+        }
+        if self.first[cpu] {
+            self.prev_insn_count[cpu] = insn_count;
+            self.first[cpu] = false;
+        }
+        self.last_insn_count[cpu] = insn_count;
+        self.stats[cpu].first_insn.get_or_insert(insn_count);
+        self.stats[cpu].last_insn = insn_count;
+    }
+
+    // CPUs seen during the warmup count their first bubble from its end, or from
+    // their last warmup record if that is later.
+    fn end_warmup(&mut self) {
+        self.warming_up = false;
+        if let Some(caches) = self.caches.as_mut() {
+            caches.reset_stats();
+        }
+        for buffer in self.store_buffers.iter_mut() {
+            buffer.stores = 0;
+            buffer.drained = 0;
+        }
+        for mshr in self.mshrs.iter_mut() {
+            mshr.reset_stats();
+        }
+        for tlb in self.tlbs.iter_mut() {
+            tlb.reset_stats();
+        }
+        let end = match self.warmup_unit {
+            WarmupUnit::Records => 0,
+            WarmupUnit::Insns => self.warmup,
+        };
+        for cpu in 0..self.first.len() {
+            if !self.first[cpu] {
+                self.prev_insn_count[cpu] = end.max(self.last_insn_count[cpu]);
+            }
+        }
+    }
+
+    fn write_record(&mut self, cpu: usize, record: &TraceRecord) {
+        let written = if self.binary_out {
+            let mut buffer = [0u8; TraceRecord::SIZE];
+            record.serialize(&mut buffer);
+            self.writers[cpu].write_all(&buffer)
+        } else {
+            let mut line = self
+                .format
+                .line(record, self.last_insn_count[cpu], self.cpi);
+            if let Some(ipc) = self.ipc {
+                let gap = self.cycle_gap(cpu, record.bubble, ipc);
+                // further lines of the record are issued in the same cycle
+                line = format!("{} {}", gap, line.replace('\n', "\n0 "));
+            }
+            if self.merged {
+                // every line of a record carries the CPU
+                line.lines()
+                    .try_for_each(|line| writeln!(self.writers[0], "{} {}", cpu, line))
+            } else {
+                writeln!(self.writers[cpu], "{}", line)
+            }
+        };
+        if let Err(e) = written {
+            let index = self.stats[cpu].requests;
+            self.write_failed(cpu, format!("record {}: {}", index, e));
+        }
+    }
+
+    // Cycles matching the bubble at the given IPC. The rounding error is carried into
+    // the next gap of the CPU, so the gaps add up to the rounded total.
+    fn cycle_gap(&mut self, cpu: usize, bubble: u64, ipc: f64) -> u64 {
+        let exact = bubble as f64 / ipc + self.cycle_carry[cpu];
+        let gap = exact.round().max(0.0);
+        self.cycle_carry[cpu] = exact - gap;
+        gap as u64
+    }
+
+    // Keeps the first failure, later ones are usually caused by it.
+    fn write_failed(&mut self, cpu: usize, message: String) {
+        if self.write_error.is_none() {
+            self.write_error = Some(format!("trace of cpu {} failed at {}", cpu, message));
+        }
+    }
+
+    fn emit(&mut self, cpu: usize, insn_count: u64, kind: TraceKind, addr1: u64, addr2: u64) {
+        if self.warming_up {
+            return;
+        }
+        let prev_insn_count = self.prev_insn_count[cpu];
+        if insn_count < prev_insn_count {
+            self.stats[cpu].out_of_order += 1;
+        }
+        let bubble = bubble(prev_insn_count, insn_count, self.out_of_order_bubble);
+        self.write_record(
+            cpu,
+            &TraceRecord {
+                bubble,
+                kind,
+                addr1,
+                addr2,
+            },
+        );
+        self.stats[cpu].requests += 1;
+        self.stats[cpu].bubble_sum += bubble;
+        self.prev_insn_count[cpu] = insn_count;
+    }
+
+    // Writebacks are emitted as stores without a bubble and don't advance the
+    // instruction count.
+    fn emit_writeback(&mut self, cpu: usize, address: u64) {
+        if !self.warming_up {
+            self.write_record(
+                cpu,
+                &TraceRecord {
+                    bubble: 0,
+                    kind: TraceKind::Store,
+                    addr1: address,
+                    addr2: 0,
+                },
+            );
+            self.stats[cpu].requests += 1;
+            self.stats[cpu].writebacks += 1;
+        }
+    }
+
+    // Prefetches are emitted as loads without a bubble, like writebacks.
+    fn emit_prefetch(&mut self, cpu: usize, address: u64) {
+        if self.emit_prefetches && !self.warming_up {
+            self.write_record(
+                cpu,
+                &TraceRecord {
+                    bubble: 0,
+                    kind: TraceKind::Load,
+                    addr1: address,
+                    addr2: 0,
+                },
+            );
+            self.stats[cpu].requests += 1;
+        }
+    }
+
+    fn hierarchy(&mut self) -> &mut CacheHierarchy {
+        self.caches
+            .as_mut()
+            .expect("filters without caches pass records through")
+    }
+
+    fn access_cache(&mut self, mem: &MemRecord) {
+        let cpu = mem.cpu;
+        let access = self.hierarchy().access(cpu, mem.address, mem.store);
+        if !self.warming_up {
+            let kernel = mem.address >= self.kernel_threshold;
+            self.stats[cpu].count_access(kernel, access.level != HierarchyResult::Miss);
+        }
+        let block = mem.address / self.hierarchy().block_size() as u64;
+        if self.dump_on == Some(block) {
+            self.dump_sets(mem, access.level);
+        }
+        // misses merged into an outstanding one don't reach memory again
+        let fill = access.level == HierarchyResult::Miss
+            && self
+                .mshrs
+                .get_mut(cpu)
+                .is_none_or(|mshr| mshr.miss(block, mem.insn_count) != MissStatus::SecondaryMiss);
+        if fill || (mem.store && self.write_through) {
+            let kind = if mem.store {
+                TraceKind::Store
+            } else {
+                TraceKind::Load
+            };
+            self.emit(cpu, mem.insn_count, kind, mem.address, 0);
+        }
+        for address in access.prefetches {
+            self.emit_prefetch(cpu, address);
+        }
+        if self.writebacks {
+            for address in access.writebacks {
+                self.emit_writeback(cpu, address);
+            }
+        }
+    }
+
+    fn dump_sets(&self, mem: &MemRecord, level: HierarchyResult) {
+        eprintln!(
+            "insn {} cpu {} {} 0x{:016x}: {:?}",
+            mem.insn_count,
+            mem.cpu,
+            if mem.store { "store" } else { "load" },
+            mem.address,
+            level
+        );
+        let Some(hierarchy) = self.caches.as_ref() else {
+            return;
+        };
+        let private = hierarchy.private().get(mem.cpu);
+        let levels = [
+            ("L1", private.and_then(|caches| caches.l1())),
+            ("L2", private.map(|caches| caches.l2())),
+            ("L3", hierarchy.l3()),
+        ];
+        for (name, cache) in levels {
+            if let Some(cache) = cache {
+                eprintln!("  {} set {}:", name, cache.set_of(mem.address));
+                for line in cache.dump_set(mem.address) {
+                    eprintln!("    {}", line);
+                }
+            }
+        }
+    }
+
+    // Non-temporal stores go straight to memory: the store is always emitted and a
+    // cached copy of the block is invalidated rather than updated, so no line is
+    // installed or dirtied.
+    fn store_non_temporal(&mut self, mem: &MemRecord) {
+        // an older buffered store to the same block reaches the cache first
+        if self.store_buffers[mem.cpu].contains(mem.address) {
+            let pending = self.store_buffers[mem.cpu].drain();
+            self.drain_stores(mem.cpu, pending, mem.insn_count);
+        }
+        let block_size = self.hierarchy().block_size() as u64;
+        let block = mem.address / block_size * block_size;
+        let invalidated = self
+            .hierarchy()
+            .invalidate_range(mem.cpu, block, block_size);
+        if self.writebacks {
+            for (owner, address) in invalidated.dirty {
+                self.emit_writeback(owner, address);
+            }
+        }
+        for mshr in self.mshrs.iter_mut() {
+            mshr.invalidate_blocks(block / block_size, block / block_size);
+        }
+        self.emit(mem.cpu, mem.insn_count, TraceKind::Store, mem.address, 0);
+    }
+
+    fn access(&mut self, mem: &MemRecord) {
+        let cpu = mem.cpu;
+        if mem.non_temporal {
+            self.store_non_temporal(mem);
+        } else if self.store_buffers[cpu].capacity == 0 {
+            self.access_cache(mem);
+        } else if mem.store {
+            if let Some(evicted) = self.store_buffers[cpu].store(mem.address) {
+                self.drain_stores(cpu, vec![evicted], mem.insn_count);
+            }
+        } else if !self.store_buffers[cpu].contains(mem.address) {
+            // loads that hit a pending store are forwarded from the buffer
+            self.access_cache(mem);
+        }
+    }
+
+    // Drained store buffer entries reach the cache as a single store at the time of the drain.
+    fn drain_stores(&mut self, cpu: usize, entries: Vec<u64>, insn_count: u64) {
+        let size = self.store_buffers[cpu].entry_size.trailing_zeros() as u8;
+        for address in entries {
+            self.access_cache(&MemRecord {
+                cpu,
+                address,
+                insn_count,
+                store: true,
+                size,
+                non_temporal: false,
+            });
+        }
+    }
+
+    fn rowclone(&mut self, rc: &RowcloneRecord) {
+        let cpu = rc.cpu;
+        // a rowclone acts as a fence for the issuing CPU's pending stores
+        let pending = self.store_buffers[cpu].drain();
+        self.drain_stores(cpu, pending, rc.insn_count);
+        let size = rc.size.unwrap_or(self.page_size);
+        if self.expand_rowclones {
+            let block_size = self.hierarchy().block_size();
+            for offset in (0..size).step_by(block_size) {
+                for (address, store) in [(rc.from + offset, false), (rc.to + offset, true)] {
+                    self.access(&MemRecord {
+                        cpu,
+                        address,
+                        insn_count: rc.insn_count,
+                        store,
+                        size: block_size.trailing_zeros() as u8,
+                        non_temporal: false,
+                    });
+                }
+            }
+            return;
+        }
+        if self.flush_rowclone_source {
+            // the copy happens in DRAM, so it must not miss data still dirty in a cache
+            let flushed = self.hierarchy().clean_range(cpu, rc.from, size);
+            if !self.warming_up {
+                self.stats[cpu].source_flushes += flushed.len() as u64;
+            }
+            for (owner, address) in flushed {
+                self.emit_writeback(owner, address);
+            }
+        }
+        let invalidated = if self.coherent_rowclones {
+            self.hierarchy().invalidate_range(cpu, rc.to, size)
+        } else {
+            self.hierarchy().invalidate_range_of(cpu, rc.to, size)
+        };
+        if !self.warming_up
+            && let Some(out) = self.rowclone_costs.as_mut()
+        {
+            let written = writeln!(
+                out,
+                "{},{},0x{:016x},{},{}",
+                rc.insn_count,
+                cpu,
+                rc.to,
+                invalidated.lines,
+                invalidated.dirty.len()
+            );
+            if let Err(e) = written
+                && self.write_error.is_none()
+            {
+                self.write_error = Some(format!("rowclone cost file failed: {}", e));
+            }
+        }
+        if self.rowclone_writebacks {
+            for (owner, address) in invalidated.dirty {
+                self.emit_writeback(owner, address);
+            }
+        }
+        if size > 0 {
+            let block_size = self.hierarchy().block_size() as u64;
+            let (first, last) = (rc.to / block_size, (rc.to + size - 1) / block_size);
+            for mshr in self.mshrs.iter_mut() {
+                mshr.invalidate_blocks(first, last);
+            }
+        }
+        self.emit(cpu, rc.insn_count, TraceKind::Rowclone, rc.from, rc.to);
+        if !self.warming_up {
+            self.stats[cpu].rowclones += 1;
+        }
+    }
+
+    // A TLB miss emits the loads of its page walk ahead of the access.
+    fn translate(&mut self, cpu: usize, address: u64, insn_count: u64) {
+        if self.tlbs.is_empty() || self.tlbs[cpu].translate(address) {
+            return;
+        }
+        for entry in page_walk(address, self.page_walk_loads) {
+            self.emit(cpu, insn_count, TraceKind::Load, entry, 0);
+        }
+    }
+
+    // Without caches every record reaches memory unchanged.
+    fn pass_through(&mut self, rec: &MemoryAccess) {
+        match rec {
+            MemoryAccess::Regular(mem) => {
+                self.observe(mem.cpu, mem.insn_count);
+                let kind = if mem.store {
+                    TraceKind::Store
+                } else {
+                    TraceKind::Load
+                };
+                self.emit(mem.cpu, mem.insn_count, kind, mem.address, 0);
+                if !self.warming_up {
+                    let kernel = mem.address >= self.kernel_threshold;
+                    self.stats[mem.cpu].count_access(kernel, false);
+                }
+            }
+            MemoryAccess::Rowclone(rc) => {
+                self.observe(rc.cpu, rc.insn_count);
+                self.emit(rc.cpu, rc.insn_count, TraceKind::Rowclone, rc.from, rc.to);
+                if !self.warming_up {
+                    self.stats[rc.cpu].rowclones += 1;
+                }
+            }
+        }
+    }
+
+    /// Runs a record through the caches, writing what reaches memory to the
+    /// traces. Fails once writing a trace failed.
+    pub fn process(&mut self, rec: &MemoryAccess) -> io::Result<()> {
+        let stats = &self.stats;
+        self.progress
+            .tick(|| stats.iter().map(|stats| stats.rowclones).sum());
+        if self.caches.is_none() {
+            self.pass_through(rec);
+            return self.result();
+        }
+        match rec {
+            MemoryAccess::Regular(mem) => {
+                self.observe(mem.cpu, mem.insn_count);
+                self.translate(mem.cpu, mem.address, mem.insn_count);
+                self.access(mem);
+            }
+            MemoryAccess::Rowclone(rc) => {
+                self.observe(rc.cpu, rc.insn_count);
+                if !self.tlb_bypass_rowclones {
+                    let size = rc.size.unwrap_or(self.page_size);
+                    for offset in (0..size).step_by(PAGE_SIZE as usize) {
+                        self.translate(rc.cpu, rc.from + offset, rc.insn_count);
+                        self.translate(rc.cpu, rc.to + offset, rc.insn_count);
+                    }
+                }
+                self.rowclone(rc);
+            }
+        }
+        self.result()
+    }
+
+    /// Drains the store buffers and flushes the traces.
+    pub fn finish(&mut self) -> io::Result<()> {
+        for cpu in 0..self.store_buffers.len() {
+            let pending = self.store_buffers[cpu].drain();
+            self.drain_stores(cpu, pending, self.last_insn_count[cpu]);
+        }
+        for cpu in 0..self.writers.len() {
+            if let Err(e) = self.writers[cpu].flush() {
+                let written = self.stats[cpu].requests;
+                self.write_failed(cpu, format!("flush after {} records: {}", written, e));
+            }
+        }
+        if let Some(Err(e)) = self.rowclone_costs.as_mut().map(|out| out.flush())
+            && self.write_error.is_none()
+        {
+            self.write_error = Some(format!("rowclone cost file failed: {}", e));
+        }
+        self.result()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{Cache, PrivateCaches, WritePolicy};
+
+    fn caches(cpus: usize) -> Option<CacheHierarchy> {
+        let private = (0..cpus)
+            .map(|_| PrivateCaches::new(None, Cache::new(4096, 64, 4)))
+            .collect();
+        Some(CacheHierarchy::new(private, None))
+    }
+
+    fn access(insn_count: u64, cpu: usize, address: u64, store: bool) -> MemoryAccess {
+        MemoryAccess::Regular(MemRecord {
+            insn_count,
+            address,
+            store,
+            cpu,
+            size: 3,
+            non_temporal: false,
+        })
+    }
+
+    fn load(insn_count: u64, cpu: usize, address: u64) -> MemoryAccess {
+        access(insn_count, cpu, address, false)
+    }
+
+    fn store(insn_count: u64, address: u64) -> MemoryAccess {
+        access(insn_count, 0, address, true)
+    }
+
+    fn rowclone(insn_count: u64, from: u64, to: u64) -> MemoryAccess {
+        MemoryAccess::Rowclone(RowcloneRecord {
+            insn_count,
+            from,
+            to,
+            cpu: 0,
+            size: None,
+        })
+    }
+
+    // The text traces of every CPU.
+    fn traces(
+        accesses: Vec<MemoryAccess>,
+        caches: Option<CacheHierarchy>,
+        options: FilterOptions,
+    ) -> (Vec<String>, Vec<TraceStats>) {
+        let writers = vec![vec![]; options.cpus];
+        let filter = filter_through_cache(accesses, caches, writers, options).unwrap();
+        let stats = filter.stats().to_vec();
+        let traces = filter
+            .writers
+            .into_iter()
+            .map(|trace| String::from_utf8(trace).unwrap())
+            .collect();
+        (traces, stats)
+    }
+
+    #[test]
+    fn misses_are_emitted_with_the_bubble_since_the_last_one() {
+        let accesses = vec![
+            load(10, 0, 0x1000),
+            load(20, 0, 0x1008),
+            load(35, 0, 0x2000),
+            load(40, 0, 0x1000),
+        ];
+        let (traces, stats) = traces(accesses, caches(1), FilterOptions::default());
+        assert_eq!(traces[0], "0 0x0000000000001000\n25 0x0000000000002000\n");
+        assert_eq!(stats[0].accesses, 4);
+        assert_eq!(stats[0].hits, 2);
+        assert_eq!(stats[0].requests, 2);
+        assert_eq!(stats[0].bubble_sum, 25);
+    }
+
+    #[test]
+    fn every_cpu_writes_its_own_trace() {
+        let accesses = vec![load(1, 0, 0x1000), load(2, 1, 0x1000), load(5, 1, 0x1000)];
+        let options = FilterOptions {
+            cpus: 2,
+            ..FilterOptions::default()
+        };
+        let (traces, _) = traces(accesses, caches(2), options);
+        assert_eq!(traces[0], "0 0x0000000000001000\n");
+        assert_eq!(traces[1], "0 0x0000000000001000\n");
+    }
+
+    #[test]
+    fn rowclone_invalidates_the_destination() {
+        let accesses = vec![
+            load(1, 0, 0x5000),
+            rowclone(3, 0x1000, 0x5000),
+            load(7, 0, 0x5000),
+        ];
+        let (traces, stats) = traces(accesses, caches(1), FilterOptions::default());
+        assert_eq!(
+            traces[0],
+            "0 0x0000000000005000\n\
+             2 0x0000000000001000 0x0000000000005000\n\
+             4 0x0000000000005000\n"
+        );
+        assert_eq!(stats[0].rowclones, 1);
+    }
+
+    #[test]
+    fn without_caches_records_pass_through() {
+        let accesses = vec![load(1, 0, 0x1000), load(2, 0, 0x1000), store(4, 0x1000)];
+        let (traces, stats) = traces(accesses, None, FilterOptions::default());
+        assert_eq!(
+            traces[0],
+            "0 0x0000000000001000\n1 0x0000000000001000\n2 -1 0x0000000000001000\n"
+        );
+        assert_eq!(stats[0].hits, 0);
+    }
+
+    #[test]
+    fn warmup_fills_the_caches_without_emitting() {
+        let accesses = || {
+            vec![
+                load(10, 0, 0x1000),
+                load(20, 0, 0x2000),
+                load(30, 0, 0x1000),
+                load(40, 0, 0x3000),
+            ]
+        };
+        let records = FilterOptions {
+            warmup: 2,
+            ..FilterOptions::default()
+        };
+        let (traces, stats) = traces(accesses(), caches(1), records);
+        // the bubble starts at the last warmup record
+        assert_eq!(traces[0], "20 0x0000000000003000\n");
+        assert_eq!(stats[0].accesses, 2);
+        assert_eq!(stats[0].hits, 1);
+
+        let insns = FilterOptions {
+            warmup: 25,
+            warmup_unit: WarmupUnit::Insns,
+            ..FilterOptions::default()
+        };
+        let (traces, _) = self::traces(accesses(), caches(1), insns);
+        // the bubble starts at the end of the warmup
+        assert_eq!(traces[0], "15 0x0000000000003000\n");
+    }
+
+    #[test]
+    fn mshrs_merge_a_miss_to_a_block_being_fetched() {
+        // the fifth block evicts the first from its set before the first's fill completes
+        let mut accesses: Vec<_> = (0..5).map(|i| load(i, 0, i * 0x400)).collect();
+        accesses.push(load(10, 0, 0x0));
+        accesses.push(load(200, 0, 0x400));
+        let options = FilterOptions {
+            mshr_entries: 8,
+            mshr_window: 100,
+            ..FilterOptions::default()
+        };
+        let (traces, stats) = traces(accesses, caches(1), options);
+        assert_eq!(traces[0].lines().count(), 6);
+        assert_eq!(stats[0].requests, 6);
+    }
+
+    // A memset of 8 byte stores through a store buffer of the given entries.
+    fn memset(base: u64, len: u64, entries: usize, entry_size: usize) -> TraceFilter<Vec<u8>> {
+        let accesses: Vec<_> = (base..base + len)
+            .step_by(8)
+            .enumerate()
+            .map(|(i, address)| store(i as u64, address))
+            .collect();
+        let options = FilterOptions {
+            store_buffer_entries: entries,
+            store_buffer_entry_size: entry_size,
+            ..FilterOptions::default()
+        };
+        filter_through_cache(accesses, caches(1), vec![vec![]], options).unwrap()
+    }
+
+    #[test]
+    fn store_buffer_coalesces_memset() {
+        // a 4KB memset reaches the cache as one store per 64B entry
+        let buffered = memset(0x10000, 4096, 8, 64);
+        assert_eq!(buffered.store_buffers()[0].stores(), 512);
+        assert_eq!(buffered.store_buffers()[0].drained(), 64);
+        assert_eq!(buffered.stats()[0].accesses, 64);
+        assert_eq!(memset(0x10000, 4096, 0, 64).stats()[0].accesses, 512);
+    }
+
+    #[test]
+    fn narrower_entries_drain_separately() {
+        let buffered = memset(0x10000, 64, 8, 32);
+        assert_eq!(buffered.store_buffers()[0].drained(), 2);
+        assert_eq!(buffered.stats()[0].accesses, 2);
+    }
+
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_writes_stop_the_filter() {
+        let accesses = vec![load(1, 0, 0x1000), load(2, 0, 0x2000)];
+        let result = filter_through_cache(
+            accesses,
+            caches(1),
+            vec![FailingWriter],
+            FilterOptions::default(),
+        );
+        let error = result.err().expect("write failure not reported");
+        assert_eq!(
+            error.to_string(),
+            "trace of cpu 0 failed at record 0: disk full"
+        );
+    }
+
+    #[test]
+    fn rowclones_invalidate_their_whole_size() {
+        // one block in each of the 16 sets, spread over the first MB of the destination
+        let blocks: Vec<u64> = (0..16).map(|i| 0x40_0000 + i * 0x1_0040).collect();
+        let reloads_missing = |size, page_size| {
+            let mut accesses: Vec<_> = blocks.iter().map(|&a| load(1, 0, a)).collect();
+            accesses.push(MemoryAccess::Rowclone(RowcloneRecord {
+                insn_count: 2,
+                from: 0x80_0000,
+                to: 0x40_0000,
+                cpu: 0,
+                size,
+            }));
+            accesses.extend(blocks.iter().map(|&a| load(3, 0, a)));
+            let options = FilterOptions {
+                page_size,
+                ..FilterOptions::default()
+            };
+            let (_, stats) = traces(accesses, caches(1), options);
+            // the first loads and the rowclone are requests too
+            stats[0].requests - 17
+        };
+        let huge = 2 * 1024 * 1024;
+        assert_eq!(reloads_missing(Some(huge), PAGE_SIZE), 16);
+        // without a size only the first page is invalidated
+        assert_eq!(reloads_missing(None, PAGE_SIZE), 1);
+        assert_eq!(reloads_missing(None, huge), 16);
+    }
+
+    #[test]
+    fn bubbles_never_underflow() {
+        assert_eq!(bubble(100, 150, 0), 50);
+        assert_eq!(bubble(100, 100, 1), 0);
+        assert_eq!(bubble(100, 99, 0), 0);
+        assert_eq!(bubble(100, 99, 1), 1);
+
+        // an out-of-order record gets the configured bubble and is counted
+        let accesses = vec![load(10, 0, 0x1000), load(8, 0, 0x2000), load(12, 0, 0x3000)];
+        let options = FilterOptions {
+            out_of_order_bubble: 1,
+            ..FilterOptions::default()
+        };
+        let (traces, stats) = traces(accesses, caches(1), options);
+        assert_eq!(
+            traces[0]
+                .lines()
+                .map(|l| l.split(' ').next().unwrap())
+                .collect::<Vec<_>>(),
+            ["0", "1", "4"]
+        );
+        assert_eq!(stats[0].out_of_order, 1);
+    }
+
+    #[test]
+    fn out_of_order_records_are_counted_per_cpu() {
+        // cpu 1 starting below cpu 0 is not out of order, only going back on a CPU is
+        let accesses = vec![
+            load(10, 0, 0x1000),
+            load(5, 1, 0x2000),
+            load(8, 0, 0x3000),
+            load(3, 1, 0x4000),
+            load(9, 1, 0x5000),
+        ];
+        let options = FilterOptions {
+            cpus: 2,
+            ..FilterOptions::default()
+        };
+        let (traces, stats) = traces(accesses, caches(2), options);
+        let bubbles = |trace: &str| -> Vec<u64> {
+            trace
+                .lines()
+                .map(|l| l.split(' ').next().unwrap().parse().unwrap())
+                .collect()
+        };
+        assert_eq!(bubbles(&traces[0]), [0, 0]);
+        assert_eq!(bubbles(&traces[1]), [0, 0, 6]);
+        assert_eq!((stats[0].out_of_order, stats[1].out_of_order), (1, 1));
+    }
+
+    #[test]
+    fn cycle_gaps_carry_their_rounding_error() {
+        let gaps = |ipc| -> Vec<String> {
+            let accesses = (0..5)
+                .map(|i| load(10 + 3 * i, 0, 0x1000 * (i + 1)))
+                .collect();
+            let options = FilterOptions {
+                ipc: Some(ipc),
+                ..FilterOptions::default()
+            };
+            let (traces, _) = traces(accesses, caches(1), options);
+            traces[0]
+                .lines()
+                .map(|l| l.rsplit_once(' ').unwrap().0.to_string())
+                .collect()
+        };
+        assert_eq!(gaps(1.0), ["0 0", "3 3", "3 3", "3 3", "3 3"]);
+        // 1.5 cycles per bubble alternate between 2 and 1, adding up to 6 cycles
+        assert_eq!(gaps(2.0), ["0 0", "2 3", "1 3", "2 3", "1 3"]);
+    }
+
+    #[test]
+    fn write_through_emits_every_store_and_no_writebacks() {
+        // three stores to one block, then four loads that evict it from its set
+        let mut accesses = vec![store(1, 0x1000), store(2, 0x1008), store(3, 0x1010)];
+        accesses.extend((1..5).map(|i| load(3 + i, 0, 0x1000 + i * 0x400)));
+        let lines = |policy| {
+            let l2 = Cache::new(4096, 64, 4).with_write_policy(policy);
+            let caches = CacheHierarchy::new(vec![PrivateCaches::new(None, l2)], None);
+            let options = FilterOptions {
+                writebacks: true,
+                write_through: policy == WritePolicy::WriteThrough,
+                ..FilterOptions::default()
+            };
+            let (traces, stats) = traces(accesses.clone(), Some(caches), options);
+            (traces[0].lines().count(), stats[0].writebacks)
+        };
+        // the store miss, the four loads and the writeback of the dirty block
+        assert_eq!(lines(WritePolicy::WriteBack), (6, 1));
+        // all three stores, the four loads and nothing left to write back
+        assert_eq!(lines(WritePolicy::WriteThrough), (7, 0));
+    }
+
+    #[test]
+    fn non_temporal_stores_bypass_the_cache() {
+        let non_temporal = |insn_count, address| {
+            MemoryAccess::Regular(MemRecord {
+                insn_count,
+                address,
+                store: true,
+                cpu: 0,
+                size: 3,
+                non_temporal: true,
+            })
+        };
+        let accesses = vec![
+            load(1, 0, 0x1000),
+            non_temporal(2, 0x1008),
+            load(3, 0, 0x1000),
+            non_temporal(4, 0x2000),
+            load(5, 0, 0x2000),
+        ];
+        let (traces, stats) = traces(accesses, caches(1), FilterOptions::default());
+        // the store drops the cached block and doesn't install the uncached one, so
+        // both loads after it miss
+        assert_eq!(
+            traces[0],
+            "0 0x0000000000001000\n\
+             1 -1 0x0000000000001008\n\
+             1 0x0000000000001000\n\
+             1 -1 0x0000000000002000\n\
+             1 0x0000000000002000\n"
+        );
+        assert_eq!(stats[0].hits, 0);
+    }
+
+    #[test]
+    fn rowclone_costs_count_dirty_lines() {
+        let accesses = vec![
+            // two dirty source lines
+            store(1, 0x1000),
+            store(2, 0x1040),
+            // a dirty and a clean destination line
+            store(3, 0x5000),
+            load(4, 0, 0x5080),
+            rowclone(5, 0x1000, 0x5000),
+        ];
+        let path = std::env::temp_dir().join(format!("rowclone_costs_{}.csv", std::process::id()));
+        let options = FilterOptions {
+            flush_rowclone_source: true,
+            rowclone_writebacks: true,
+            rowclone_costs: Some(Box::new(std::fs::File::create(&path).unwrap())),
+            ..FilterOptions::default()
+        };
+        let (_, stats) = traces(accesses, caches(1), options);
+        let costs = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // the flushed source lines are clean and stay cached, only the destination is
+        // invalidated
+        assert_eq!(costs, "5,0,0x0000000000005000,2,1\n");
+        assert_eq!(stats[0].source_flushes, 2);
+        assert_eq!(stats[0].writebacks, 3);
+    }
+
+    #[test]
+    fn accesses_are_split_into_kernel_and_user() {
+        let kernel = 0xffff_8880_0000_1000;
+        let accesses = vec![
+            load(1, 0, kernel),
+            load(2, 0, kernel),
+            load(3, 0, 0x7f00_0000_1000),
+        ];
+        let (_, stats) = traces(accesses, caches(1), FilterOptions::default());
+        assert_eq!((stats[0].kernel_accesses, stats[0].kernel_hits), (2, 1));
+        assert_eq!((stats[0].accesses, stats[0].hits), (3, 1));
+
+        // everything from the threshold up counts as kernel
+        let options = FilterOptions {
+            kernel_threshold: 0x7f00_0000_1000,
+            ..FilterOptions::default()
+        };
+        let accesses = vec![load(1, 0, 0x7f00_0000_0fff), load(2, 0, 0x7f00_0000_1000)];
+        let (_, stats) = traces(accesses, caches(1), options);
+        assert_eq!((stats[0].kernel_accesses, stats[0].accesses), (1, 2));
+    }
+
+    #[test]
+    fn writebacks_follow_the_record_that_caused_them() {
+        let accesses = vec![
+            store(1, 0x1000),
+            // fill the rest of the set, then evict the dirty block
+            load(3, 0, 0x1400),
+            load(5, 0, 0x1800),
+            load(7, 0, 0x1c00),
+            load(10, 0, 0x2000),
+            store(12, 0x5000),
+            rowclone(20, 0x9000, 0x5000),
+        ];
+        let options = FilterOptions {
+            writebacks: true,
+            rowclone_writebacks: true,
+            ..FilterOptions::default()
+        };
+        let (traces, stats) = traces(accesses, caches(1), options);
+        // writebacks take no bubble and don't move the bubble of the next record
+        assert_eq!(
+            traces[0],
+            "0 -1 0x0000000000001000\n\
+             2 0x0000000000001400\n\
+             2 0x0000000000001800\n\
+             2 0x0000000000001c00\n\
+             3 0x0000000000002000\n\
+             0 -1 0x0000000000001000\n\
+             2 -1 0x0000000000005000\n\
+             0 -1 0x0000000000005000\n\
+             8 0x0000000000009000 0x0000000000005000\n"
+        );
+        assert_eq!(stats[0].writebacks, 2);
+    }
+
+    #[test]
+    fn rowclones_only_invalidate_other_cpus_when_coherent() {
+        let holders = |coherent_rowclones| {
+            // both CPUs cache the destination before CPU 0 rowclones to it
+            let accesses = vec![
+                load(1, 0, 0x5000),
+                load(2, 1, 0x5000),
+                rowclone(3, 0x1000, 0x5000),
+            ];
+            let options = FilterOptions {
+                cpus: 2,
+                coherent_rowclones,
+                ..FilterOptions::default()
+            };
+            let filter =
+                filter_through_cache(accesses, caches(2), vec![vec![]; 2], options).unwrap();
+            let private = filter.caches().unwrap().private();
+            (0..2)
+                .map(|cpu| {
+                    let l2 = private[cpu].l2();
+                    (l2.contains(0x5000), l2.stats().invalidations)
+                })
+                .collect::<Vec<_>>()
+        };
+        // a private rowclone touches exactly the issuing CPU's cache
+        assert_eq!(holders(false), [(false, 1), (true, 0)]);
+        assert_eq!(holders(true), [(false, 1), (false, 1)]);
+    }
+}
@@ -0,0 +1,275 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+
+use clap::ValueEnum;
+
+// Binary form of the ramulator traces written by the cache simulator. Every record
+// is SIZE bytes, integers little-endian: bubble u64, kind u8, addr1 u64, addr2 u64.
+const BUBBLE_OFFSET: usize = 0;
+const KIND_OFFSET: usize = 8;
+const ADDR1_OFFSET: usize = 9;
+const ADDR2_OFFSET: usize = 17;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceKind {
+    Load = 0,
+    Store = 1,
+    // addr1 is the source, addr2 the destination
+    Rowclone = 2,
+}
+
+/// One request of a trace; addr2 is 0 unless the request is a rowclone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub bubble: u64,
+    pub kind: TraceKind,
+    pub addr1: u64,
+    pub addr2: u64,
+}
+
+fn read_u64(buffer: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buffer[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+impl TraceRecord {
+    pub const SIZE: usize = 8 + 1 + 8 + 8;
+
+    pub fn deserialize(buffer: &[u8; Self::SIZE]) -> io::Result<TraceRecord> {
+        let kind = match buffer[KIND_OFFSET] {
+            0 => TraceKind::Load,
+            1 => TraceKind::Store,
+            2 => TraceKind::Rowclone,
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown trace record kind {}", kind),
+                ));
+            }
+        };
+        Ok(TraceRecord {
+            bubble: read_u64(buffer, BUBBLE_OFFSET),
+            kind,
+            addr1: read_u64(buffer, ADDR1_OFFSET),
+            addr2: read_u64(buffer, ADDR2_OFFSET),
+        })
+    }
+
+    pub fn serialize(&self, buffer: &mut [u8; Self::SIZE]) {
+        buffer[BUBBLE_OFFSET..BUBBLE_OFFSET + 8].copy_from_slice(&self.bubble.to_le_bytes());
+        buffer[KIND_OFFSET] = self.kind as u8;
+        buffer[ADDR1_OFFSET..ADDR1_OFFSET + 8].copy_from_slice(&self.addr1.to_le_bytes());
+        buffer[ADDR2_OFFSET..ADDR2_OFFSET + 8].copy_from_slice(&self.addr2.to_le_bytes());
+    }
+}
+
+// The text form is the ramulator trace line.
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            TraceKind::Load => write!(f, "{} 0x{:016x}", self.bubble, self.addr1),
+            TraceKind::Store => write!(f, "{} -1 0x{:016x}", self.bubble, self.addr1),
+            TraceKind::Rowclone => write!(
+                f,
+                "{} 0x{:016x} 0x{:016x}",
+                self.bubble, self.addr1, self.addr2
+            ),
+        }
+    }
+}
+
+/// Text dialects of the trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    // bubble in instructions, the form written by Display: `bubble addr` for loads,
+    // `bubble -1 addr` for stores and `bubble from to` for rowclones
+    RamulatorInsn,
+    // like ramulator-insn, but the bubble is in CPU cycles: instructions times the
+    // CPI, rounded
+    RamulatorCycles,
+    // instruction count, r, w or c for rowclones, then the addresses
+    Plain,
+    // DRAMSim3: `addr READ|WRITE cycle`, the cycle being the instruction count times
+    // the CPI, rounded. DRAMSim3 has no copy command, so a rowclone becomes a READ of
+    // the source followed by a WRITE of the destination.
+    Dramsim3,
+    // ChampSim: `insn_count source destination`, the memory operands of ChampSim's
+    // instruction records with 0x0 for an absent one. A load only has a source, a
+    // store only a destination and a rowclone is one instruction reading its source
+    // and writing its destination.
+    Champsim,
+}
+
+impl OutputFormat {
+    /// Formats the record as a line of this dialect, or two for a DRAMSim3 rowclone;
+    /// `insn_count` is the instruction count of the access that caused the request.
+    pub fn line(self, record: &TraceRecord, insn_count: u64, cpi: f64) -> String {
+        match self {
+            OutputFormat::RamulatorInsn => record.to_string(),
+            OutputFormat::RamulatorCycles => TraceRecord {
+                bubble: (record.bubble as f64 * cpi).round() as u64,
+                ..*record
+            }
+            .to_string(),
+            OutputFormat::Plain => match record.kind {
+                TraceKind::Load => format!("{} r 0x{:016x}", insn_count, record.addr1),
+                TraceKind::Store => format!("{} w 0x{:016x}", insn_count, record.addr1),
+                TraceKind::Rowclone => format!(
+                    "{} c 0x{:016x} 0x{:016x}",
+                    insn_count, record.addr1, record.addr2
+                ),
+            },
+            OutputFormat::Dramsim3 => {
+                let cycle = (insn_count as f64 * cpi).round() as u64;
+                match record.kind {
+                    TraceKind::Load => format!("0x{:016x} READ {}", record.addr1, cycle),
+                    TraceKind::Store => format!("0x{:016x} WRITE {}", record.addr1, cycle),
+                    TraceKind::Rowclone => format!(
+                        "0x{:016x} READ {}\n0x{:016x} WRITE {}",
+                        record.addr1, cycle, record.addr2, cycle
+                    ),
+                }
+            }
+            OutputFormat::Champsim => {
+                let (source, destination) = match record.kind {
+                    TraceKind::Load => (record.addr1, 0),
+                    TraceKind::Store => (0, record.addr1),
+                    TraceKind::Rowclone => (record.addr1, record.addr2),
+                };
+                format!("{} 0x{:016x} 0x{:016x}", insn_count, source, destination)
+            }
+        }
+    }
+}
+
+pub struct TraceReader<R: Read> {
+    reader: BufReader<R>,
+    buffer: [u8; TraceRecord::SIZE],
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn from_reader(reader: R) -> Self {
+        TraceReader {
+            reader: BufReader::new(reader),
+            buffer: [0u8; TraceRecord::SIZE],
+        }
+    }
+}
+
+impl TraceReader<File> {
+    pub fn new(filename: &str) -> io::Result<Self> {
+        File::open(filename).map(Self::from_reader)
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reader.read_exact(&mut self.buffer) {
+            Ok(_) => Some(TraceRecord::deserialize(&self.buffer)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOAD: TraceRecord = TraceRecord {
+        bubble: 12,
+        kind: TraceKind::Load,
+        addr1: 0x1000,
+        addr2: 0,
+    };
+    const STORE: TraceRecord = TraceRecord {
+        bubble: 3,
+        kind: TraceKind::Store,
+        addr1: 0x2040,
+        addr2: 0,
+    };
+    const ROWCLONE: TraceRecord = TraceRecord {
+        bubble: 7,
+        kind: TraceKind::Rowclone,
+        addr1: 0x20_0000,
+        addr2: 0x40_0000,
+    };
+
+    // Lines of the load, store and rowclone at instruction 100 with a CPI of 1.5.
+    fn golden(format: OutputFormat) -> [String; 3] {
+        [LOAD, STORE, ROWCLONE].map(|record| format.line(&record, 100, 1.5))
+    }
+
+    #[test]
+    fn ramulator_insn_lines() {
+        assert_eq!(
+            golden(OutputFormat::RamulatorInsn),
+            [
+                "12 0x0000000000001000",
+                "3 -1 0x0000000000002040",
+                "7 0x0000000000200000 0x0000000000400000",
+            ]
+        );
+    }
+
+    #[test]
+    fn ramulator_cycles_lines() {
+        assert_eq!(
+            golden(OutputFormat::RamulatorCycles),
+            [
+                "18 0x0000000000001000",
+                "5 -1 0x0000000000002040",
+                "11 0x0000000000200000 0x0000000000400000",
+            ]
+        );
+    }
+
+    #[test]
+    fn plain_lines() {
+        assert_eq!(
+            golden(OutputFormat::Plain),
+            [
+                "100 r 0x0000000000001000",
+                "100 w 0x0000000000002040",
+                "100 c 0x0000000000200000 0x0000000000400000",
+            ]
+        );
+    }
+
+    #[test]
+    fn dramsim3_lines() {
+        assert_eq!(
+            golden(OutputFormat::Dramsim3),
+            [
+                "0x0000000000001000 READ 150",
+                "0x0000000000002040 WRITE 150",
+                "0x0000000000200000 READ 150\n0x0000000000400000 WRITE 150",
+            ]
+        );
+    }
+
+    #[test]
+    fn champsim_lines() {
+        assert_eq!(
+            golden(OutputFormat::Champsim),
+            [
+                "100 0x0000000000001000 0x0000000000000000",
+                "100 0x0000000000000000 0x0000000000002040",
+                "100 0x0000000000200000 0x0000000000400000",
+            ]
+        );
+    }
+
+    #[test]
+    fn binary_records_round_trip() {
+        for record in [LOAD, STORE, ROWCLONE] {
+            let mut buffer = [0u8; TraceRecord::SIZE];
+            record.serialize(&mut buffer);
+            assert_eq!(TraceRecord::deserialize(&buffer).unwrap(), record);
+        }
+    }
+}